@@ -0,0 +1,48 @@
+//! Generates the gRPC service with the `grpc` feature. Its messages are
+//! written by hand in src/grpc.rs, so there's no protoc to install;
+//! proto/persephone.proto describes the same service for clients.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+#[cfg(feature = "grpc")]
+fn grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::grpc::{input}"))
+            .output_type(format!("crate::grpc::{output}"))
+            .codec_path("tonic::codec::ProstCodec")
+    };
+    let service = Service::builder()
+        .name("Assistant")
+        .package("persephone")
+        .method(method("generate", "Generate", "GenerateRequest", "GenerateReply").build())
+        .method(
+            method(
+                "generate_stream",
+                "GenerateStream",
+                "GenerateRequest",
+                "GenerateChunk",
+            )
+            .server_streaming()
+            .build(),
+        )
+        .method(
+            method(
+                "summarize",
+                "Summarize",
+                "SummarizeRequest",
+                "GenerateReply",
+            )
+            .build(),
+        )
+        .method(method("embed", "Embed", "EmbedRequest", "EmbedReply").build())
+        .build();
+    Builder::new().compile(&[service]);
+}
@@ -1,115 +1,420 @@
-use std::time::Instant;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
+use crate::error::{Error, Result};
+use crate::llama::Llama;
+use crate::metrics::{metrics, Metrics, Operation};
+use crate::model::{KvCache, Model};
+use crate::scheduler::{Priority, Scheduler};
+use crate::speculative::{score, Draft, PromptLookup, Proposer, Sampler, SpeculationStats};
+use crate::telemetry::{redact, RequestId};
 use crate::token_output_stream::TokenOutputStream;
-use crate::utils::device;
-use anyhow::Result;
+use anyhow::anyhow;
 use async_stream::stream;
-use candle_core::DType::F16;
-use candle_core::Tensor;
+use candle_core::{DType, Tensor};
 use candle_transformers::generation::LogitsProcessor;
-use candle_transformers::generation::Sampling;
-use candle_transformers::models::llama::{Cache, Config, Llama, LlamaEosToks};
+use candle_transformers::generation::Sampling::TopP;
+use candle_transformers::models::llama::{Config, LlamaEosToks};
 use candle_transformers::utils::apply_repeat_penalty;
 use futures_util::Stream;
+use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
+use tracing::{debug, field, info, info_span};
 
 use crate::utils;
 
+const SEED: u64 = 299792458;
+const TEMPERATURE: f64 = 0.8;
+const TOP_P: f64 = 0.9;
+
+/// How the next token is picked.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sampling {
+    pub seed: u64,
+    pub temperature: f64,
+    pub top_p: f64,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            seed: SEED,
+            temperature: TEMPERATURE,
+            top_p: TOP_P,
+        }
+    }
+}
+
+impl Sampling {
+    pub fn validate(&self) -> Result<()> {
+        if self.temperature.is_nan() || self.temperature <= 0.0 {
+            return Err(Error::Config("temperature must be positive".into()));
+        }
+        if self.top_p.is_nan() || self.top_p <= 0.0 || self.top_p > 1.0 {
+            return Err(Error::Config("top_p must be in (0, 1]".into()));
+        }
+        Ok(())
+    }
+}
+
+/// How a single answer is decoded.
+#[derive(Clone, Debug, Default)]
+pub struct AnswerOptions {
+    /// Overrides the assistant's sampling
+    pub sampling: Option<Sampling>,
+    /// Decode with prompt lookup rather than the draft model, if any
+    pub lookup: Option<PromptLookup>,
+    /// The request being answered, for tracing
+    pub request_id: Option<RequestId>,
+    /// What the answer is for, for metrics
+    pub operation: Operation,
+    /// Told the tokens the answer uses as it uses them
+    pub meter: Option<Arc<dyn Meter>>,
+    /// Whose turn it is at the assistant, otherwise answers just queue for it
+    pub scheduler: Option<Scheduler>,
+    /// Where the answer waits for its turn
+    pub priority: Priority,
+    /// Whose answer it is, so keys take turns fairly
+    pub key: Option<String>,
+}
+
+/// Counts the tokens answers use, for limits and accounting.
+pub trait Meter: Send + Sync {
+    /// The prompt was this many tokens.
+    fn prompt(&self, _tokens: usize) {}
+
+    /// This many more tokens were generated.
+    fn generated(&self, _tokens: usize) {}
+}
+
+impl std::fmt::Debug for dyn Meter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Meter")
+    }
+}
+
+/// Counts an answer's tokens, passing them on to the meter it wraps, if any.
+#[derive(Default)]
+pub struct Tally {
+    inner: Option<Arc<dyn Meter>>,
+    prompt: AtomicUsize,
+    generated: AtomicUsize,
+}
+
+impl Tally {
+    pub fn new(inner: Option<Arc<dyn Meter>>) -> Self {
+        Self {
+            inner,
+            ..Default::default()
+        }
+    }
+
+    pub fn prompt_tokens(&self) -> usize {
+        self.prompt.load(Ordering::Relaxed)
+    }
+
+    pub fn generated_tokens(&self) -> usize {
+        self.generated.load(Ordering::Relaxed)
+    }
+}
+
+impl Meter for Tally {
+    fn prompt(&self, tokens: usize) {
+        self.prompt.fetch_add(tokens, Ordering::Relaxed);
+        if let Some(inner) = &self.inner {
+            inner.prompt(tokens);
+        }
+    }
+
+    fn generated(&self, tokens: usize) {
+        self.generated.fetch_add(tokens, Ordering::Relaxed);
+        if let Some(inner) = &self.inner {
+            inner.generated(tokens);
+        }
+    }
+}
+
 #[derive(Clone)]
-pub struct Assistant {
-    model: Llama,
+pub struct Assistant<M = Llama> {
+    model: M,
     tokenizer: Tokenizer,
     config: Config,
+    draft: Option<Draft>,
+    speculation: Arc<SpeculationStats>,
+    sampling: Sampling,
+    max_tokens: Option<usize>,
 }
 
-impl Assistant {
-    pub fn new(model: Llama, tokenizer: Tokenizer, config: Config) -> Self {
+impl<M: Model> Assistant<M> {
+    pub fn new(model: M, tokenizer: Tokenizer, config: Config) -> Self {
         Self {
             model,
             tokenizer,
             config,
+            draft: None,
+            speculation: Arc::new(SpeculationStats::default()),
+            sampling: Sampling::default(),
+            max_tokens: None,
+        }
+    }
+
+    pub fn with_sampling(mut self, sampling: Sampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Stops answers after this many tokens rather than when the context fills.
+    pub fn with_max_tokens(mut self, max_tokens: Option<usize>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Decodes speculatively, with the draft model proposing tokens.
+    pub fn with_draft(mut self, draft: Draft) -> Self {
+        self.draft = Some(draft);
+        self
+    }
+
+    /// Counts how proposed tokens fare in `stats`, which can then be read
+    /// without waiting for the assistant.
+    pub fn with_speculation(mut self, stats: Arc<SpeculationStats>) -> Self {
+        self.speculation = stats;
+        self
+    }
+
+    /// How proposed tokens have fared across every answer.
+    pub fn speculation(&self) -> &SpeculationStats {
+        &self.speculation
+    }
+
+    /// The mean of the prompt's final hidden states, scaled to unit length,
+    /// for comparing texts by cosine similarity. `meter` is told the prompt's
+    /// tokens.
+    pub fn embed(&self, text: &str, meter: Option<&dyn Meter>) -> Result<Vec<f32>> {
+        let tokens = self
+            .tokenizer
+            .encode(text, true)
+            .map_err(|e| Error::Tokenization(e.to_string()))?
+            .get_ids()
+            .to_vec();
+        let limit = self.config.max_position_embeddings;
+        if tokens.len() > limit {
+            return Err(Error::ContextOverflow {
+                tokens: tokens.len(),
+                limit,
+            });
         }
+        if let Some(meter) = meter {
+            meter.prompt(tokens.len());
+        }
+        let device = utils::device().map_err(Error::ModelLoad)?;
+        let mut cache = self.model.cache(&self.config, &device)?;
+        let input = Tensor::new(tokens.as_slice(), &device)?.unsqueeze(0)?;
+        let mean = self
+            .model
+            .hidden(&input, &mut cache)?
+            .to_dtype(DType::F32)?
+            .squeeze(0)?
+            .mean(0)?;
+        let norm = mean.sqr()?.sum_all()?.sqrt()?.to_scalar::<f32>()?;
+        Ok(mean
+            .affine(1.0 / norm.max(f32::EPSILON) as f64, 0.0)?
+            .to_vec1()?)
     }
 
     pub async fn answer<'a>(
         &'a self,
         prompt: String,
     ) -> Result<impl Stream<Item = Result<String>> + 'a> {
-        println!(
-            "avx: {}, neon: {}, simd128: {}, f16c: {}",
-            candle_core::utils::with_avx(),
-            candle_core::utils::with_neon(),
-            candle_core::utils::with_simd128(),
-            candle_core::utils::with_f16c()
+        self.answer_with(prompt, AnswerOptions::default()).await
+    }
+
+    /// Answers with prompt lookup decoding, which speeds up answers that copy
+    /// text from the prompt without needing a draft model.
+    pub async fn answer_with_lookup<'a>(
+        &'a self,
+        prompt: String,
+        lookup: PromptLookup,
+    ) -> Result<impl Stream<Item = Result<String>> + 'a> {
+        let options = AnswerOptions {
+            lookup: Some(lookup),
+            ..Default::default()
+        };
+        self.answer_with(prompt, options).await
+    }
+
+    /// Answers in an `answer` span, tagged with the request id, that holds a
+    /// `prefill` span up to the first token and a `decode` span after it.
+    pub async fn answer_with<'a>(
+        &'a self,
+        prompt: String,
+        options: AnswerOptions,
+    ) -> Result<impl Stream<Item = Result<String>> + 'a> {
+        let span = info_span!(
+            "answer",
+            request_id = options.request_id.as_ref().map(|it| it.as_str()),
         );
-        let mut cache = Cache::new(true, F16, &self.config, &device()?)?;
+        debug!(parent: &span, prompt = %redact(&prompt), "answering");
+        let metrics = metrics();
+        metrics
+            .requests
+            .with_label_values(&[options.operation.as_str()])
+            .inc();
+        let device = utils::device().map_err(Error::ModelLoad)?;
+        let mut cache = self.model.cache(&self.config, &device)?;
         let mut tokenizer = TokenOutputStream::new(self.tokenizer.clone());
         let mut tokens = tokenizer
             .tokenizer()
             .encode(prompt, true)
-            .map_err(anyhow::Error::msg)?
+            .map_err(|e| Error::Tokenization(e.to_string()))?
             .get_ids()
             .to_vec();
-        let eos_tok_id = self
-            .config
-            .eos_token_id
-            .clone()
-            .ok_or("no eos_token?")
-            .map_err(anyhow::Error::msg)?;
-        let eos = match eos_tok_id {
-            LlamaEosToks::Single(t) => Ok(t),
-            _ => Err("multiple eos tokens?"),
+        let limit = self.config.max_position_embeddings;
+        if tokens.len() >= limit {
+            return Err(Error::ContextOverflow {
+                tokens: tokens.len(),
+                limit,
+            });
         }
-        .map_err(anyhow::Error::msg)?;
-        let device = utils::device()?;
+        let eos = match self.config.eos_token_id {
+            Some(LlamaEosToks::Single(t)) => Ok(t),
+            Some(_) => Err(anyhow!("multiple eos tokens?")),
+            None => Err(anyhow!("no eos_token?")),
+        }
+        .map_err(Error::ModelLoad)?;
+        let Sampling {
+            seed,
+            temperature,
+            top_p,
+        } = options.sampling.unwrap_or(self.sampling);
         let mut logits_processor = LogitsProcessor::from_sampling(
-            299792458,
-            Sampling::TopP {
-                p: 0.9,
-                temperature: 0.8,
+            seed,
+            TopP {
+                p: top_p,
+                temperature,
             },
         );
+        let mut proposer = match (options.lookup, &self.draft) {
+            (Some(lookup), _) => Some(Proposer::Lookup(lookup, tokens.len())),
+            (None, Some(draft)) => Some(Proposer::Draft(
+                draft,
+                draft.model.cache(&draft.config, &device)?,
+            )),
+            (None, None) => None,
+        };
+        let mut sampler = Sampler::new(seed, temperature, top_p);
         let start = Instant::now();
+        let mut prefill = Some(info_span!(parent: &span, "prefill", tokens = tokens.len()));
+        let decode = info_span!(parent: &span, "decode", tokens = field::Empty);
         let s = stream! {
-            let mut index_pos = 0;
+            let _active = Metrics::track(&metrics.active_generations);
+            if let Some(meter) = &options.meter {
+                meter.prompt(tokens.len());
+            }
+            let mut first_token = None;
             let mut generated_tokens = 0;
-            loop {
-                let (context_size, context_index) = if cache.use_kv_cache && index_pos > 0 {
-                    (1, index_pos)
-                } else {
-                    (tokens.len(), 0)
-                };
-                let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
-                let input = Tensor::new(ctxt, &device)?.unsqueeze(0)?;
-                let logits = self.model.forward(&input, context_index, &mut cache)?;
-                let logits = logits.squeeze(0)?;
-                let start_at = tokens.len().saturating_sub(64);
-                apply_repeat_penalty(
-                    &logits,
-                    1.1,
-                    &tokens[start_at..],
-                )?;
-                index_pos += ctxt.len();
-                let next_token = logits_processor.sample(&logits)?;
-                generated_tokens += 1;
-                tokens.push(next_token);
-
-                if next_token == eos {
-                    break;
+            let (mut proposed, mut accepted) = (0, 0);
+            'generate: loop {
+                if tokens.len() >= limit {
+                    Err(Error::ContextOverflow { tokens: tokens.len(), limit })?;
+                }
+                let step = prefill.as_ref().unwrap_or(&decode);
+                let next_tokens = step.in_scope(|| -> Result<Vec<u32>> {
+                    if let Some(proposer) = proposer.as_mut() {
+                        let (mut proposals, draft_probs) = proposer
+                            .propose(&mut sampler, &tokens, &device)
+                            .map_err(Error::Inference)?;
+                        // Leave room in the context for the token after the proposals
+                        proposals.truncate(limit - tokens.len() - 1);
+                        let target =
+                            score(&self.model, &mut cache, &sampler, &tokens, &proposals, &device)
+                                .map_err(Error::Inference)?;
+                        let (n, next_token) = sampler
+                            .verify(&proposals, &target, draft_probs.as_deref())
+                            .map_err(Error::Inference)?;
+                        self.speculation.record(proposals.len(), n);
+                        proposed += proposals.len();
+                        accepted += n;
+                        // Forget the rejected proposals
+                        cache.truncate(tokens.len() + n)?;
+                        proposer.truncate(tokens.len() + n).map_err(Error::Inference)?;
+                        let mut next_tokens = proposals[..n].to_vec();
+                        next_tokens.push(next_token);
+                        Ok(next_tokens)
+                    } else {
+                        let ctxt = &tokens[cache.len()..];
+                        let input = Tensor::new(ctxt, &device)?.unsqueeze(0)?;
+                        let logits = self.model.forward(&input, &mut cache)?;
+                        let logits = logits.squeeze(0)?;
+                        let start_at = tokens.len().saturating_sub(64);
+                        let logits = apply_repeat_penalty(
+                            &logits,
+                            1.1,
+                            &tokens[start_at..],
+                        )?;
+                        let next_token = logits_processor.sample(&logits)?;
+                        Ok(vec![next_token])
+                    }
+                })?;
+                // Closing the span records how long the first token took
+                prefill = None;
+                if first_token.is_none() {
+                    let elapsed = start.elapsed();
+                    metrics.time_to_first_token.observe(elapsed.as_secs_f64());
+                    first_token = Some(elapsed);
                 }
+                for next_token in next_tokens {
+                    generated_tokens += 1;
+                    if let Some(meter) = &options.meter {
+                        meter.generated(1);
+                    }
+                    tokens.push(next_token);
 
-                if let Some(t) = tokenizer.next_token(next_token)? {
-                    yield Ok(t)
+                    if next_token == eos {
+                        break 'generate;
+                    }
+
+                    let t = tokenizer
+                        .next_token(next_token)
+                        .map_err(|e| Error::Tokenization(e.to_string()))?;
+                    if let Some(t) = t {
+                        yield Ok(t)
+                    }
+                    if self.max_tokens.is_some_and(|max| generated_tokens >= max) {
+                        break 'generate;
+                    }
                 }
             }
-            if let Some(rest) = tokenizer.decode_rest()? {
+            let rest = tokenizer
+                .decode_rest()
+                .map_err(|e| Error::Tokenization(e.to_string()))?;
+            if let Some(rest) = rest {
                 yield Ok(rest)
             }
             let done = start.elapsed();
-            println!(
-                "Generated {generated_tokens} tokens ({:.2} t/s) in {:.2} seconds",
-                generated_tokens as f64 / done.as_secs_f64(),
-                done.as_secs_f64()
+            metrics.latency.observe(done.as_secs_f64());
+            metrics.generated_tokens.observe(generated_tokens as f64);
+            let decoding = done - first_token.unwrap_or_default();
+            if generated_tokens > 1 && !decoding.is_zero() {
+                metrics
+                    .decode_tokens_per_second
+                    .observe((generated_tokens - 1) as f64 / decoding.as_secs_f64());
+            }
+            decode.record("tokens", generated_tokens);
+            info!(
+                parent: &span,
+                tokens = generated_tokens,
+                tokens_per_second = generated_tokens as f64 / done.as_secs_f64(),
+                seconds = done.as_secs_f64(),
+                proposed,
+                accepted,
+                "answered"
             );
         };
 
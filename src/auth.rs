@@ -0,0 +1,459 @@
+//! API keys. When any are configured every request needs one, either in an
+//! `Authorization: Bearer <key>` or `x-api-key` header or, for GraphQL over a
+//! WebSocket, as `apiKey` in the `connection_init` payload. `GET /api/ask` and
+//! `/voice` also take it as an `api_key` query parameter, as browsers can't set
+//! headers there. Only the keys' SHA-256 hashes are kept, so the config file
+//! doesn't give them away.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{FromRequestParts, Query},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap, Uri},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    assistant::Meter,
+    error::{Error, Result},
+    server::AppState,
+    usage::{Day, Tokens, Usage},
+};
+
+const API_KEY: &str = "x-api-key";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    /// Requests need one of these keys, unless there are none
+    pub keys: Vec<KeySettings>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeySettings {
+    /// Who the key belongs to
+    pub name: String,
+    /// The key's SHA-256 hash in hex, from `persephone key new`
+    pub sha256: String,
+    /// Requests allowed a minute, in bursts of up to as many
+    pub requests_per_minute: Option<u32>,
+    /// Tokens generated a minute, in bursts of up to as many
+    pub tokens_per_minute: Option<u32>,
+    /// Prompt and completion tokens allowed a calendar month (UTC)
+    pub monthly_tokens: Option<u64>,
+    /// Whether the key can see every key's usage
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl AuthSettings {
+    pub fn validate(&self) -> Result<()> {
+        let mut seen = HashMap::new();
+        let mut names = HashSet::new();
+        for key in &self.keys {
+            // Usage, quotas and batches are kept by name
+            if !names.insert(&key.name) {
+                return Err(Error::Config(format!(
+                    "more than one key is named {}",
+                    key.name
+                )));
+            }
+            let hex = key.sha256.len() == 64 && key.sha256.bytes().all(|it| it.is_ascii_hexdigit());
+            if !hex {
+                return Err(Error::Config(format!(
+                    "the key for {} should be a SHA-256 hash in hex",
+                    key.name
+                )));
+            }
+            if key.requests_per_minute == Some(0)
+                || key.tokens_per_minute == Some(0)
+                || key.monthly_tokens == Some(0)
+            {
+                return Err(Error::Config(format!(
+                    "the limits for {} must allow at least one",
+                    key.name
+                )));
+            }
+            if let Some(other) = seen.insert(key.sha256.to_lowercase(), &key.name) {
+                return Err(Error::Config(format!(
+                    "{} and {other} have the same key",
+                    key.name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hashes a key the way the config stores it.
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// A new random key.
+pub fn new_key() -> String {
+    format!("sk-{:032x}", rand::random::<u128>())
+}
+
+/// Limits a rate to `capacity` a minute, allowing bursts of up to as many.
+pub struct TokenBucket {
+    capacity: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn per_minute(capacity: u32) -> Self {
+        Self {
+            capacity: capacity.into(),
+            state: Mutex::new((capacity.into(), Instant::now())),
+        }
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity as u32
+    }
+
+    /// What could be spent now, which is negative while paying off an
+    /// answer that ran over.
+    pub fn available(&self) -> f64 {
+        let mut state = self.state.lock().unwrap_or_else(|it| it.into_inner());
+        self.refill(&mut state);
+        state.0
+    }
+
+    /// Spends `n` if there's that much, otherwise says how long until there
+    /// will be.
+    pub fn take(&self, n: f64) -> std::result::Result<(), Duration> {
+        let mut state = self.state.lock().unwrap_or_else(|it| it.into_inner());
+        self.refill(&mut state);
+        if state.0 >= n {
+            state.0 -= n;
+            Ok(())
+        } else {
+            Err(self.wait(n - state.0))
+        }
+    }
+
+    /// Spends `n` even if that overdraws the bucket.
+    pub fn spend(&self, n: f64) {
+        let mut state = self.state.lock().unwrap_or_else(|it| it.into_inner());
+        self.refill(&mut state);
+        state.0 -= n;
+    }
+
+    fn refill(&self, (available, last): &mut (f64, Instant)) {
+        let now = Instant::now();
+        let per_second = self.capacity / 60.0;
+        *available = (*available + per_second * (now - *last).as_secs_f64()).min(self.capacity);
+        *last = now;
+    }
+
+    fn wait(&self, missing: f64) -> Duration {
+        Duration::from_secs_f64(missing * 60.0 / self.capacity)
+    }
+}
+
+/// A key's limits and what it has used since the server started.
+struct KeyState {
+    name: String,
+    admin: bool,
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+    monthly_tokens: Option<u64>,
+    usage: Usage,
+    requests_made: AtomicU64,
+    tokens_generated: AtomicU64,
+}
+
+/// Who's making a request.
+#[derive(Clone)]
+pub struct Caller(Arc<KeyState>);
+
+impl Caller {
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.0.admin
+    }
+
+    /// Counts a request against the key's limit.
+    pub fn request(&self) -> Result<()> {
+        if let Some(bucket) = &self.0.requests {
+            bucket.take(1.0).map_err(|wait| Error::RateLimited {
+                limit: "requests",
+                retry_after: seconds(wait),
+            })?;
+        }
+        self.0.requests_made.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Checks the key has tokens left to answer with, this minute and this
+    /// month. Answers aren't cut short, those that run over are paid for by
+    /// the next ones.
+    pub async fn check_tokens(&self) -> Result<()> {
+        if let Some(bucket) = &self.0.tokens {
+            let available = bucket.available();
+            if available < 1.0 {
+                return Err(Error::RateLimited {
+                    limit: "tokens",
+                    retry_after: seconds(bucket.wait(1.0 - available)),
+                });
+            }
+        }
+        if let Some(budget) = self.0.monthly_tokens {
+            let caller = self.clone();
+            let month = tokio::task::spawn_blocking(move || caller.this_month())
+                .await
+                .map_err(|e| Error::Store(e.into()))??;
+            if month.total() >= budget {
+                return Err(Error::QuotaExceeded { budget });
+            }
+        }
+        Ok(())
+    }
+
+    /// What an answer should be metered with, which records its usage once
+    /// it's done or dropped.
+    pub fn meter(&self) -> Arc<dyn Meter> {
+        Arc::new(Charge {
+            caller: self.clone(),
+            prompt: AtomicU64::new(0),
+            completion: AtomicU64::new(0),
+        })
+    }
+
+    /// What the key has used this month, as recorded so far.
+    pub fn this_month(&self) -> Result<Tokens> {
+        self.0.usage.month(&self.0.name, Day::today())
+    }
+
+    pub fn monthly_tokens(&self) -> Option<u64> {
+        self.0.monthly_tokens
+    }
+
+    pub fn requests_made(&self) -> u64 {
+        self.0.requests_made.load(Ordering::Relaxed)
+    }
+
+    pub fn tokens_generated(&self) -> u64 {
+        self.0.tokens_generated.load(Ordering::Relaxed)
+    }
+
+    pub fn request_limit(&self) -> Option<&TokenBucket> {
+        self.0.requests.as_ref()
+    }
+
+    pub fn token_limit(&self) -> Option<&TokenBucket> {
+        self.0.tokens.as_ref()
+    }
+}
+
+impl Meter for Caller {
+    fn generated(&self, tokens: usize) {
+        if let Some(bucket) = &self.0.tokens {
+            bucket.spend(tokens as f64);
+        }
+        self.0
+            .tokens_generated
+            .fetch_add(tokens as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counts one answer's tokens, recording them in the usage store when the
+/// answer's done with it. Recording commits to disk, so on the async runtime
+/// it's done on a blocking thread.
+struct Charge {
+    caller: Caller,
+    prompt: AtomicU64,
+    completion: AtomicU64,
+}
+
+impl Meter for Charge {
+    fn prompt(&self, tokens: usize) {
+        self.prompt.fetch_add(tokens as u64, Ordering::Relaxed);
+    }
+
+    fn generated(&self, tokens: usize) {
+        self.caller.generated(tokens);
+        self.completion.fetch_add(tokens as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        let tokens = Tokens {
+            requests: 1,
+            prompt: *self.prompt.get_mut(),
+            completion: *self.completion.get_mut(),
+        };
+        let (usage, key, day) = (
+            self.caller.0.usage.clone(),
+            self.caller.name().to_string(),
+            Day::today(),
+        );
+        let record = move || {
+            if let Err(e) = usage.record(&key, day, tokens) {
+                warn!(key, error = %e, "couldn't record usage");
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(record)),
+            Err(_) => record(),
+        }
+    }
+}
+
+/// Whole seconds to wait, rounded up so retrying then succeeds.
+fn seconds(wait: Duration) -> u64 {
+    (wait.as_secs_f64().ceil() as u64).max(1)
+}
+
+/// The configured keys, by hash.
+#[derive(Clone, Default)]
+pub struct Auth {
+    keys: Arc<HashMap<String, Caller>>,
+}
+
+impl Auth {
+    /// Reads the keys, which record what they use in `usage`.
+    pub fn new(settings: &AuthSettings, usage: &Usage) -> Self {
+        let keys = settings
+            .keys
+            .iter()
+            .map(|key| {
+                let state = KeyState {
+                    name: key.name.clone(),
+                    admin: key.admin,
+                    requests: key.requests_per_minute.map(TokenBucket::per_minute),
+                    tokens: key.tokens_per_minute.map(TokenBucket::per_minute),
+                    monthly_tokens: key.monthly_tokens,
+                    usage: usage.clone(),
+                    requests_made: AtomicU64::new(0),
+                    tokens_generated: AtomicU64::new(0),
+                };
+                (key.sha256.to_lowercase(), Caller(Arc::new(state)))
+            })
+            .collect();
+        Self {
+            keys: Arc::new(keys),
+        }
+    }
+
+    /// Whether requests need a key.
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    pub fn authenticate(&self, key: &str) -> Result<Caller> {
+        self.keys
+            .get(&hash_key(key))
+            .cloned()
+            .ok_or(Error::Unauthorized)
+    }
+
+    /// The key called `name`, for work that carries on after its request.
+    pub fn named(&self, name: &str) -> Option<Caller> {
+        self.keys.values().find(|it| it.name() == name).cloned()
+    }
+
+    /// Finds the caller from the request's headers, `None` when keys aren't
+    /// needed.
+    pub fn from_headers(&self, headers: &HeaderMap) -> Result<Option<Caller>> {
+        if !self.enabled() {
+            return Ok(None);
+        }
+        let key = headers
+            .get(AUTHORIZATION)
+            .and_then(|it| it.to_str().ok())
+            .and_then(|it| it.strip_prefix("Bearer "))
+            .or_else(|| headers.get(API_KEY).and_then(|it| it.to_str().ok()))
+            .ok_or(Error::Unauthorized)?;
+        self.authenticate(key.trim()).map(Some)
+    }
+
+    /// Finds the caller from an `api_key` query parameter, otherwise from the
+    /// request's headers.
+    pub fn from_query(&self, uri: &Uri, headers: &HeaderMap) -> Result<Option<Caller>> {
+        let query = Query::<KeyQuery>::try_from_uri(uri)
+            .map_err(|e| Error::InvalidRequest(e.body_text()))?;
+        match query.0.api_key {
+            Some(key) if self.enabled() => self.authenticate(key.trim()).map(Some),
+            _ => self.from_headers(headers),
+        }
+    }
+
+    /// Finds the caller from a graphql-ws `connection_init` payload.
+    pub fn from_payload(&self, payload: &serde_json::Value) -> Result<Option<Caller>> {
+        if !self.enabled() {
+            return Ok(None);
+        }
+        let key = payload
+            .get("apiKey")
+            .and_then(|it| it.as_str())
+            .or_else(|| {
+                ["Authorization", "authorization"]
+                    .iter()
+                    .find_map(|it| payload.get(it).and_then(|it| it.as_str()))
+                    .and_then(|it| it.strip_prefix("Bearer "))
+            })
+            .ok_or(Error::Unauthorized)?;
+        self.authenticate(key.trim()).map(Some)
+    }
+}
+
+#[derive(Deserialize)]
+struct KeyQuery {
+    api_key: Option<String>,
+}
+
+/// The caller of an HTTP request, whose key has been checked and the request
+/// counted against its limit. `None` when keys aren't needed.
+pub struct Authenticated(pub Option<Caller>);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for Authenticated {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self> {
+        counted(state.auth.from_headers(&parts.headers)?).map(Self)
+    }
+}
+
+/// Like [`Authenticated`], but the key can also be an `api_key` query
+/// parameter.
+pub struct QueryAuthenticated(pub Option<Caller>);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for QueryAuthenticated {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self> {
+        counted(state.auth.from_query(&parts.uri, &parts.headers)?).map(Self)
+    }
+}
+
+/// Counts the request against the caller's limit.
+fn counted(caller: Option<Caller>) -> Result<Option<Caller>> {
+    if let Some(caller) = &caller {
+        caller.request()?;
+    }
+    Ok(caller)
+}
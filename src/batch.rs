@@ -0,0 +1,543 @@
+//! Bulk answers for offline work. A batch is a JSONL file of requests, each
+//! an `ask` or a `summarize`:
+//!
+//! ```text
+//! {"id": "q1", "kind": "ask", "prompt": "Where is Paris?"}
+//! {"kind": "summarize", "messages": [{"author": "user", "message": "..."}]}
+//! ```
+//!
+//! They're answered in order at batch priority, so interactive answers go
+//! first. Each answer, or the error that stopped it, is appended to a JSONL
+//! file of results along with its request's line number and `id`, if it had
+//! one. The results are the checkpoint: running the batch again carries on
+//! after the last line they hold.
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_graphql::{ErrorExtensions, ResultExt, Value};
+use axum::{
+    extract::{self, Multipart, State},
+    http::header::CONTENT_TYPE,
+    response::IntoResponse,
+    Json,
+};
+use futures_util::lock::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    assistant::{Assistant, Meter, Tally},
+    auth::{Auth, Authenticated, Caller},
+    error::{self, Error},
+    llama::Llama,
+    model::Model,
+    moderation::Moderator,
+    persona::Personas,
+    pii::Masker,
+    readiness::Loadable,
+    scheduler::{Priority, Scheduler},
+    server::{
+        answer_once, meter, prepare_ask, prepare_summarize, Answer, Answerer, AppState, AskRequest,
+        Message,
+    },
+    shutdown::Shutdown,
+    telemetry::RequestId,
+};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchSettings {
+    /// Where uploaded batches and their results are kept, otherwise batches
+    /// can't be uploaded
+    pub directory: Option<PathBuf>,
+}
+
+/// A line of a batch.
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Job {
+    Ask(AskRequest),
+    Summarize {
+        #[serde(default)]
+        messages: Vec<Message>,
+        summary: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct Failed {
+    code: Option<String>,
+    message: String,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Outcome {
+    Answered(Answer),
+    Failed { error: Failed },
+}
+
+/// A line of a batch's results.
+#[derive(Serialize)]
+struct Row {
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+/// How far a batch has got.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Progress {
+    /// Requests in the batch
+    pub total: usize,
+    pub answered: usize,
+    pub failed: usize,
+}
+
+impl Progress {
+    pub fn finished(&self) -> bool {
+        self.answered + self.failed >= self.total
+    }
+}
+
+/// The requests in a batch with their line numbers, skipping blank lines.
+fn requests(batch: &str) -> impl Iterator<Item = (usize, &str)> {
+    batch
+        .lines()
+        .enumerate()
+        .map(|(i, it)| (i + 1, it))
+        .filter(|(_, it)| !it.trim().is_empty())
+}
+
+/// The whole lines of a batch's results, without one cut off halfway through
+/// being written.
+fn whole_lines(results: &[u8]) -> &[u8] {
+    let end = results
+        .iter()
+        .rposition(|it| *it == b'\n')
+        .map_or(0, |it| it + 1);
+    &results[..end]
+}
+
+/// What the results so far hold, as how many requests were answered and
+/// failed and the last line they got to.
+fn checkpoint(results: &[u8], total: usize) -> error::Result<(Progress, usize)> {
+    let invalid = |e: serde_json::Error| Error::InvalidRequest(format!("unreadable results: {e}"));
+    let mut progress = Progress {
+        total,
+        ..Default::default()
+    };
+    let mut last = 0;
+    for row in whole_lines(results).split(|it| *it == b'\n') {
+        if row.is_empty() {
+            continue;
+        }
+        let row: serde_json::Value = serde_json::from_slice(row).map_err(invalid)?;
+        last = row["line"].as_u64().map_or(last, |it| it as usize);
+        if row.get("error").is_some() {
+            progress.failed += 1;
+        } else {
+            progress.answered += 1;
+        }
+    }
+    Ok((progress, last))
+}
+
+/// Reads a batch's results, which are empty until it's started.
+fn read_results(path: &Path) -> error::Result<Vec<u8>> {
+    match fs::read(path) {
+        Ok(results) => Ok(results),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// How far the batch of requests in `input` has got, from its results.
+pub fn progress(input: &Path, results: &Path) -> error::Result<Progress> {
+    let total = requests(&fs::read_to_string(input)?).count();
+    Ok(checkpoint(&read_results(results)?, total)?.0)
+}
+
+/// Opens the results to append to, dropping a line cut off halfway through
+/// being written.
+fn open_results(path: &Path, total: usize) -> error::Result<(File, Progress, usize)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    let mut results = vec![];
+    file.read_to_end(&mut results)?;
+    let whole = whole_lines(&results);
+    if whole.len() < results.len() {
+        file.set_len(whole.len() as u64)?;
+    }
+    let (progress, last) = checkpoint(whole, total)?;
+    Ok((file, progress, last))
+}
+
+/// The code of an error from an answer's stream, which has already been
+/// turned into a GraphQL error.
+fn code(e: &async_graphql::Error) -> Option<&str> {
+    match e.extensions.as_ref().and_then(|it| it.get("code")) {
+        Some(Value::String(code)) => Some(code),
+        _ => None,
+    }
+}
+
+/// Answers batches with the assistant, which may still be loading.
+pub struct Batch<M: Model = Llama> {
+    assistant: Loadable<Assistant<M>>,
+    personas: Personas,
+    moderator: Moderator,
+    masker: Masker,
+    scheduler: Scheduler,
+    shutdown: Shutdown,
+}
+
+impl<M: Model + 'static> Batch<M> {
+    pub fn new(
+        assistant: Loadable<Assistant<M>>,
+        personas: Personas,
+        moderator: Moderator,
+        masker: Masker,
+        scheduler: Scheduler,
+        shutdown: Shutdown,
+    ) -> Self {
+        Self {
+            assistant,
+            personas,
+            moderator,
+            masker,
+            scheduler,
+            shutdown,
+        }
+    }
+
+    fn answerer(&self) -> Answerer<'_> {
+        Answerer {
+            personas: &self.personas,
+            moderator: &self.moderator,
+            masker: &self.masker,
+            scheduler: &self.scheduler,
+        }
+    }
+
+    /// Answers the requests in `input` that aren't in `results` yet, metered
+    /// as the caller's. Once a shutdown begins it stops after the answer
+    /// going, leaving the rest for next time.
+    pub async fn run(
+        &self,
+        input: &Path,
+        results: &Path,
+        caller: Option<&Caller>,
+    ) -> error::Result<Progress> {
+        let batch = fs::read_to_string(input)?;
+        let (mut file, mut progress, last) = open_results(results, requests(&batch).count())?;
+        let storage = self.assistant.wait().await?;
+        for (line, request) in requests(&batch).filter(|(line, _)| *line > last) {
+            if self.shutdown.is_draining() {
+                break;
+            }
+            let outcome = match self.answer(storage.clone(), request, caller).await {
+                Ok(answer) => Outcome::Answered(answer),
+                Err(e) if code(&e) == Some(Error::ShuttingDown.code()) => break,
+                Err(e) => Outcome::Failed {
+                    error: Failed {
+                        code: code(&e).map(Into::into),
+                        message: e.message,
+                    },
+                },
+            };
+            match outcome {
+                Outcome::Answered(_) => progress.answered += 1,
+                Outcome::Failed { .. } => progress.failed += 1,
+            }
+            let id = serde_json::from_str::<serde_json::Value>(request)
+                .ok()
+                .and_then(|it| it.get("id")?.as_str().map(Into::into));
+            let mut row =
+                serde_json::to_vec(&Row { line, id, outcome }).map_err(std::io::Error::other)?;
+            row.push(b'\n');
+            file.write_all(&row)?;
+            file.sync_data()?;
+        }
+        Ok(progress)
+    }
+
+    async fn answer(
+        &self,
+        storage: Arc<Mutex<Assistant<M>>>,
+        request: &str,
+        caller: Option<&Caller>,
+    ) -> async_graphql::Result<Answer> {
+        let job = serde_json::from_str(request)
+            .map_err(|e| Error::InvalidRequest(e.to_string()).extend())?;
+        let request_id = Some(RequestId::new());
+        let prepared = match job {
+            Job::Ask(request) => prepare_ask(self.answerer(), request, request_id).await,
+            Job::Summarize { messages, summary } => {
+                prepare_summarize(self.answerer(), messages, summary, request_id).await
+            }
+        }
+        .extend()?;
+        let tally = Arc::new(Tally::new(self.meter(caller).await.extend()?));
+        let events = prepared.at(Priority::Batch).by(caller).answer(
+            storage,
+            &self.shutdown,
+            Some(tally.clone()),
+        );
+        answer_once(events, &tally).await
+    }
+
+    /// Waits out the caller's per minute token limit, rather than failing
+    /// the rest of the batch.
+    async fn meter(&self, caller: Option<&Caller>) -> error::Result<Option<Arc<dyn Meter>>> {
+        loop {
+            match meter(caller).await {
+                Err(Error::RateLimited { retry_after, .. }) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs(retry_after)) => {}
+                        _ = self.shutdown.begun() => return Err(Error::ShuttingDown),
+                    }
+                }
+                metered => return metered,
+            }
+        }
+    }
+}
+
+/// Who uploaded a batch.
+#[derive(Deserialize, Serialize)]
+struct Owner {
+    key: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchStatus {
+    pub id: String,
+    #[serde(flatten)]
+    pub progress: Progress,
+    pub finished: bool,
+}
+
+impl BatchStatus {
+    fn new(id: &str, progress: Progress) -> Self {
+        Self {
+            id: id.into(),
+            finished: progress.finished(),
+            progress,
+        }
+    }
+}
+
+/// Uploaded batches, kept in a directory so they carry on after a restart.
+/// Each has its requests in `<id>.jsonl`, its results in
+/// `<id>.results.jsonl` and the key that uploaded it in `<id>.json`.
+pub struct Batches<M: Model = Llama> {
+    directory: PathBuf,
+    batch: Arc<Batch<M>>,
+    auth: Auth,
+}
+
+impl<M: Model> Clone for Batches<M> {
+    fn clone(&self) -> Self {
+        Self {
+            directory: self.directory.clone(),
+            batch: self.batch.clone(),
+            auth: self.auth.clone(),
+        }
+    }
+}
+
+impl<M: Model + 'static> Batches<M> {
+    pub fn new(directory: &Path, batch: Batch<M>, auth: Auth) -> error::Result<Self> {
+        fs::create_dir_all(directory)?;
+        Ok(Self {
+            directory: directory.into(),
+            batch: Arc::new(batch),
+            auth,
+        })
+    }
+
+    fn path(&self, id: &str, extension: &str) -> PathBuf {
+        self.directory.join(format!("{id}.{extension}"))
+    }
+
+    /// Starts answering the requests in `jsonl` in the background, metered
+    /// as the caller's.
+    pub fn submit(&self, jsonl: &[u8], caller: Option<&Caller>) -> error::Result<BatchStatus> {
+        let batch = std::str::from_utf8(jsonl).map_err(|e| Error::InvalidRequest(e.to_string()))?;
+        let total = requests(batch).count();
+        if total == 0 {
+            return Err(Error::InvalidRequest("the batch has no requests".into()));
+        }
+        let id = RequestId::new().to_string();
+        let owner = Owner {
+            key: caller.map(|it| it.name().into()),
+        };
+        let owner = serde_json::to_vec(&owner).map_err(std::io::Error::other)?;
+        fs::write(self.path(&id, "json"), owner)?;
+        fs::write(self.path(&id, "jsonl"), jsonl)?;
+        self.spawn(&id, caller.cloned());
+        Ok(BatchStatus::new(
+            &id,
+            Progress {
+                total,
+                ..Default::default()
+            },
+        ))
+    }
+
+    pub fn status(&self, id: &str, caller: Option<&Caller>) -> error::Result<BatchStatus> {
+        self.owner(id, caller)?;
+        let progress = progress(&self.path(id, "jsonl"), &self.path(id, "results.jsonl"))?;
+        Ok(BatchStatus::new(id, progress))
+    }
+
+    /// The results so far, as JSONL.
+    pub fn results(&self, id: &str, caller: Option<&Caller>) -> error::Result<Vec<u8>> {
+        self.owner(id, caller)?;
+        let mut results = read_results(&self.path(id, "results.jsonl"))?;
+        results.truncate(whole_lines(&results).len());
+        Ok(results)
+    }
+
+    /// Carries on with the batches that hadn't finished, as the keys that
+    /// uploaded them. Batches that can't be read are left as they are.
+    pub fn resume(&self) -> error::Result<()> {
+        for entry in fs::read_dir(&self.directory)? {
+            let name = entry?.file_name();
+            let Some(id) = name.to_str().and_then(|it| it.strip_suffix(".jsonl")) else {
+                continue;
+            };
+            if id.contains('.') {
+                continue;
+            }
+            let key = match self.owner(id, None) {
+                Ok(owner) => owner.key,
+                Err(e) => {
+                    warn!(batch = id, error = %e, "skipping a batch that can't be read");
+                    continue;
+                }
+            };
+            let caller = match &key {
+                Some(key) => match self.auth.named(key) {
+                    Some(caller) => Some(caller),
+                    None => {
+                        warn!(
+                            batch = id,
+                            key, "the batch's key is gone, leaving it unfinished"
+                        );
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            match self.status(id, None) {
+                Ok(status) if status.finished => continue,
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(batch = id, error = %e, "skipping a batch that can't be read");
+                    continue;
+                }
+            }
+            info!(batch = id, "resuming batch");
+            self.spawn(id, caller);
+        }
+        Ok(())
+    }
+
+    fn spawn(&self, id: &str, caller: Option<Caller>) {
+        let batch = self.batch.clone();
+        let (input, results) = (self.path(id, "jsonl"), self.path(id, "results.jsonl"));
+        let id = id.to_string();
+        tokio::spawn(async move {
+            match batch.run(&input, &results, caller.as_ref()).await {
+                Ok(progress) => info!(
+                    batch = id,
+                    answered = progress.answered,
+                    failed = progress.failed,
+                    finished = progress.finished(),
+                    "batch stopped"
+                ),
+                Err(e) => warn!(batch = id, error = %e, "batch failed"),
+            }
+        });
+    }
+
+    /// Who uploaded the batch, as long as it's the caller or the caller is an
+    /// admin.
+    fn owner(&self, id: &str, caller: Option<&Caller>) -> error::Result<Owner> {
+        let not_found = || Error::NotFound(format!("batch {id}"));
+        if id.is_empty() || !id.bytes().all(|it| it.is_ascii_hexdigit()) {
+            return Err(not_found());
+        }
+        let owner = match fs::read(self.path(id, "json")) {
+            Ok(owner) => owner,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(not_found()),
+            Err(e) => return Err(e.into()),
+        };
+        let owner: Owner = serde_json::from_slice(&owner).map_err(std::io::Error::other)?;
+        if let (Some(caller), Some(key)) = (caller, &owner.key) {
+            if caller.name() != key && !caller.is_admin() {
+                return Err(Error::Forbidden("see another key's batch"));
+            }
+        }
+        Ok(owner)
+    }
+}
+
+fn batches(state: &AppState) -> error::Result<&Batches> {
+    state.batches.as_ref().ok_or(Error::Disabled("batching"))
+}
+
+/// Starts answering the JSONL file in the `file` field of a multipart upload
+/// as a batch.
+pub(crate) async fn upload(
+    State(state): State<AppState>,
+    Authenticated(caller): Authenticated,
+    mut multipart: Multipart,
+) -> error::Result<Json<BatchStatus>> {
+    let batches = batches(&state)?;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| Error::InvalidRequest(e.to_string()))?
+    {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let jsonl = field
+            .bytes()
+            .await
+            .map_err(|e| Error::InvalidRequest(e.to_string()))?;
+        return batches.submit(&jsonl, caller.as_ref()).map(Json);
+    }
+    Err(Error::InvalidRequest("missing file field".into()))
+}
+
+pub(crate) async fn status(
+    State(state): State<AppState>,
+    Authenticated(caller): Authenticated,
+    extract::Path(id): extract::Path<String>,
+) -> error::Result<Json<BatchStatus>> {
+    batches(&state)?.status(&id, caller.as_ref()).map(Json)
+}
+
+/// The results so far, which are complete once the batch has finished.
+pub(crate) async fn results(
+    State(state): State<AppState>,
+    Authenticated(caller): Authenticated,
+    extract::Path(id): extract::Path<String>,
+) -> error::Result<impl IntoResponse> {
+    let results = batches(&state)?.results(&id, caller.as_ref())?;
+    Ok(([(CONTENT_TYPE, "application/x-ndjson")], results))
+}
@@ -0,0 +1,157 @@
+//! Measures how quickly a model reads prompts and generates tokens.
+use std::{fs, time::Instant};
+
+use candle_core::{Device, Tensor};
+use candle_transformers::models::llama::Config;
+use serde::Serialize;
+
+use crate::{
+    config::{DeviceKind, Precision},
+    error::{Error, Result},
+    model::Model,
+};
+
+/// Everything a benchmark measured, in the shape `persephone bench --json`
+/// prints it.
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub model: String,
+    pub cpu: CpuFeatures,
+    pub runs: Vec<Run>,
+}
+
+/// What a case ran on.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Setup {
+    pub dtype: Precision,
+    pub device: DeviceKind,
+    pub threads: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Run {
+    #[serde(flatten)]
+    pub setup: Setup,
+    #[serde(flatten)]
+    pub measurement: Measurement,
+}
+
+/// A prompt of `prompt_tokens` tokens followed by `output_tokens` generated ones.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Case {
+    pub prompt_tokens: usize,
+    pub output_tokens: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Measurement {
+    #[serde(flatten)]
+    pub case: Case,
+    /// Prompt tokens read per second
+    pub prefill_tokens_per_second: f64,
+    /// Tokens generated per second after the first
+    pub decode_tokens_per_second: f64,
+    pub time_to_first_token_ms: f64,
+    /// The most memory the process held during the case, where the OS says
+    pub peak_memory_bytes: Option<u64>,
+}
+
+/// Which CPU features candle was built to use.
+#[derive(Clone, Debug, Serialize)]
+pub struct CpuFeatures {
+    pub avx: bool,
+    pub neon: bool,
+    pub simd128: bool,
+    pub f16c: bool,
+}
+
+impl CpuFeatures {
+    pub fn detect() -> Self {
+        Self {
+            avx: candle_core::utils::with_avx(),
+            neon: candle_core::utils::with_neon(),
+            simd128: candle_core::utils::with_simd128(),
+            f16c: candle_core::utils::with_f16c(),
+        }
+    }
+}
+
+/// Reads a prompt and then greedily generates tokens one at a time, timing
+/// both. The prompt is made up tokens, as speed doesn't depend on them.
+pub fn measure<M: Model>(
+    model: &M,
+    config: &Config,
+    device: &Device,
+    case: Case,
+) -> Result<Measurement> {
+    let limit = config.max_position_embeddings;
+    let tokens = case.prompt_tokens + case.output_tokens;
+    if tokens > limit {
+        return Err(Error::ContextOverflow { tokens, limit });
+    }
+    if case.prompt_tokens == 0 || case.output_tokens == 0 {
+        return Err(Error::InvalidRequest(
+            "cases need at least one prompt and one output token".into(),
+        ));
+    }
+    reset_peak_memory();
+    let prompt: Vec<u32> = (0..case.prompt_tokens)
+        .map(|i| (i % config.vocab_size) as u32)
+        .collect();
+    let mut cache = model.cache(config, device)?;
+    let start = Instant::now();
+    let logits = model.forward(
+        &Tensor::new(prompt.as_slice(), device)?.unsqueeze(0)?,
+        &mut cache,
+    )?;
+    let mut next = greedy(&logits)?;
+    let first_token = start.elapsed();
+    let start = Instant::now();
+    for _ in 1..case.output_tokens {
+        let logits = model.forward(&Tensor::new(&[next], device)?.unsqueeze(0)?, &mut cache)?;
+        next = greedy(&logits)?;
+    }
+    let decode = start.elapsed();
+    let decoded = (case.output_tokens - 1) as f64;
+    Ok(Measurement {
+        case,
+        prefill_tokens_per_second: case.prompt_tokens as f64 / first_token.as_secs_f64(),
+        decode_tokens_per_second: if decoded > 0.0 {
+            decoded / decode.as_secs_f64()
+        } else {
+            0.0
+        },
+        time_to_first_token_ms: first_token.as_secs_f64() * 1000.0,
+        peak_memory_bytes: peak_memory(),
+    })
+}
+
+/// Runs `f` in a pool of `threads` threads, which candle's CPU kernels run
+/// on.
+pub fn with_threads<T: Send>(threads: usize, f: impl FnOnce() -> T + Send) -> Result<T> {
+    if threads == 0 {
+        return Err(Error::InvalidRequest("threads must be at least one".into()));
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|e| Error::Inference(e.into()))?;
+    Ok(pool.install(f))
+}
+
+fn greedy(logits: &Tensor) -> Result<u32> {
+    Ok(logits.squeeze(0)?.argmax(0)?.to_scalar::<u32>()?)
+}
+
+/// Starts counting peak memory afresh, on Linux.
+fn reset_peak_memory() {
+    let _ = fs::write("/proc/self/clear_refs", "5");
+}
+
+/// The process's peak resident memory, on Linux.
+fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|it| it.starts_with("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
@@ -0,0 +1,141 @@
+//! A conversation at the terminal, for trying out prompts and models.
+use std::{fs, io::Write, path::PathBuf, pin::pin};
+
+use futures_util::StreamExt;
+
+use crate::{
+    assistant::{AnswerOptions, Assistant, Sampling},
+    error::{Error, Result},
+    model::Model,
+    persona::Persona,
+    server::Message,
+};
+
+pub const HELP: &str = "\
+/reset          forget the conversation
+/system <text>  answer with this system prompt
+/temp <value>   sample with this temperature
+/save <path>    save the conversation as JSON
+/help           show this
+/quit           leave";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Reset,
+    System(String),
+    Temperature(f64),
+    Save(PathBuf),
+    Help,
+    Quit,
+}
+
+impl Command {
+    /// Parses a slash command, or returns `None` when the line is a question.
+    pub fn parse(line: &str) -> Option<Result<Command>> {
+        let line = line.trim().strip_prefix('/')?;
+        let (name, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim();
+        let missing = |what: &str| Error::InvalidRequest(format!("/{name} needs {what}"));
+        Some(match name {
+            "reset" => Ok(Command::Reset),
+            "system" if argument.is_empty() => Err(missing("a prompt")),
+            "system" => Ok(Command::System(argument.into())),
+            "temp" => argument
+                .parse()
+                .map(Command::Temperature)
+                .map_err(|_| missing("a number")),
+            "save" if argument.is_empty() => Err(missing("a path")),
+            "save" => Ok(Command::Save(argument.into())),
+            "help" => Ok(Command::Help),
+            "quit" | "exit" => Ok(Command::Quit),
+            _ => Err(Error::InvalidRequest(format!(
+                "there's no /{name} command, try /help"
+            ))),
+        })
+    }
+}
+
+/// The conversation so far and how it is being answered.
+pub struct Chat {
+    persona: Persona,
+    sampling: Sampling,
+    history: Vec<Message>,
+}
+
+impl Chat {
+    pub fn new(persona: Persona, sampling: Sampling) -> Self {
+        let sampling = persona.sampling.unwrap_or(sampling);
+        Self {
+            persona,
+            sampling,
+            history: vec![],
+        }
+    }
+
+    pub fn history(&self) -> &[Message] {
+        &self.history
+    }
+
+    /// Carries out a command, returning what to tell the user.
+    pub fn run(&mut self, command: Command) -> Result<String> {
+        match command {
+            Command::Reset => {
+                self.history.clear();
+                Ok("Forgot the conversation".into())
+            }
+            Command::System(system) => {
+                self.persona.system = system;
+                Ok("Changed the system prompt".into())
+            }
+            Command::Temperature(temperature) => {
+                let sampling = Sampling {
+                    temperature,
+                    ..self.sampling
+                };
+                sampling.validate()?;
+                self.sampling = sampling;
+                Ok(format!("Sampling with temperature {temperature}"))
+            }
+            Command::Save(path) => {
+                let json = serde_json::to_string_pretty(&self.history)
+                    .map_err(|e| Error::InvalidRequest(e.to_string()))?;
+                fs::write(&path, json)?;
+                Ok(format!("Saved the conversation to {}", path.display()))
+            }
+            Command::Help => Ok(HELP.into()),
+            Command::Quit => Ok(String::new()),
+        }
+    }
+
+    /// Answers the question, writing the answer to `out` as it's generated
+    /// and remembering both.
+    pub async fn say<M: Model>(
+        &mut self,
+        assistant: &Assistant<M>,
+        question: &str,
+        out: &mut impl Write,
+    ) -> Result<String> {
+        let prompt = self.persona.ask(question, &self.history, None);
+        let options = AnswerOptions {
+            sampling: Some(self.sampling),
+            ..Default::default()
+        };
+        let mut tokens = pin!(assistant.answer_with(prompt, options).await?);
+        let mut answer = String::new();
+        while let Some(token) = tokens.next().await {
+            let token = token?;
+            write!(out, "{token}")?;
+            out.flush()?;
+            answer.push_str(&token);
+        }
+        self.history.push(Message {
+            author: "user".into(),
+            message: question.into(),
+        });
+        self.history.push(Message {
+            author: "assistant".into(),
+            message: answer.clone(),
+        });
+        Ok(answer)
+    }
+}
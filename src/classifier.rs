@@ -0,0 +1,167 @@
+//! Small BERT models: a sequence classifier for moderation, such as a
+//! toxicity model, and a token classifier that finds names for PII masking.
+use std::{collections::HashMap, ops::Range};
+
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::{linear, ops::sigmoid, ops::softmax_last_dim, Linear, VarBuilder};
+use candle_transformers::models::bert::{BertModel, Config};
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+use crate::{
+    error::{Error, Result},
+    moderation::Classifier,
+    pii::Recognizer,
+};
+
+/// The labels, by index.
+fn labels(id2label: HashMap<String, String>) -> Vec<String> {
+    let mut labels: Vec<_> = id2label
+        .into_iter()
+        .filter_map(|(id, label)| Some((id.parse::<usize>().ok()?, label)))
+        .collect();
+    labels.sort();
+    labels.into_iter().map(|(_, label)| label).collect()
+}
+
+/// The parts of a Hugging Face classifier's config the head needs.
+#[derive(Deserialize)]
+pub struct HeadConfig {
+    pub id2label: HashMap<String, String>,
+    /// `multi_label_classification` scores labels independently
+    pub problem_type: Option<String>,
+}
+
+pub struct TextClassifier {
+    bert: BertModel,
+    pooler: Linear,
+    classifier: Linear,
+    labels: Vec<String>,
+    multi_label: bool,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl TextClassifier {
+    /// Loads a `BertForSequenceClassification` model.
+    pub fn load(
+        vb: VarBuilder,
+        config: &Config,
+        head: HeadConfig,
+        tokenizer: Tokenizer,
+    ) -> candle_core::Result<Self> {
+        let labels = labels(head.id2label);
+        let bert = BertModel::load(vb.pp("bert"), config)?;
+        let pooler = linear(
+            config.hidden_size,
+            config.hidden_size,
+            vb.pp("bert.pooler.dense"),
+        )?;
+        let classifier = linear(config.hidden_size, labels.len(), vb.pp("classifier"))?;
+        Ok(Self {
+            bert,
+            pooler,
+            classifier,
+            multi_label: head.problem_type.as_deref() == Some("multi_label_classification"),
+            labels,
+            tokenizer,
+            device: vb.device().clone(),
+        })
+    }
+
+    fn scores(&self, ids: &[u32]) -> candle_core::Result<Vec<f32>> {
+        let ids = Tensor::new(ids, &self.device)?.unsqueeze(0)?;
+        let types = ids.zeros_like()?;
+        let hidden = self.bert.forward(&ids, &types, None)?;
+        // The pooler reads the [CLS] token
+        let cls = hidden.narrow(1, 0, 1)?.squeeze(1)?;
+        let pooled = self.pooler.forward(&cls)?.tanh()?;
+        let logits = self.classifier.forward(&pooled)?.to_dtype(DType::F32)?;
+        let scores = if self.multi_label {
+            sigmoid(&logits)?
+        } else {
+            softmax_last_dim(&logits)?
+        };
+        scores.squeeze(0)?.to_vec1()
+    }
+}
+
+impl Classifier for TextClassifier {
+    fn classify(&mut self, text: &str) -> Result<Vec<(String, f32)>> {
+        let encoding = self
+            .tokenizer
+            .encode(text, true)
+            .map_err(|e| Error::Tokenization(e.to_string()))?;
+        let scores = self
+            .scores(encoding.get_ids())
+            .map_err(|e| Error::Inference(e.into()))?;
+        Ok(self.labels.iter().cloned().zip(scores).collect())
+    }
+}
+
+/// Tags each token, like `B-PER` for the start of a person's name.
+pub struct EntityTagger {
+    bert: BertModel,
+    classifier: Linear,
+    labels: Vec<String>,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl EntityTagger {
+    /// Loads a `BertForTokenClassification` model.
+    pub fn load(
+        vb: VarBuilder,
+        config: &Config,
+        head: HeadConfig,
+        tokenizer: Tokenizer,
+    ) -> candle_core::Result<Self> {
+        let labels = labels(head.id2label);
+        let bert = BertModel::load(vb.pp("bert"), config)?;
+        let classifier = linear(config.hidden_size, labels.len(), vb.pp("classifier"))?;
+        Ok(Self {
+            bert,
+            classifier,
+            labels,
+            tokenizer,
+            device: vb.device().clone(),
+        })
+    }
+
+    /// The most likely label's index for each token.
+    fn tags(&self, ids: &[u32]) -> candle_core::Result<Vec<u32>> {
+        let ids = Tensor::new(ids, &self.device)?.unsqueeze(0)?;
+        let types = ids.zeros_like()?;
+        let hidden = self.bert.forward(&ids, &types, None)?;
+        let logits = self.classifier.forward(&hidden)?;
+        logits.squeeze(0)?.argmax(1)?.to_vec1()
+    }
+}
+
+impl Recognizer for EntityTagger {
+    fn names(&mut self, text: &str) -> Result<Vec<Range<usize>>> {
+        let encoding = self
+            .tokenizer
+            .encode(text, true)
+            .map_err(|e| Error::Tokenization(e.to_string()))?;
+        let tags = self
+            .tags(encoding.get_ids())
+            .map_err(|e| Error::Inference(e.into()))?;
+        let mut names: Vec<Range<usize>> = vec![];
+        let mut inside = false;
+        for (&tag, &(start, end)) in tags.iter().zip(encoding.get_offsets()) {
+            let label = self.labels.get(tag as usize).map(String::as_str);
+            // Special tokens have empty offsets
+            match (label, names.last_mut()) {
+                _ if start == end => inside = false,
+                (Some("I-PER"), Some(name)) if inside => name.end = end,
+                (Some("B-PER" | "I-PER"), _) => {
+                    names.push(start..end);
+                    inside = true;
+                }
+                _ => inside = false,
+            }
+        }
+        Ok(names)
+    }
+}
@@ -0,0 +1,259 @@
+//! Server settings, read from a TOML file and overridden by environment
+//! variables named `PERSEPHONE_<SECTION>__<KEY>`, for example
+//! `PERSEPHONE_SERVER__LISTEN=127.0.0.1:8080` or
+//! `PERSEPHONE_SAMPLING__TEMPERATURE=0.5`.
+use std::{net::SocketAddr, path::Path};
+
+use candle_core::{
+    utils::{cuda_is_available, metal_is_available},
+    DType,
+};
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+
+use crate::{
+    assistant::Sampling,
+    auth::AuthSettings,
+    batch::BatchSettings,
+    error::{Error, Result},
+    loading::{DRAFT_REPO, MODEL_REPO, TOKENIZER_REPO, VOICE_REPO, WHISPER_REPO},
+    moderation::ModerationSettings,
+    persona::{PersonaSettings, Personas},
+    pii::PiiSettings,
+    scheduler::SchedulerSettings,
+    telemetry::LogSettings,
+    usage::UsageSettings,
+};
+
+const ENV_PREFIX: &str = "PERSEPHONE_";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub model: ModelSettings,
+    pub sampling: Sampling,
+    pub personas: PersonaSettings,
+    pub limits: Limits,
+    pub features: Features,
+    pub logging: LogSettings,
+    pub auth: AuthSettings,
+    pub usage: UsageSettings,
+    pub moderation: ModerationSettings,
+    pub pii: PiiSettings,
+    pub batch: BatchSettings,
+    pub scheduler: SchedulerSettings,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub listen: String,
+    /// Where to serve gRPC as well, which needs the `grpc` feature
+    pub grpc_listen: Option<String>,
+    /// How long answers get to finish when shutting down before they're cut
+    /// off
+    pub shutdown_deadline_seconds: u64,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:8000".into(),
+            grpc_listen: None,
+            shutdown_deadline_seconds: 30,
+        }
+    }
+}
+
+/// Where models come from and what they run on.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelSettings {
+    pub repo: String,
+    pub tokenizer_repo: String,
+    pub draft_repo: String,
+    pub voice_repo: String,
+    pub whisper_repo: String,
+    /// Decode speculatively, with the draft model proposing this many tokens
+    /// at a time
+    pub speculate: Option<usize>,
+    pub dtype: Precision,
+    pub device: DeviceKind,
+}
+
+impl Default for ModelSettings {
+    fn default() -> Self {
+        Self {
+            repo: MODEL_REPO.into(),
+            tokenizer_repo: TOKENIZER_REPO.into(),
+            draft_repo: DRAFT_REPO.into(),
+            voice_repo: VOICE_REPO.into(),
+            whisper_repo: WHISPER_REPO.into(),
+            speculate: None,
+            dtype: Precision::F16,
+            device: DeviceKind::Auto,
+        }
+    }
+}
+
+/// The type the language models' weights are loaded as.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    F16,
+    Bf16,
+    F32,
+}
+
+impl Precision {
+    pub fn dtype(self) -> DType {
+        match self {
+            Precision::F16 => DType::F16,
+            Precision::Bf16 => DType::BF16,
+            Precision::F32 => DType::F32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    /// Metal or CUDA when available, otherwise the CPU
+    #[default]
+    Auto,
+    Cpu,
+    Cuda,
+    Metal,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// The most tokens in an answer, otherwise answers run until the context
+    /// is full
+    pub max_tokens: Option<usize>,
+    /// The largest request body, which bounds uploaded audio
+    pub max_upload_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_tokens: None,
+            max_upload_bytes: 25 * 1024 * 1024,
+        }
+    }
+}
+
+/// Parts of the server that can be turned off, skipping their models.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    pub graphiql: bool,
+    /// Speaking answers, `/speak` and the `speak` query
+    pub voice: bool,
+    /// Transcribing audio, `/transcribe` and the `transcribe` mutation
+    pub transcription: bool,
+    /// Prometheus metrics at `/metrics`
+    pub metrics: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            graphiql: true,
+            voice: true,
+            transcription: true,
+            metrics: true,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the settings from `path`, or the defaults when there isn't one,
+    /// applies the process's environment and validates them.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let toml = match path {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|e| Error::Config(format!("can't read {}: {e}", path.display())))?,
+            None => String::new(),
+        };
+        Self::parse(&toml, std::env::vars())
+    }
+
+    /// Parses settings from TOML, overriding them with any `PERSEPHONE_`
+    /// variables in `env`, and validates them.
+    pub fn parse(toml: &str, env: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let mut table: Table = toml::from_str(toml).map_err(|e| Error::Config(e.to_string()))?;
+        for (key, value) in env {
+            let Some(key) = key.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let Some((name, key)) = key.split_once("__") else {
+                continue;
+            };
+            let section = table
+                .entry(name.to_lowercase())
+                .or_insert_with(|| Value::Table(Table::new()));
+            let Value::Table(section) = section else {
+                return Err(Error::Config(format!("{name} isn't a section")));
+            };
+            section.insert(key.to_lowercase(), env_value(&value));
+        }
+        let settings: Settings = Value::Table(table)
+            .try_into()
+            .map_err(|e: toml::de::Error| Error::Config(e.to_string()))?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| Err(Error::Config(message.into()));
+        if self.server.listen.parse::<SocketAddr>().is_err() {
+            return Err(Error::Config(format!(
+                "can't listen on {}, it should look like 0.0.0.0:8000",
+                self.server.listen
+            )));
+        }
+        if let Some(listen) = &self.server.grpc_listen {
+            if !cfg!(feature = "grpc") {
+                return invalid("grpc_listen needs persephone built with the grpc feature");
+            }
+            if listen.parse::<SocketAddr>().is_err() {
+                return Err(Error::Config(format!(
+                    "can't serve gRPC on {listen}, it should look like 0.0.0.0:50051"
+                )));
+            }
+        }
+        if self.model.speculate == Some(0) {
+            return invalid("speculate must propose at least one token");
+        }
+        match self.model.device {
+            DeviceKind::Cuda if !cuda_is_available() => return invalid("CUDA isn't available"),
+            DeviceKind::Metal if !metal_is_available() => return invalid("Metal isn't available"),
+            _ => {}
+        }
+        if self.limits.max_tokens == Some(0) {
+            return invalid("max_tokens must be at least one");
+        }
+        if self.limits.max_upload_bytes == 0 {
+            return invalid("max_upload_bytes must be at least one");
+        }
+        self.sampling.validate()?;
+        self.logging.filter()?;
+        self.auth.validate()?;
+        self.moderation.validate()?;
+        self.scheduler.validate()?;
+        Personas::load(&self.personas).map(|_| ())
+    }
+}
+
+/// Reads an environment variable as a TOML value, or as a string when it
+/// isn't one.
+fn env_value(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut it| it.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.into()))
+}
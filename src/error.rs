@@ -0,0 +1,133 @@
+use async_graphql::ErrorExtensions;
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+};
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("model failed to load: {0}")]
+    ModelLoad(anyhow::Error),
+    #[error("tokenization failed: {0}")]
+    Tokenization(String),
+    #[error("inference failed: {0}")]
+    Inference(anyhow::Error),
+    #[error("the conversation needs {tokens} tokens, more than the model's {limit}")]
+    ContextOverflow { tokens: usize, limit: usize },
+    #[error("the request was cancelled")]
+    Cancelled,
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    #[error("{0} is turned off")]
+    Disabled(&'static str),
+    #[error("server shutting down")]
+    ShuttingDown,
+    #[error("a valid API key is needed")]
+    Unauthorized,
+    #[error("the key's {limit} limit is used up, try again in {retry_after}s")]
+    RateLimited {
+        limit: &'static str,
+        retry_after: u64,
+    },
+    #[error("the key's monthly budget of {budget} tokens is used up")]
+    QuotaExceeded { budget: u64 },
+    #[error("this key can't {0}")]
+    Forbidden(&'static str),
+    #[error("{0} wasn't found")]
+    NotFound(String),
+    #[error("refused by moderation: {0}")]
+    Refused(String),
+    #[error("usage store failed: {0}")]
+    Store(anyhow::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl Error {
+    /// A stable machine readable name for the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::ModelLoad(_) => "MODEL_LOAD",
+            Error::Tokenization(_) => "TOKENIZATION",
+            Error::Inference(_) => "INFERENCE",
+            Error::ContextOverflow { .. } => "CONTEXT_OVERFLOW",
+            Error::Cancelled => "CANCELLED",
+            Error::InvalidRequest(_) => "INVALID_REQUEST",
+            Error::Config(_) => "CONFIG",
+            Error::Disabled(_) => "DISABLED",
+            Error::ShuttingDown => "SHUTTING_DOWN",
+            Error::Unauthorized => "UNAUTHORIZED",
+            Error::RateLimited { .. } => "RATE_LIMITED",
+            Error::QuotaExceeded { .. } => "QUOTA_EXCEEDED",
+            Error::Forbidden(_) => "FORBIDDEN",
+            Error::NotFound(_) => "NOT_FOUND",
+            Error::Refused(_) => "REFUSED",
+            Error::Store(_) => "STORE",
+            Error::Io(_) => "IO",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::ModelLoad(_) | Error::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Tokenization(_) | Error::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Inference(_) | Error::Config(_) | Error::Store(_) | Error::Io(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            Error::Disabled(_) | Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::ContextOverflow { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::RateLimited { .. } | Error::QuotaExceeded { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::Refused(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            // nginx's client closed request
+            Error::Cancelled => StatusCode::from_u16(499).unwrap_or(StatusCode::BAD_REQUEST),
+        }
+    }
+}
+
+impl From<candle_core::Error> for Error {
+    fn from(e: candle_core::Error) -> Self {
+        Error::Inference(e.into())
+    }
+}
+
+impl ErrorExtensions for Error {
+    fn extend(&self) -> async_graphql::Error {
+        async_graphql::Error::new(self.to_string()).extend_with(|_, e| {
+            e.set("code", self.code());
+            if let Error::ContextOverflow { tokens, limit } = self {
+                e.set("tokens", *tokens);
+                e.set("limit", *limit);
+            }
+            if let Error::RateLimited { retry_after, .. } = self {
+                e.set("retryAfter", *retry_after);
+            }
+            if let Error::Refused(rule) = self {
+                e.set("rule", rule.as_str());
+            }
+            if let Error::QuotaExceeded { budget } = self {
+                e.set("budget", *budget);
+            }
+        })
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), format!("{}: {}", self.code(), self)).into_response();
+        if let Error::RateLimited { retry_after, .. } = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, retry_after.into());
+        }
+        response
+    }
+}
@@ -0,0 +1,409 @@
+//! The assistant over gRPC, with the `grpc` feature, for services that don't
+//! speak GraphQL. It's served on `[server] grpc_listen` next to the HTTP API,
+//! and answers go through the same personas, moderation, masking and API keys,
+//! which come in `authorization: Bearer` or `x-api-key` metadata. The messages
+//! are written here by hand so building doesn't need protoc;
+//! proto/persephone.proto describes the same service for clients.
+use std::{pin::Pin, sync::Arc};
+
+use async_graphql::Value;
+use async_stream::stream;
+use futures_util::{Stream, StreamExt};
+use tokio::net::TcpListener;
+use tonic::{
+    transport::{server::TcpIncoming, Server},
+    Code, Request, Response, Status,
+};
+
+use crate::{
+    assistant::{self, AnswerOptions, Tally},
+    auth::{Auth, Caller},
+    error::{self, Error},
+    llama::Llama,
+    model::Model,
+    moderation::{self, Moderator},
+    persona::Personas,
+    pii::Masker,
+    readiness::Loadable,
+    scheduler::Scheduler,
+    server::{
+        self, acquire_with, answer_once, meter, prepare_ask, prepare_summarize, AnswerEvent,
+        AnswerUsage, Answerer, AskRequest, Prepared,
+    },
+    shutdown::Shutdown,
+    telemetry::{RequestId, REQUEST_ID},
+};
+
+include!(concat!(env!("OUT_DIR"), "/persephone.Assistant.rs"));
+
+use assistant_server::{Assistant as AssistantRpc, AssistantServer};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Message {
+    #[prost(string, tag = "1")]
+    pub author: String,
+    #[prost(string, tag = "2")]
+    pub message: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GenerateRequest {
+    #[prost(string, tag = "1")]
+    pub prompt: String,
+    #[prost(message, repeated, tag = "2")]
+    pub messages: Vec<Message>,
+    #[prost(string, optional, tag = "3")]
+    pub summary: Option<String>,
+    /// The id of the persona to answer as, otherwise the default one
+    #[prost(string, optional, tag = "4")]
+    pub persona: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SummarizeRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub messages: Vec<Message>,
+    #[prost(string, optional, tag = "2")]
+    pub summary: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Usage {
+    #[prost(uint64, tag = "1")]
+    pub prompt_tokens: u64,
+    #[prost(uint64, tag = "2")]
+    pub completion_tokens: u64,
+    #[prost(uint64, tag = "3")]
+    pub total_tokens: u64,
+}
+
+/// Something moderation did, like `redact` on `output`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Decision {
+    #[prost(string, tag = "1")]
+    pub rule: String,
+    #[prost(string, tag = "2")]
+    pub stage: String,
+    #[prost(string, tag = "3")]
+    pub action: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GenerateReply {
+    #[prost(string, tag = "1")]
+    pub text: String,
+    #[prost(message, optional, tag = "2")]
+    pub usage: Option<Usage>,
+    #[prost(message, repeated, tag = "3")]
+    pub moderation: Vec<Decision>,
+    /// Whether moderation stopped the answer early
+    #[prost(bool, tag = "4")]
+    pub stopped: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Finished {
+    #[prost(message, optional, tag = "1")]
+    pub usage: Option<Usage>,
+    #[prost(message, repeated, tag = "2")]
+    pub moderation: Vec<Decision>,
+    #[prost(bool, tag = "3")]
+    pub stopped: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GenerateChunk {
+    #[prost(oneof = "Event", tags = "1, 2")]
+    pub event: Option<Event>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Event {
+    #[prost(string, tag = "1")]
+    Token(String),
+    #[prost(message, tag = "2")]
+    Finished(Finished),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct EmbedRequest {
+    #[prost(string, tag = "1")]
+    pub text: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct EmbedReply {
+    #[prost(float, repeated, tag = "1")]
+    pub embedding: Vec<f32>,
+}
+
+impl From<Message> for server::Message {
+    fn from(message: Message) -> Self {
+        Self {
+            author: message.author,
+            message: message.message,
+        }
+    }
+}
+
+impl From<GenerateRequest> for AskRequest {
+    fn from(request: GenerateRequest) -> Self {
+        Self {
+            prompt: request.prompt,
+            messages: request.messages.into_iter().map(Into::into).collect(),
+            summary: request.summary,
+            persona: request.persona,
+            options: None,
+        }
+    }
+}
+
+impl From<AnswerUsage> for Usage {
+    fn from(usage: AnswerUsage) -> Self {
+        Self {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+        }
+    }
+}
+
+impl From<moderation::Decision> for Decision {
+    fn from(decision: moderation::Decision) -> Self {
+        Self {
+            rule: decision.rule,
+            stage: decision.stage.as_str().into(),
+            action: decision.action.as_str().into(),
+        }
+    }
+}
+
+/// The gRPC code for one of the errors' codes.
+fn code(code: &str) -> Code {
+    match code {
+        "INVALID_REQUEST" | "CONTEXT_OVERFLOW" | "TOKENIZATION" => Code::InvalidArgument,
+        "UNAUTHORIZED" => Code::Unauthenticated,
+        "FORBIDDEN" => Code::PermissionDenied,
+        "REFUSED" => Code::FailedPrecondition,
+        "RATE_LIMITED" | "QUOTA_EXCEEDED" => Code::ResourceExhausted,
+        "DISABLED" => Code::Unimplemented,
+        "NOT_FOUND" => Code::NotFound,
+        "SHUTTING_DOWN" | "MODEL_LOAD" => Code::Unavailable,
+        "CANCELLED" => Code::Cancelled,
+        _ => Code::Internal,
+    }
+}
+
+impl From<Error> for Status {
+    fn from(e: Error) -> Self {
+        Status::new(code(e.code()), format!("{}: {}", e.code(), e))
+    }
+}
+
+/// Errors from an answer's stream have already been turned into GraphQL
+/// errors, with their code as an extension.
+fn status(e: async_graphql::Error) -> Status {
+    match e.extensions.as_ref().and_then(|it| it.get("code")) {
+        Some(Value::String(c)) => Status::new(code(c), format!("{c}: {}", e.message)),
+        _ => Status::internal(e.message),
+    }
+}
+
+type Chunks = Pin<Box<dyn Stream<Item = Result<GenerateChunk, Status>> + Send>>;
+
+/// Answers with the assistant, which may still be loading.
+pub struct AssistantService<M: Model = Llama> {
+    assistant: Loadable<assistant::Assistant<M>>,
+    personas: Personas,
+    moderator: Moderator,
+    masker: Masker,
+    scheduler: Scheduler,
+    shutdown: Shutdown,
+    auth: Auth,
+}
+
+impl<M: Model + 'static> AssistantService<M> {
+    pub fn new(
+        assistant: Loadable<assistant::Assistant<M>>,
+        personas: Personas,
+        moderator: Moderator,
+        masker: Masker,
+        scheduler: Scheduler,
+        shutdown: Shutdown,
+        auth: Auth,
+    ) -> Self {
+        Self {
+            assistant,
+            personas,
+            moderator,
+            masker,
+            scheduler,
+            shutdown,
+            auth,
+        }
+    }
+
+    fn answerer(&self) -> Answerer<'_> {
+        Answerer {
+            personas: &self.personas,
+            moderator: &self.moderator,
+            masker: &self.masker,
+            scheduler: &self.scheduler,
+        }
+    }
+
+    /// Checks the caller's key, counting the request against its limit.
+    fn caller<T>(&self, request: &Request<T>) -> error::Result<Option<Caller>> {
+        let caller = self
+            .auth
+            .from_headers(&request.metadata().clone().into_headers())?;
+        if let Some(caller) = &caller {
+            caller.request()?;
+        }
+        Ok(caller)
+    }
+
+    /// Starts answering, returning the answer's events and what counts its
+    /// tokens.
+    async fn answer(
+        &self,
+        prepared: Prepared,
+        caller: Option<&Caller>,
+    ) -> Result<
+        (
+            impl Stream<Item = async_graphql::Result<AnswerEvent>>,
+            Arc<Tally>,
+        ),
+        Status,
+    > {
+        let storage = self.assistant.wait().await?;
+        let tally = Arc::new(Tally::new(meter(caller).await?));
+        let events = prepared
+            .by(caller)
+            .answer(storage, &self.shutdown, Some(tally.clone()));
+        Ok((events, tally))
+    }
+
+    async fn reply(
+        &self,
+        prepared: Prepared,
+        caller: Option<&Caller>,
+    ) -> Result<Response<GenerateReply>, Status> {
+        let (events, tally) = self.answer(prepared, caller).await?;
+        let answer = answer_once(events, &tally).await.map_err(status)?;
+        Ok(Response::new(GenerateReply {
+            text: answer.text,
+            usage: Some(answer.usage.into()),
+            moderation: answer.moderation.into_iter().map(Into::into).collect(),
+            stopped: answer.stopped,
+        }))
+    }
+}
+
+/// The caller's request id, or a new one.
+fn request_id<T>(request: &Request<T>) -> RequestId {
+    request
+        .metadata()
+        .get(REQUEST_ID.as_str())
+        .and_then(|it| it.to_str().ok())
+        .and_then(RequestId::parse)
+        .unwrap_or_default()
+}
+
+#[tonic::async_trait]
+impl<M: Model + 'static> AssistantRpc for AssistantService<M> {
+    async fn generate(
+        &self,
+        request: Request<GenerateRequest>,
+    ) -> Result<Response<GenerateReply>, Status> {
+        let caller = self.caller(&request)?;
+        let id = request_id(&request);
+        let prepared = prepare_ask(self.answerer(), request.into_inner().into(), Some(id)).await?;
+        self.reply(prepared, caller.as_ref()).await
+    }
+
+    type GenerateStreamStream = Chunks;
+
+    async fn generate_stream(
+        &self,
+        request: Request<GenerateRequest>,
+    ) -> Result<Response<Chunks>, Status> {
+        let caller = self.caller(&request)?;
+        let id = request_id(&request);
+        let prepared = prepare_ask(self.answerer(), request.into_inner().into(), Some(id)).await?;
+        let (events, tally) = self.answer(prepared, caller.as_ref()).await?;
+        let chunks = stream! {
+            let mut events = std::pin::pin!(events);
+            while let Some(event) = events.next().await {
+                let event = match event {
+                    Ok(AnswerEvent::Token(token)) => Event::Token(token.text),
+                    Ok(AnswerEvent::Finished(finished)) => Event::Finished(Finished {
+                        usage: Some(AnswerUsage::from(tally.as_ref()).into()),
+                        moderation: finished.moderation.into_iter().map(Into::into).collect(),
+                        stopped: finished.stopped,
+                    }),
+                    Err(e) => {
+                        yield Err(status(e));
+                        return;
+                    }
+                };
+                yield Ok(GenerateChunk { event: Some(event) });
+            }
+        };
+        Ok(Response::new(Box::pin(chunks)))
+    }
+
+    async fn summarize(
+        &self,
+        request: Request<SummarizeRequest>,
+    ) -> Result<Response<GenerateReply>, Status> {
+        let caller = self.caller(&request)?;
+        let id = request_id(&request);
+        let request = request.into_inner();
+        let messages = request.messages.into_iter().map(Into::into).collect();
+        let prepared =
+            prepare_summarize(self.answerer(), messages, request.summary, Some(id)).await?;
+        self.reply(prepared, caller.as_ref()).await
+    }
+
+    async fn embed(&self, request: Request<EmbedRequest>) -> Result<Response<EmbedReply>, Status> {
+        let caller = self.caller(&request)?;
+        let id = request_id(&request);
+        let storage = self.assistant.wait().await?;
+        let active = self.shutdown.admit()?;
+        let options = AnswerOptions {
+            request_id: Some(id),
+            meter: meter(caller.as_ref()).await?,
+            scheduler: Some(self.scheduler.clone()),
+            key: caller.map(|it| it.name().into()),
+            ..Default::default()
+        };
+        let (turn, assistant) = acquire_with(&options, storage.lock_owned()).await;
+        let text = request.into_inner().text;
+        // A whole forward pass, so it's kept off the async runtime
+        let embedding = tokio::task::spawn_blocking(move || {
+            let _turn = turn;
+            assistant.embed(&text, options.meter.as_deref())
+        })
+        .await
+        .map_err(|e| Error::Inference(e.into()))??;
+        drop(active);
+        Ok(Response::new(EmbedReply { embedding }))
+    }
+}
+
+/// Serves gRPC until shutdown begins.
+pub async fn serve<M: Model + 'static>(
+    service: AssistantService<M>,
+    listener: TcpListener,
+    shutdown: Shutdown,
+) -> error::Result<()> {
+    let incoming =
+        TcpIncoming::from_listener(listener, true, None).map_err(std::io::Error::other)?;
+    Server::builder()
+        .add_service(AssistantServer::new(service))
+        .serve_with_incoming_shutdown(incoming, async move { shutdown.begun().await })
+        .await
+        .map_err(std::io::Error::other)?;
+    Ok(())
+}
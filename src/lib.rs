@@ -1,7 +1,32 @@
 pub mod assistant;
+pub mod auth;
+pub mod batch;
+pub mod bench;
+pub mod chat;
+pub mod classifier;
+pub mod config;
+pub mod error;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod llama;
 pub mod loading;
+pub mod metrics;
+pub mod model;
+pub mod moderation;
+pub mod persona;
+pub mod pii;
 pub mod prompt;
+pub mod readiness;
+pub mod scheduler;
 pub mod server;
+pub mod shutdown;
+pub mod speculative;
+pub mod sse;
+pub mod telemetry;
 pub mod token_output_stream;
+pub mod transcription;
+pub mod usage;
 pub mod utils;
+pub mod vad;
 pub mod voice;
+pub mod voice_chat;
@@ -1,7 +1,11 @@
 use anyhow::{anyhow, Result};
-use candle_core::DType::F16;
+use candle_core::{
+    DType::{self, F16, F32},
+    Device,
+};
 use candle_nn::VarBuilder;
-use candle_transformers::models::llama::{Config, Llama, LlamaConfig};
+use candle_transformers::models::llama::{Config, LlamaConfig};
+use candle_transformers::models::{bert, parler_tts, whisper};
 use hf_hub::{
     api::sync::{Api, ApiRepo},
     Repo, RepoType,
@@ -12,7 +16,13 @@ use std::{
 };
 use tokenizers::Tokenizer;
 
-use crate::utils::device;
+use crate::{
+    classifier::{EntityTagger, HeadConfig, TextClassifier},
+    llama::Llama,
+    transcription::Transcriber,
+    utils::device,
+    voice::Voice,
+};
 
 fn build_repo(repo: &str) -> Result<ApiRepo> {
     let api = Api::new()?;
@@ -23,13 +33,17 @@ fn build_repo(repo: &str) -> Result<ApiRepo> {
     )))
 }
 
-const TOKENIZER_REPO: &str = "HuggingFaceTB/SmolLM2-360M-Instruct";
+pub const TOKENIZER_REPO: &str = "HuggingFaceTB/SmolLM2-360M-Instruct";
 const TOKENIZER: &str = "tokenizer.json";
 #[derive(Debug)]
 pub struct TokenizerFile(PathBuf);
 impl TokenizerFile {
     pub fn download() -> Result<TokenizerFile> {
-        let repo = build_repo(TOKENIZER_REPO)?;
+        Self::download_from(TOKENIZER_REPO)
+    }
+
+    pub fn download_from(repo: &str) -> Result<TokenizerFile> {
+        let repo = build_repo(repo)?;
         let filename = repo.get(TOKENIZER)?;
         Ok(Self(filename))
     }
@@ -45,7 +59,8 @@ impl Display for TokenizerFile {
     }
 }
 
-const MODEL_REPO: &str = "HuggingFaceTB/SmolLM2-360M-Instruct";
+pub const MODEL_REPO: &str = "HuggingFaceTB/SmolLM2-360M-Instruct";
+pub const DRAFT_REPO: &str = "HuggingFaceTB/SmolLM2-135M-Instruct";
 const MODEL_FILE: &str = "model.safetensors";
 const CONFIG: &str = "config.json";
 #[derive(Debug)]
@@ -55,18 +70,36 @@ pub struct ModelFile {
 }
 impl ModelFile {
     pub fn download() -> Result<ModelFile> {
-        let repo = build_repo(MODEL_REPO)?;
+        Self::download_from(MODEL_REPO)
+    }
+
+    /// The draft model for speculative decoding, which shares the tokenizer.
+    pub fn download_draft() -> Result<ModelFile> {
+        Self::download_from(DRAFT_REPO)
+    }
+
+    pub fn download_from(repo: &str) -> Result<ModelFile> {
+        let repo = build_repo(repo)?;
         let filename = repo.get(MODEL_FILE)?;
         let config = repo.get(CONFIG)?;
         Ok(Self { config, filename })
     }
 
     pub fn model(&self) -> Result<(Llama, Config)> {
+        self.model_as(F16)
+    }
+
+    /// Loads the model with its weights as `dtype`.
+    pub fn model_as(&self, dtype: DType) -> Result<(Llama, Config)> {
+        self.model_on(dtype, &device()?)
+    }
+
+    /// Loads the model with its weights as `dtype` on `device`.
+    pub fn model_on(&self, dtype: DType, device: &Device) -> Result<(Llama, Config)> {
         let config: LlamaConfig = serde_json::from_slice(&std::fs::read(&self.config)?)?;
         let config = config.into_config(false);
-        let device = device()?;
         let filenames = vec![&self.filename];
-        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, F16, &device)? };
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, device)? };
         let llama = Llama::load(vb, &config)?;
         Ok((llama, config))
     }
@@ -77,3 +110,145 @@ impl Display for ModelFile {
         write!(f, "{:?} {:?}", self.config, self.filename)
     }
 }
+
+pub const VOICE_REPO: &str = "parler-tts/parler-tts-mini-v1";
+#[derive(Debug)]
+pub struct VoiceFile {
+    config: PathBuf,
+    filename: PathBuf,
+    tokenizer: PathBuf,
+}
+impl VoiceFile {
+    pub fn download() -> Result<VoiceFile> {
+        Self::download_from(VOICE_REPO)
+    }
+
+    pub fn download_from(repo: &str) -> Result<VoiceFile> {
+        let repo = build_repo(repo)?;
+        let filename = repo.get(MODEL_FILE)?;
+        let config = repo.get(CONFIG)?;
+        let tokenizer = repo.get(TOKENIZER)?;
+        Ok(Self {
+            config,
+            filename,
+            tokenizer,
+        })
+    }
+
+    pub fn voice(&self) -> Result<Voice> {
+        let config: parler_tts::Config = serde_json::from_slice(&std::fs::read(&self.config)?)?;
+        let device = device()?;
+        let filenames = vec![&self.filename];
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, F32, &device)? };
+        let model = parler_tts::Model::new(&config, vb)?;
+        let tokenizer = Tokenizer::from_file(&self.tokenizer).map_err(|e| anyhow!(e))?;
+        Ok(Voice::new(model, tokenizer, config))
+    }
+}
+
+impl Display for VoiceFile {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} {:?} {:?}",
+            self.config, self.filename, self.tokenizer
+        )
+    }
+}
+
+pub const WHISPER_REPO: &str = "openai/whisper-base.en";
+#[derive(Debug)]
+pub struct WhisperFile {
+    config: PathBuf,
+    filename: PathBuf,
+    tokenizer: PathBuf,
+}
+impl WhisperFile {
+    pub fn download() -> Result<WhisperFile> {
+        Self::download_from(WHISPER_REPO)
+    }
+
+    pub fn download_from(repo: &str) -> Result<WhisperFile> {
+        let repo = build_repo(repo)?;
+        let filename = repo.get(MODEL_FILE)?;
+        let config = repo.get(CONFIG)?;
+        let tokenizer = repo.get(TOKENIZER)?;
+        Ok(Self {
+            config,
+            filename,
+            tokenizer,
+        })
+    }
+
+    pub fn transcriber(&self) -> Result<Transcriber> {
+        let config: whisper::Config = serde_json::from_slice(&std::fs::read(&self.config)?)?;
+        let device = device()?;
+        let filenames = vec![&self.filename];
+        let vb =
+            unsafe { VarBuilder::from_mmaped_safetensors(&filenames, whisper::DTYPE, &device)? };
+        let model = whisper::model::Whisper::load(&vb, config)?;
+        let tokenizer = Tokenizer::from_file(&self.tokenizer).map_err(|e| anyhow!(e))?;
+        Ok(Transcriber::new(model, tokenizer))
+    }
+}
+
+impl Display for WhisperFile {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} {:?} {:?}",
+            self.config, self.filename, self.tokenizer
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct ClassifierFile {
+    config: PathBuf,
+    filename: PathBuf,
+    tokenizer: PathBuf,
+}
+impl ClassifierFile {
+    pub fn download_from(repo: &str) -> Result<ClassifierFile> {
+        let repo = build_repo(repo)?;
+        let filename = repo.get(MODEL_FILE)?;
+        let config = repo.get(CONFIG)?;
+        let tokenizer = repo.get(TOKENIZER)?;
+        Ok(Self {
+            config,
+            filename,
+            tokenizer,
+        })
+    }
+
+    pub fn classifier(&self) -> Result<TextClassifier> {
+        let (vb, config, head, tokenizer) = self.parts()?;
+        Ok(TextClassifier::load(vb, &config, head, tokenizer)?)
+    }
+
+    pub fn entity_tagger(&self) -> Result<EntityTagger> {
+        let (vb, config, head, tokenizer) = self.parts()?;
+        Ok(EntityTagger::load(vb, &config, head, tokenizer)?)
+    }
+
+    fn parts(&self) -> Result<(VarBuilder<'_>, bert::Config, HeadConfig, Tokenizer)> {
+        let json = std::fs::read(&self.config)?;
+        let config: bert::Config = serde_json::from_slice(&json)?;
+        let head: HeadConfig = serde_json::from_slice(&json)?;
+        let device = device()?;
+        let filenames = vec![&self.filename];
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, F32, &device)? };
+        let tokenizer = Tokenizer::from_file(&self.tokenizer).map_err(|e| anyhow!(e))?;
+        Ok((vb, config, head, tokenizer))
+    }
+}
+
+impl Display for ClassifierFile {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} {:?} {:?}",
+            self.config, self.filename, self.tokenizer
+        )
+    }
+}
@@ -1,47 +1,441 @@
+use std::{
+    io::{stdin, stdout, BufRead, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
 use anyhow::{anyhow, Result};
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand};
+use futures_util::lock::Mutex;
 use persephone::{
-    loading::{ModelFile, TokenizerFile},
-    server::start,
+    auth::{self, AuthSettings, KeySettings},
+    batch::Batch,
+    bench::{measure, with_threads, Case, CpuFeatures, Report, Run, Setup},
+    chat::{Chat, Command as ChatCommand, HELP},
+    config::{DeviceKind, Precision, Settings},
+    loading::{ClassifierFile, ModelFile, TokenizerFile, VoiceFile, WhisperFile},
+    persona::Personas,
+    readiness::Loadable,
+    scheduler::Scheduler,
+    server::{load_assistant, load_masker, load_moderator, start},
+    shutdown::{signalled, Shutdown},
+    telemetry,
+    utils::device_of,
 };
+use serde::de::DeserializeOwned;
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[derive(Subcommand)]
 enum Command {
     /// Download and cache models
     Download,
     /// Serve the api
     Serve,
+    /// Chat in the terminal
+    Chat {
+        /// The id of the persona to chat with, otherwise the default one
+        #[arg(long)]
+        persona: Option<String>,
+    },
+    /// Answer a single prompt, followed by anything piped to stdin, on stdout
+    Generate {
+        /// What to ask, otherwise stdin alone is the prompt
+        #[arg(long)]
+        prompt: Option<String>,
+        /// The id of the persona to answer as, otherwise the default one
+        #[arg(long)]
+        persona: Option<String>,
+    },
+    /// Answer a JSONL file of requests, carrying on where an earlier run
+    /// stopped
+    Batch {
+        /// The requests, one to a line
+        input: PathBuf,
+        /// Where to append the results, one to a request
+        output: PathBuf,
+    },
+    /// Measure prefill and decode speed, time to first token and peak memory
+    Bench {
+        /// Prompt lengths to try, in tokens
+        #[arg(long, value_delimiter = ',', default_value = "128,512")]
+        prompt_tokens: Vec<usize>,
+        /// Answer lengths to try, in tokens
+        #[arg(long, value_delimiter = ',', default_value = "128")]
+        output_tokens: Vec<usize>,
+        /// Weight types to try, otherwise the configured one
+        #[arg(long, value_delimiter = ',', value_parser = parse_setting::<Precision>)]
+        dtype: Vec<Precision>,
+        /// Devices to try, otherwise the configured one
+        #[arg(long, value_delimiter = ',', value_parser = parse_setting::<DeviceKind>)]
+        device: Vec<DeviceKind>,
+        /// CPU thread counts to try, otherwise every available core
+        #[arg(long, value_delimiter = ',')]
+        threads: Vec<usize>,
+        /// Print the results as JSON rather than a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Work with the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Work with API keys
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyCommand {
+    /// Make a new key, printing it and the config that accepts it
+    New {
+        /// Who the key is for
+        #[arg(long)]
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Validate the configuration and print the settings it results in
+    Check,
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     /// Which mode to run in
-    #[arg(value_enum)]
+    #[command(subcommand)]
     command: Command,
+    /// A TOML config file, otherwise the defaults are used. Either way
+    /// PERSEPHONE_<SECTION>__<KEY> environment variables override settings.
+    #[arg(long, global = true, env = "PERSEPHONE_CONFIG")]
+    config: Option<PathBuf>,
+    /// Decode speculatively, with a draft model proposing this many tokens at a time
+    #[arg(long, global = true)]
+    speculate: Option<usize>,
+}
+
+fn download(settings: &Settings) -> Result<()> {
+    let model = &settings.model;
+    let filename = ModelFile::download_from(&model.repo)?;
+    let draft = ModelFile::download_from(&model.draft_repo)?;
+    let tokenizer = TokenizerFile::download_from(&model.tokenizer_repo)?;
+    let voice = VoiceFile::download_from(&model.voice_repo)?;
+    let whisper = WhisperFile::download_from(&model.whisper_repo)?;
+    println!(
+        "Model saved in {} and draft in {} and tokenizer in {} and voice in {} and whisper in {}",
+        filename, draft, tokenizer, voice, whisper
+    );
+    if let Some(classifier) = &settings.moderation.classifier {
+        let classifier = ClassifierFile::download_from(&classifier.repo)?;
+        println!("Moderation classifier saved in {classifier}");
+    }
+    if let Some(ner) = &settings.pii.ner {
+        let ner = ClassifierFile::download_from(&ner.repo)?;
+        println!("Name recognizer saved in {ner}");
+    }
+    Ok(())
+}
+
+async fn serve(settings: Settings) -> Result<()> {
+    Ok(start(settings).await?)
 }
 
-fn download() -> Result<()> {
-    let filename = ModelFile::download()?;
-    let tokenizer = TokenizerFile::download()?;
-    println!("Model saved in {} and tokenizer in {}", filename, tokenizer);
+async fn chat(settings: &Settings, persona: Option<String>) -> Result<()> {
+    let persona = Personas::load(&settings.personas)?.get(persona.as_deref())?;
+    let assistant = load_assistant(settings)?;
+    let mut chat = Chat::new(persona, settings.sampling);
+    println!("{HELP}");
+    let mut lines = stdin().lock().lines();
+    loop {
+        print!("> ");
+        stdout().flush()?;
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let line = line?;
+        match ChatCommand::parse(&line) {
+            Some(Ok(ChatCommand::Quit)) => break,
+            Some(command) => match command.and_then(|it| chat.run(it)) {
+                Ok(message) => println!("{message}"),
+                Err(e) => eprintln!("{e}"),
+            },
+            None if line.trim().is_empty() => {}
+            None => {
+                if let Err(e) = chat.say(&assistant, &line, &mut stdout()).await {
+                    eprintln!("{e}");
+                }
+                println!();
+            }
+        }
+    }
     Ok(())
 }
 
-async fn serve() -> Result<()> {
-    start().await.map_err(|e| anyhow!(e.message))
+async fn generate(
+    settings: &Settings,
+    prompt: Option<String>,
+    persona: Option<String>,
+) -> Result<()> {
+    let mut input = prompt.unwrap_or_default();
+    if !stdin().is_terminal() {
+        let mut piped = String::new();
+        stdin().read_to_string(&mut piped)?;
+        if !input.is_empty() && !piped.is_empty() {
+            input.push_str("\n\n");
+        }
+        input.push_str(&piped);
+    }
+    if input.trim().is_empty() {
+        return Err(anyhow!(
+            "nothing to answer, pass --prompt or pipe text to stdin"
+        ));
+    }
+    let persona = Personas::load(&settings.personas)?.get(persona.as_deref())?;
+    let assistant = load_assistant(settings)?;
+    let mut chat = Chat::new(persona, settings.sampling);
+    chat.say(&assistant, &input, &mut stdout()).await?;
+    println!();
+    Ok(())
+}
+
+async fn batch(settings: &Settings, input: &Path, output: &Path) -> Result<()> {
+    let assistant = load_assistant(settings)?;
+    let shutdown = Shutdown::default();
+    let batch = Batch::new(
+        Loadable::ready("assistant", Arc::new(Mutex::new(assistant))),
+        Personas::load(&settings.personas)?,
+        load_moderator(&settings.moderation)?,
+        load_masker(&settings.pii)?,
+        Scheduler::new(&settings.scheduler),
+        shutdown.clone(),
+    );
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            signalled().await;
+            eprintln!("Stopping after the answer going, run again to carry on");
+            shutdown.begin();
+        }
+    });
+    let progress = batch.run(input, output, None).await?;
+    eprintln!(
+        "{} of {} requests answered, {} failed",
+        progress.answered, progress.total, progress.failed
+    );
+    Ok(())
+}
+
+struct BenchOptions {
+    cases: Vec<Case>,
+    dtypes: Vec<Precision>,
+    devices: Vec<DeviceKind>,
+    threads: Vec<usize>,
+}
+
+fn bench(settings: &Settings, options: BenchOptions) -> Result<Report> {
+    let file = ModelFile::download_from(&settings.model.repo)?;
+    let mut runs = vec![];
+    for &dtype in &options.dtypes {
+        for &device in &options.devices {
+            let on = device_of(device)?;
+            let (model, config) = file.model_on(dtype.dtype(), &on)?;
+            for &threads in &options.threads {
+                let setup = Setup {
+                    dtype,
+                    device,
+                    threads,
+                };
+                with_threads(threads, || -> Result<()> {
+                    // the first forward pass pays for allocations and kernel setup
+                    let warmup = Case {
+                        prompt_tokens: 8,
+                        output_tokens: 2,
+                    };
+                    measure(&model, &config, &on, warmup)?;
+                    for &case in &options.cases {
+                        let measurement = measure(&model, &config, &on, case)?;
+                        runs.push(Run { setup, measurement });
+                    }
+                    Ok(())
+                })??;
+            }
+        }
+    }
+    Ok(Report {
+        model: settings.model.repo.clone(),
+        cpu: CpuFeatures::detect(),
+        runs,
+    })
+}
+
+fn print_report(report: &Report) {
+    let cpu = &report.cpu;
+    println!(
+        "{} (avx: {}, neon: {}, simd128: {}, f16c: {})",
+        report.model, cpu.avx, cpu.neon, cpu.simd128, cpu.f16c
+    );
+    println!(
+        "{:<6} {:<6} {:>7} {:>7} {:>7} {:>12} {:>12} {:>10} {:>10}",
+        "dtype",
+        "device",
+        "threads",
+        "prompt",
+        "output",
+        "prefill t/s",
+        "decode t/s",
+        "ttft ms",
+        "peak MiB"
+    );
+    for run in &report.runs {
+        let (setup, measured) = (&run.setup, &run.measurement);
+        let peak = measured
+            .peak_memory_bytes
+            .map(|it| format!("{:.0}", it as f64 / (1024.0 * 1024.0)))
+            .unwrap_or_else(|| "-".into());
+        println!(
+            "{:<6} {:<6} {:>7} {:>7} {:>7} {:>12.1} {:>12.1} {:>10.1} {:>10}",
+            format!("{:?}", setup.dtype).to_lowercase(),
+            format!("{:?}", setup.device).to_lowercase(),
+            setup.threads,
+            measured.case.prompt_tokens,
+            measured.case.output_tokens,
+            measured.prefill_tokens_per_second,
+            measured.decode_tokens_per_second,
+            measured.time_to_first_token_ms,
+            peak
+        );
+    }
+}
+
+/// Parses a command line value the way the config file spells it.
+fn parse_setting<T: DeserializeOwned>(value: &str) -> std::result::Result<T, String> {
+    serde_json::from_value(serde_json::Value::String(value.into())).map_err(|e| e.to_string())
+}
+
+fn check(settings: &Settings) -> Result<()> {
+    print!("{}", toml::to_string_pretty(settings)?);
+    Ok(())
+}
+
+fn settings(cli: &Cli) -> Result<Settings> {
+    let mut settings = Settings::load(cli.config.as_deref())?;
+    if cli.speculate.is_some() {
+        settings.model.speculate = cli.speculate;
+        settings.validate()?;
+    }
+    Ok(settings)
+}
+
+/// `values`, or just `default` when none were given.
+fn or<T>(values: Vec<T>, default: T) -> Vec<T> {
+    if values.is_empty() {
+        vec![default]
+    } else {
+        values
+    }
+}
+
+fn new_key(name: &str) {
+    let key = auth::new_key();
+    let settings = AuthSettings {
+        keys: vec![KeySettings {
+            name: name.into(),
+            sha256: auth::hash_key(&key),
+            requests_per_minute: None,
+            tokens_per_minute: None,
+            monthly_tokens: None,
+            admin: false,
+        }],
+    };
+    let mut config = toml::Table::new();
+    let settings = toml::Value::try_from(settings).expect("couldn't print the configuration");
+    config.insert("auth".into(), settings);
+    println!("{key}\n");
+    println!("# Give the key above to {name}, it isn't stored anywhere");
+    print!("{config}");
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
+    let settings = match settings(&cli) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    telemetry::init(&settings.logging).expect("couldn't start logging");
 
     match cli.command {
         Command::Download => {
-            download().expect("couldn't get models");
+            download(&settings).expect("couldn't get models");
         }
         Command::Serve => {
-            serve().await.expect("couldn't start server");
+            serve(settings).await.expect("couldn't start server");
+        }
+        Command::Chat { persona } => {
+            chat(&settings, persona).await.expect("couldn't chat");
+        }
+        Command::Generate { prompt, persona } => {
+            if let Err(e) = generate(&settings, prompt, persona).await {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Batch { input, output } => {
+            if let Err(e) = batch(&settings, &input, &output).await {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Bench {
+            prompt_tokens,
+            output_tokens,
+            dtype,
+            device,
+            threads,
+            json,
+        } => {
+            let options = BenchOptions {
+                cases: prompt_tokens
+                    .iter()
+                    .flat_map(|&prompt_tokens| {
+                        output_tokens.iter().map(move |&output_tokens| Case {
+                            prompt_tokens,
+                            output_tokens,
+                        })
+                    })
+                    .collect(),
+                dtypes: or(dtype, settings.model.dtype),
+                devices: or(device, settings.model.device),
+                threads: or(
+                    threads,
+                    std::thread::available_parallelism().map_or(1, |it| it.get()),
+                ),
+            };
+            match bench(&settings, options) {
+                Ok(report) if json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&report).expect("couldn't print the report")
+                ),
+                Ok(report) => print_report(&report),
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Config {
+            command: ConfigCommand::Check,
+        } => {
+            check(&settings).expect("couldn't print the configuration");
         }
+        Command::Key {
+            command: KeyCommand::New { name },
+        } => new_key(&name),
     }
 }
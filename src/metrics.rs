@@ -0,0 +1,155 @@
+//! Prometheus metrics, served at `/metrics`. They're recorded as answers are
+//! generated, so every way of asking is counted the same way.
+use std::{sync::LazyLock, time::Duration};
+
+use prometheus::{
+    exponential_buckets, Encoder, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounterVec,
+    IntGauge, Opts, Registry, TextEncoder,
+};
+
+use crate::error::{Error, Result};
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// What an answer was asked for, which requests are counted by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Operation {
+    #[default]
+    Ask,
+    Summarize,
+}
+
+impl Operation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operation::Ask => "ask",
+            Operation::Summarize => "summarize",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    pub requests: IntCounterVec,
+    /// Answers waiting for the assistant
+    pub queue_depth: IntGauge,
+    /// How long answers waited for the assistant, by priority
+    pub queue_wait: HistogramVec,
+    pub active_generations: IntGauge,
+    pub time_to_first_token: Histogram,
+    pub latency: Histogram,
+    pub generated_tokens: Histogram,
+    pub decode_tokens_per_second: Histogram,
+    pub model_load_seconds: GaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("persephone".into()), None).expect("the prefix is valid");
+        let seconds = |name: &str, help: &str| {
+            let buckets = exponential_buckets(0.01, 2.0, 14).expect("the buckets are valid");
+            Histogram::with_opts(HistogramOpts::new(name, help).buckets(buckets))
+                .expect("the histogram is valid")
+        };
+        let metrics = Self {
+            requests: IntCounterVec::new(
+                Opts::new("requests_total", "Answers asked for, by operation"),
+                &["operation"],
+            )
+            .expect("the counter is valid"),
+            queue_depth: IntGauge::new("queue_depth", "Answers waiting for the assistant")
+                .expect("the gauge is valid"),
+            queue_wait: HistogramVec::new(
+                HistogramOpts::new(
+                    "queue_wait_seconds",
+                    "Time answers waited for the assistant, by priority",
+                )
+                .buckets(exponential_buckets(0.01, 2.0, 14).expect("the buckets are valid")),
+                &["priority"],
+            )
+            .expect("the histogram is valid"),
+            active_generations: IntGauge::new("active_generations", "Answers being generated")
+                .expect("the gauge is valid"),
+            time_to_first_token: seconds(
+                "time_to_first_token_seconds",
+                "Time from starting an answer to its first token",
+            ),
+            latency: seconds(
+                "request_duration_seconds",
+                "Time from starting an answer to finishing it",
+            ),
+            generated_tokens: Histogram::with_opts(
+                HistogramOpts::new("generated_tokens", "Tokens in each answer")
+                    .buckets(exponential_buckets(1.0, 2.0, 13).expect("the buckets are valid")),
+            )
+            .expect("the histogram is valid"),
+            decode_tokens_per_second: Histogram::with_opts(
+                HistogramOpts::new(
+                    "decode_tokens_per_second",
+                    "Tokens generated per second after the first, per answer",
+                )
+                .buckets(exponential_buckets(1.0, 1.5, 16).expect("the buckets are valid")),
+            )
+            .expect("the histogram is valid"),
+            model_load_seconds: GaugeVec::new(
+                Opts::new("model_load_seconds", "How long each model took to load"),
+                &["model"],
+            )
+            .expect("the gauge is valid"),
+            registry,
+        };
+        let collectors: [Box<dyn prometheus::core::Collector>; 9] = [
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.queue_depth.clone()),
+            Box::new(metrics.queue_wait.clone()),
+            Box::new(metrics.active_generations.clone()),
+            Box::new(metrics.time_to_first_token.clone()),
+            Box::new(metrics.latency.clone()),
+            Box::new(metrics.generated_tokens.clone()),
+            Box::new(metrics.decode_tokens_per_second.clone()),
+            Box::new(metrics.model_load_seconds.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metrics are only registered once");
+        }
+        metrics
+    }
+
+    /// Every metric, in Prometheus's text format.
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| Error::Inference(e.into()))?;
+        String::from_utf8(buffer).map_err(|e| Error::Inference(e.into()))
+    }
+
+    pub fn loaded(&self, model: &str, took: Duration) {
+        self.model_load_seconds
+            .with_label_values(&[model])
+            .set(took.as_secs_f64());
+    }
+
+    /// Counts `gauge` up until the returned guard is dropped.
+    pub fn track(gauge: &IntGauge) -> Tracked {
+        gauge.inc();
+        Tracked(gauge.clone())
+    }
+}
+
+/// Counts a gauge back down when dropped.
+pub struct Tracked(IntGauge);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
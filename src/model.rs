@@ -0,0 +1,69 @@
+//! The language model behind the assistant. Answers only need logits and a
+//! cache that can be rolled back, so tests can stand in a scripted model for
+//! the real one.
+use candle_core::{Device, Result, Tensor};
+use candle_transformers::models::llama::Config;
+
+use crate::llama::{Cache, Llama};
+
+/// Attention state for the positions a model has already seen.
+pub trait KvCache: Send {
+    /// The number of positions held in the cache.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets every position from `len` onwards.
+    fn truncate(&mut self, len: usize) -> Result<()>;
+}
+
+pub trait Model: Send + Sync {
+    type Cache: KvCache;
+
+    /// An empty cache for a single answer.
+    fn cache(&self, config: &Config, device: &Device) -> Result<Self::Cache>;
+
+    /// Feeds `x` after the cached positions, returning the logits for the
+    /// last position.
+    fn forward(&self, x: &Tensor, cache: &mut Self::Cache) -> Result<Tensor>;
+
+    /// Feeds `x` after the cached positions, returning the logits for its
+    /// last `n` positions.
+    fn forward_last(&self, x: &Tensor, n: usize, cache: &mut Self::Cache) -> Result<Tensor>;
+
+    /// Feeds `x` after the cached positions, returning the final hidden
+    /// state for every position.
+    fn hidden(&self, x: &Tensor, cache: &mut Self::Cache) -> Result<Tensor>;
+}
+
+impl KvCache for Cache {
+    fn len(&self) -> usize {
+        Cache::len(self)
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        Cache::truncate(self, len)
+    }
+}
+
+impl Model for Llama {
+    type Cache = Cache;
+
+    fn cache(&self, config: &Config, device: &Device) -> Result<Cache> {
+        Cache::new(self.dtype(), config, device)
+    }
+
+    fn forward(&self, x: &Tensor, cache: &mut Cache) -> Result<Tensor> {
+        Llama::forward(self, x, cache)
+    }
+
+    fn forward_last(&self, x: &Tensor, n: usize, cache: &mut Cache) -> Result<Tensor> {
+        Llama::forward_last(self, x, n, cache)
+    }
+
+    fn hidden(&self, x: &Tensor, cache: &mut Cache) -> Result<Tensor> {
+        Llama::hidden(self, x, cache)
+    }
+}
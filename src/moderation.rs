@@ -0,0 +1,493 @@
+//! Policy checks on prompts before an answer starts and on answers as they're
+//! generated. Rules match words or regular expressions, and an optional
+//! classifier model flags whole prompts and sentences. Each match either
+//! refuses the request, redacts the text or stops the answer, and is recorded
+//! as a [`Decision`].
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use async_graphql::{Enum, SimpleObject};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    error::{Error, Result},
+    pii::{Placeholders, Unmasker},
+    readiness::Loadable,
+    voice::Sentences,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Enum)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Refuses the request, or on an answer stops it like `stop`
+    Refuse,
+    /// Replaces what matched
+    Redact,
+    /// Ends the answer before what matched
+    Stop,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Refuse => "refuse",
+            Action::Redact => "redact",
+            Action::Stop => "stop",
+        }
+    }
+}
+
+/// Which text a rule checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Enum)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    /// The prompt, messages and summary
+    Input,
+    /// The answer
+    Output,
+}
+
+impl Stage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::Input => "input",
+            Stage::Output => "output",
+        }
+    }
+}
+
+fn both() -> Vec<Stage> {
+    vec![Stage::Input, Stage::Output]
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSettings {
+    pub name: String,
+    /// Words or phrases, matched whole and ignoring case
+    #[serde(default)]
+    pub words: Vec<String>,
+    /// A regular expression, instead of words
+    pub pattern: Option<String>,
+    pub action: Action,
+    /// What it checks, by default both prompts and answers
+    #[serde(default = "both")]
+    pub on: Vec<Stage>,
+}
+
+impl RuleSettings {
+    fn regex(&self) -> Result<Regex> {
+        let pattern = match (&self.pattern, self.words.is_empty()) {
+            (Some(pattern), true) => pattern.clone(),
+            (None, false) => {
+                let words: Vec<_> = self.words.iter().map(|it| regex::escape(it)).collect();
+                format!(r"\b(?:{})\b", words.join("|"))
+            }
+            _ => {
+                return Err(Error::Config(format!(
+                    "the {} moderation rule needs either words or a pattern",
+                    self.name
+                )))
+            }
+        };
+        RegexBuilder::new(&pattern)
+            .case_insensitive(self.pattern.is_none())
+            .build()
+            .map_err(|e| Error::Config(format!("the {} moderation rule: {e}", self.name)))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClassifierSettings {
+    /// A BERT sequence classification model with safetensors weights
+    pub repo: String,
+    /// The labels that are flagged, otherwise every label
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// The score from which a label is flagged
+    #[serde(default = "threshold")]
+    pub threshold: f32,
+    /// Refuse or stop, it can't redact
+    pub action: Action,
+    /// What it checks, by default both prompts and answers
+    #[serde(default = "both")]
+    pub on: Vec<Stage>,
+}
+
+fn threshold() -> f32 {
+    0.5
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationSettings {
+    /// Checked in order, the first that refuses or stops wins
+    pub rules: Vec<RuleSettings>,
+    pub classifier: Option<ClassifierSettings>,
+    /// Characters of an answer held back so rules can match across tokens
+    pub hold_back: usize,
+    /// What redacted text is replaced with
+    pub replacement: String,
+}
+
+impl Default for ModerationSettings {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            classifier: None,
+            hold_back: 32,
+            replacement: "[redacted]".into(),
+        }
+    }
+}
+
+impl ModerationSettings {
+    pub fn validate(&self) -> Result<()> {
+        for rule in &self.rules {
+            rule.regex()?;
+        }
+        if let Some(classifier) = &self.classifier {
+            if classifier.action == Action::Redact {
+                return Err(Error::Config(
+                    "the moderation classifier can refuse or stop but not redact".into(),
+                ));
+            }
+            if !(0.0..=1.0).contains(&classifier.threshold) {
+                return Err(Error::Config(
+                    "the moderation classifier's threshold must be between 0 and 1".into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Scores text, such as for toxicity.
+pub trait Classifier: Send {
+    /// Each label's score, from 0 to 1.
+    fn classify(&mut self, text: &str) -> Result<Vec<(String, f32)>>;
+}
+
+/// Something moderation did.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, SimpleObject)]
+pub struct Decision {
+    /// The rule's name, or the classifier's label
+    pub rule: String,
+    pub stage: Stage,
+    pub action: Action,
+}
+
+/// The decisions made about a request, shared between the tasks answering it.
+#[derive(Clone, Debug, Default)]
+pub struct Decisions(Arc<Mutex<Vec<Decision>>>);
+
+impl Decisions {
+    fn push(&self, decision: Decision) {
+        info!(rule = %decision.rule, stage = ?decision.stage, action = ?decision.action, "moderated");
+        self.0
+            .lock()
+            .unwrap_or_else(|it| it.into_inner())
+            .push(decision);
+    }
+
+    pub fn take(&self) -> Vec<Decision> {
+        std::mem::take(&mut self.0.lock().unwrap_or_else(|it| it.into_inner()))
+    }
+}
+
+struct Rule {
+    name: String,
+    regex: Regex,
+    action: Action,
+    on: Vec<Stage>,
+}
+
+struct Flagging {
+    classifier: Loadable<Box<dyn Classifier>>,
+    settings: ClassifierSettings,
+}
+
+struct Policy {
+    rules: Vec<Rule>,
+    classifier: Option<Flagging>,
+    hold_back: usize,
+    replacement: String,
+}
+
+/// Applies the configured rules and classifier.
+#[derive(Clone)]
+pub struct Moderator(Arc<Policy>);
+
+impl Moderator {
+    /// The rules in `settings`, and the classifier when there is one.
+    pub fn new(
+        settings: &ModerationSettings,
+        classifier: Option<Box<dyn Classifier>>,
+    ) -> Result<Self> {
+        let classifier = classifier
+            .map(|it| Loadable::ready("classifier", Arc::new(futures_util::lock::Mutex::new(it))));
+        Self::loading(settings, classifier)
+    }
+
+    /// Like `new`, with a classifier that may still be loading. Checks that
+    /// need it wait for it.
+    pub fn loading(
+        settings: &ModerationSettings,
+        classifier: Option<Loadable<Box<dyn Classifier>>>,
+    ) -> Result<Self> {
+        let rules = settings
+            .rules
+            .iter()
+            .map(|rule| {
+                Ok(Rule {
+                    name: rule.name.clone(),
+                    regex: rule.regex()?,
+                    action: rule.action,
+                    on: rule.on.clone(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        let classifier =
+            settings
+                .classifier
+                .clone()
+                .zip(classifier)
+                .map(|(settings, classifier)| Flagging {
+                    classifier,
+                    settings,
+                });
+        Ok(Self(Arc::new(Policy {
+            rules,
+            classifier,
+            hold_back: settings.hold_back,
+            replacement: settings.replacement.clone(),
+        })))
+    }
+
+    /// Checks a prompt, returning it with anything redacted, or `Refused`.
+    pub async fn input(&self, text: &str, decisions: &Decisions) -> Result<String> {
+        let mut text = text.to_string();
+        for rule in self.rules(Stage::Input) {
+            let found = rule.regex.find_iter(&text).count();
+            if found == 0 {
+                continue;
+            }
+            for _ in 0..found {
+                decisions.push(rule.decision(Stage::Input));
+            }
+            if rule.action != Action::Redact {
+                return Err(Error::Refused(rule.name.clone()));
+            }
+            text = rule
+                .regex
+                .replace_all(&text, self.0.replacement.as_str())
+                .into_owned();
+        }
+        if let Some(label) = self.flag(Stage::Input, &text, decisions).await? {
+            return Err(Error::Refused(label));
+        }
+        Ok(text)
+    }
+
+    /// A filter for an answer's tokens.
+    pub fn output(&self, decisions: &Decisions) -> OutputFilter {
+        OutputFilter {
+            moderator: self.clone(),
+            decisions: decisions.clone(),
+            unmasker: None,
+            pending: String::new(),
+            sentences: Sentences::new(),
+            stopped: false,
+        }
+    }
+
+    fn rules(&self, stage: Stage) -> impl Iterator<Item = &Rule> {
+        self.0.rules.iter().filter(move |it| it.on.contains(&stage))
+    }
+
+    /// Runs the classifier on a blocking thread, returning the first label
+    /// flagged.
+    async fn flag(
+        &self,
+        stage: Stage,
+        text: &str,
+        decisions: &Decisions,
+    ) -> Result<Option<String>> {
+        let Some(flagging) = &self.0.classifier else {
+            return Ok(None);
+        };
+        let settings = &flagging.settings;
+        if !settings.on.contains(&stage) || text.trim().is_empty() {
+            return Ok(None);
+        }
+        let mut classifier = flagging.classifier.wait().await?.lock_owned().await;
+        let text = text.to_string();
+        let scores = tokio::task::spawn_blocking(move || classifier.classify(&text))
+            .await
+            .map_err(|e| Error::Inference(anyhow!(e)))??;
+        let flagged = scores.into_iter().find(|(label, score)| {
+            *score >= settings.threshold
+                && (settings.labels.is_empty() || settings.labels.contains(label))
+        });
+        Ok(flagged.map(|(label, _)| {
+            decisions.push(Decision {
+                rule: label.clone(),
+                stage,
+                action: settings.action,
+            });
+            label
+        }))
+    }
+}
+
+impl Rule {
+    fn decision(&self, stage: Stage) -> Decision {
+        Decision {
+            rule: self.name.clone(),
+            stage,
+            action: self.action,
+        }
+    }
+}
+
+/// What an answer can show so far.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Released {
+    pub text: String,
+    /// Whether the answer should stop here
+    pub stop: bool,
+}
+
+/// Moderates an answer token by token. The last `hold_back` characters are
+/// held back until later tokens show whether they're part of a match, and the
+/// classifier checks each sentence as it's finished.
+pub struct OutputFilter {
+    moderator: Moderator,
+    decisions: Decisions,
+    unmasker: Option<Unmasker>,
+    pending: String,
+    sentences: Sentences,
+    stopped: bool,
+}
+
+impl OutputFilter {
+    /// Unmasks the answer to a masked prompt before checking it, so the rules
+    /// see what the user will.
+    pub fn unmasking(mut self, placeholders: &Placeholders) -> Self {
+        if !placeholders.is_empty() {
+            self.unmasker = Some(placeholders.unmasker());
+        }
+        self
+    }
+
+    pub async fn push(&mut self, token: &str) -> Result<Released> {
+        if self.stopped {
+            return Ok(Released {
+                stop: true,
+                ..Default::default()
+            });
+        }
+        let token = match &mut self.unmasker {
+            Some(unmasker) => unmasker.push(token),
+            None => token.to_string(),
+        };
+        self.pending.push_str(&token);
+        if let Some(released) = self.check(false) {
+            return Ok(released);
+        }
+        for sentence in self.sentences.push(&token) {
+            if let Some(released) = self.classify(&sentence).await? {
+                return Ok(released);
+            }
+        }
+        let hold_back = self.moderator.0.hold_back;
+        let keep = self
+            .pending
+            .chars()
+            .rev()
+            .take(hold_back)
+            .map(char::len_utf8)
+            .sum::<usize>();
+        let text = self.pending.drain(..self.pending.len() - keep).collect();
+        Ok(Released { text, stop: false })
+    }
+
+    /// Releases what's held back once the answer's done.
+    pub async fn finish(&mut self) -> Result<Released> {
+        if self.stopped {
+            return Ok(Released {
+                stop: true,
+                ..Default::default()
+            });
+        }
+        let mut sentences = vec![];
+        if let Some(unmasker) = &mut self.unmasker {
+            let rest = unmasker.finish();
+            self.pending.push_str(&rest);
+            sentences = self.sentences.push(&rest);
+        }
+        if let Some(released) = self.check(true) {
+            return Ok(released);
+        }
+        sentences.extend(self.sentences.finish());
+        for sentence in sentences {
+            if let Some(released) = self.classify(&sentence).await? {
+                return Ok(released);
+            }
+        }
+        Ok(Released {
+            text: std::mem::take(&mut self.pending),
+            stop: false,
+        })
+    }
+
+    /// Applies the rules to what's held back. Matches running to the end of it
+    /// could still grow, so wait for the next token unless the answer's done.
+    fn check(&mut self, done: bool) -> Option<Released> {
+        let moderator = self.moderator.clone();
+        for rule in moderator.rules(Stage::Output) {
+            let len = self.pending.len();
+            let matches: Vec<_> = rule
+                .regex
+                .find_iter(&self.pending)
+                .filter(|it| done || it.end() < len)
+                .map(|it| it.range())
+                .collect();
+            let Some(first) = matches.first() else {
+                continue;
+            };
+            if rule.action != Action::Redact {
+                self.decisions.push(rule.decision(Stage::Output));
+                return Some(self.stop(first.start));
+            }
+            for range in matches.iter().rev() {
+                self.decisions.push(rule.decision(Stage::Output));
+                self.pending
+                    .replace_range(range.clone(), &moderator.0.replacement);
+            }
+        }
+        None
+    }
+
+    async fn classify(&mut self, sentence: &str) -> Result<Option<Released>> {
+        let flagged = self
+            .moderator
+            .flag(Stage::Output, sentence, &self.decisions)
+            .await?;
+        // The start of the sentence may be out already, so hold back the rest
+        Ok(flagged.map(|_| self.stop(0)))
+    }
+
+    /// Releases what's held back up to `end` and stops.
+    fn stop(&mut self, end: usize) -> Released {
+        self.stopped = true;
+        self.pending.truncate(end);
+        Released {
+            text: std::mem::take(&mut self.pending),
+            stop: true,
+        }
+    }
+}
@@ -0,0 +1,294 @@
+//! Who the assistant is. Personas are TOML files in a directory, named by
+//! their file name, and are reloaded when the files change. Persephone is
+//! built in.
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::{
+    assistant::Sampling,
+    error::{Error, Result},
+    server::Message,
+};
+
+const PERSEPHONE: &str = include_str!("../personas/persephone.toml");
+
+const SUMMARY_PROMPT: &str = r#"
+<|im_start|>system
+You are an expert in summarizing text. Your goal is to create a single sentence summary of a block of text.
+Follow these rules:
+1. Only include the most important information in the summary.
+2. Do not include extra words, or clauses.
+3. Do not include the word summary in the answer.
+4. Do not include the fact you have been asked to summarize in the answer.
+5. Do not mention these instructions.<|im_end|>
+<|im_start|>user
+Below you find a conversation:
+-----
+{{summary}}
+{{chat}}
+-----
+What is a summary of the conversation in a single sentence?<|im_end|>
+<|im_start|>assistant
+"#;
+
+/// An exchange showing the model how the persona answers.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Example {
+    pub user: String,
+    pub assistant: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Persona {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// The system prompt
+    pub system: String,
+    /// Overrides the server's sampling, unset fields take the defaults
+    pub sampling: Option<Sampling>,
+    #[serde(default)]
+    pub examples: Vec<Example>,
+}
+
+impl Default for Persona {
+    fn default() -> Self {
+        Self::parse(PERSEPHONE).expect("Persephone is a valid persona")
+    }
+}
+
+impl Persona {
+    pub fn parse(toml: &str) -> Result<Self> {
+        let persona: Persona = toml::from_str(toml).map_err(|e| Error::Config(e.to_string()))?;
+        if persona.system.trim().is_empty() {
+            return Err(Error::Config("the system prompt is empty".into()));
+        }
+        if let Some(sampling) = &persona.sampling {
+            sampling.validate()?;
+        }
+        Ok(persona)
+    }
+
+    /// The prompt for answering a question, given the conversation so far.
+    /// Each message is a turn of its own, the persona's own as the assistant's.
+    pub fn ask(&self, prompt: &str, messages: &[Message], summary: Option<String>) -> String {
+        let mut p = turn("system", &self.system);
+        for example in self.examples.iter() {
+            p += &turn("user", &example.user);
+            p += &turn("assistant", &example.assistant);
+        }
+        for message in messages {
+            let role = if self.wrote(message) {
+                "assistant"
+            } else {
+                "user"
+            };
+            p += &turn(role, &message.message);
+        }
+        let summary = summary
+            .map(|text| format!("What have we been talking about so far?\n\"{text}\"\n"))
+            .unwrap_or_default();
+        p += &turn("user", &(summary + prompt));
+        p + "<|im_start|>assistant\n"
+    }
+
+    /// Whether the message is one of the persona's answers, written by
+    /// `assistant` or by the persona's name.
+    fn wrote(&self, message: &Message) -> bool {
+        let author = message.author.trim();
+        author.eq_ignore_ascii_case("assistant")
+            || (!self.name.is_empty() && author.eq_ignore_ascii_case(&self.name))
+    }
+}
+
+fn turn(role: &str, text: &str) -> String {
+    format!("<|im_start|>{role}\n{}<|im_end|>\n", text.trim())
+}
+
+/// Each message on its own line.
+fn script(messages: &[Message]) -> String {
+    messages
+        .iter()
+        .map(|it| format!("{}\n", it.message))
+        .collect()
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersonaSettings {
+    /// A directory of persona files, added to the built in Persephone
+    pub directory: Option<PathBuf>,
+    /// Who answers when a request doesn't pick a persona
+    pub default: String,
+    /// The prompt for summarizing a conversation, `{{chat}}` and
+    /// `{{summary}}` are filled in for each request
+    pub summary_prompt: String,
+}
+
+impl Default for PersonaSettings {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            default: "persephone".into(),
+            summary_prompt: SUMMARY_PROMPT.into(),
+        }
+    }
+}
+
+/// What the persona files looked like when they were last read, to tell
+/// when they change.
+type Fingerprint = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+#[derive(Default)]
+struct Loaded {
+    personas: BTreeMap<String, Persona>,
+    fingerprint: Option<Fingerprint>,
+}
+
+/// Every persona by id.
+#[derive(Clone)]
+pub struct Personas {
+    settings: Arc<PersonaSettings>,
+    loaded: Arc<RwLock<Loaded>>,
+}
+
+impl Personas {
+    /// Reads the personas, failing if any of them are invalid.
+    pub fn load(settings: &PersonaSettings) -> Result<Self> {
+        if !settings.summary_prompt.contains("{{chat}}") {
+            return Err(Error::Config(
+                "the summary prompt is missing {{chat}}".into(),
+            ));
+        }
+        let personas = Self {
+            settings: Arc::new(settings.clone()),
+            loaded: Arc::default(),
+        };
+        personas.reload()?;
+        Ok(personas)
+    }
+
+    /// Rereads the personas if their files have changed since they were last
+    /// read, returning whether they had. When a file is invalid the personas
+    /// are left as they were.
+    pub fn reload(&self) -> Result<bool> {
+        let files = match &self.settings.directory {
+            Some(directory) => persona_files(directory)?,
+            None => vec![],
+        };
+        let fingerprint = files
+            .iter()
+            .map(|path| {
+                let metadata = fs::metadata(path).ok();
+                let modified = metadata.as_ref().and_then(|it| it.modified().ok());
+                (path.clone(), modified, metadata.map_or(0, |it| it.len()))
+            })
+            .collect();
+        if self.read().fingerprint.as_ref() == Some(&fingerprint) {
+            return Ok(false);
+        }
+        let mut personas = BTreeMap::from([("persephone".to_string(), Persona::default())]);
+        for path in files.iter() {
+            let id = path
+                .file_stem()
+                .map(|it| it.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let persona = fs::read_to_string(path)
+                .map_err(Error::from)
+                .and_then(|it| Persona::parse(&it))
+                .map_err(|e| Error::Config(format!("{}: {e}", path.display())))?;
+            personas.insert(id, persona);
+        }
+        if !personas.contains_key(&self.settings.default) {
+            return Err(Error::Config(format!(
+                "there is no {} persona",
+                self.settings.default
+            )));
+        }
+        *self.loaded.write().unwrap_or_else(PoisonError::into_inner) = Loaded {
+            personas,
+            fingerprint: Some(fingerprint),
+        };
+        Ok(true)
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Loaded> {
+        self.loaded.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Checks for changes to the persona files every so often.
+    pub fn watch(&self, every: Duration) -> JoinHandle<()> {
+        let personas = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            loop {
+                interval.tick().await;
+                match personas.reload() {
+                    Ok(true) => info!("reloaded personas"),
+                    Ok(false) => {}
+                    Err(e) => warn!("keeping the old personas: {e}"),
+                }
+            }
+        })
+    }
+
+    /// The persona with `id`, or the default one.
+    pub fn get(&self, id: Option<&str>) -> Result<Persona> {
+        let id = id.unwrap_or(&self.settings.default);
+        self.read()
+            .personas
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error::InvalidRequest(format!("there is no {id} persona")))
+    }
+
+    /// Every persona along with its id.
+    pub fn list(&self) -> Vec<(String, Persona)> {
+        self.read()
+            .personas
+            .iter()
+            .map(|(id, persona)| (id.clone(), persona.clone()))
+            .collect()
+    }
+
+    /// The prompt asking for a single sentence summary of the conversation so far.
+    pub fn summarize(&self, messages: &[Message], summary: Option<String>) -> Result<String> {
+        if messages.is_empty() {
+            return Err(Error::InvalidRequest("empty messages array!".into()));
+        }
+        let p = self
+            .settings
+            .summary_prompt
+            .replace("{{chat}}", &script(messages));
+        Ok(if let Some(summary) = summary {
+            p.replace(
+                "{{summary}}",
+                &("What have we been talking about?\n".to_owned() + &summary),
+            )
+        } else {
+            p.replace("{{summary}}", "")
+        })
+    }
+}
+
+fn persona_files(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(directory)
+        .map_err(|e| Error::Config(format!("can't read {}: {e}", directory.display())))?
+        .filter_map(|entry| entry.ok().map(|it| it.path()))
+        .filter(|path| path.extension().is_some_and(|it| it == "toml"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
@@ -0,0 +1,294 @@
+//! Finding personal information — emails, phone numbers, card numbers, IP
+//! addresses and, with a NER model, people's names — and masking it. Masks are
+//! placeholders like `<EMAIL_1>` that can be put back, so prompts can be
+//! masked before they reach the model and its answers unmasked for the user.
+use std::{
+    ops::Range,
+    sync::{Arc, LazyLock},
+};
+
+use anyhow::anyhow;
+use futures_util::lock::Mutex;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    readiness::Loadable,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Email,
+    Phone,
+    Card,
+    Ip,
+    /// People's names, found by the NER model
+    Name,
+}
+
+impl Kind {
+    fn label(self) -> &'static str {
+        match self {
+            Kind::Email => "EMAIL",
+            Kind::Phone => "PHONE",
+            Kind::Card => "CARD",
+            Kind::Ip => "IP",
+            Kind::Name => "NAME",
+        }
+    }
+
+    fn pattern(self) -> Option<&'static Regex> {
+        match self {
+            Kind::Email => Some(&EMAIL),
+            Kind::Phone => Some(&PHONE),
+            Kind::Card => Some(&CARD),
+            Kind::Ip => Some(&IP),
+            Kind::Name => None,
+        }
+    }
+}
+
+static EMAIL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap());
+static CARD: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(?:\d[ -]?){12,18}\d\b").unwrap());
+static IP: LazyLock<Regex> = LazyLock::new(|| {
+    let v4 = r"\b(?:(?:25[0-5]|2[0-4]\d|1?\d?\d)\.){3}(?:25[0-5]|2[0-4]\d|1?\d?\d)\b";
+    let v6 = r"\b(?:[0-9A-Fa-f]{1,4}:){7}[0-9A-Fa-f]{1,4}\b|\b(?:[0-9A-Fa-f]{1,4}:)+:(?:[0-9A-Fa-f]{1,4}(?::[0-9A-Fa-f]{1,4})*)?";
+    Regex::new(&format!("{v4}|{v6}")).unwrap()
+});
+static PHONE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{2,5}\)|\b\d{2,5})[\s.-]?\d{3,4}[\s.-]?\d{3,4}\b")
+        .unwrap()
+});
+
+/// Card numbers pass the Luhn check, which rules out most other long numbers.
+fn luhn(text: &str) -> bool {
+    let digits: Vec<u32> = text.chars().filter_map(|it| it.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match i % 2 {
+            0 => d,
+            _ if d * 2 > 9 => d * 2 - 9,
+            _ => d * 2,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+fn all() -> Vec<Kind> {
+    vec![Kind::Email, Kind::Card, Kind::Ip, Kind::Phone, Kind::Name]
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NerSettings {
+    /// A BERT token classification model with safetensors weights, tagging
+    /// people as `B-PER` and `I-PER`
+    pub repo: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PiiSettings {
+    /// Mask prompts before they reach the model, unmasking its answers
+    pub mask_prompts: bool,
+    /// What to look for, earlier kinds winning where they overlap
+    pub kinds: Vec<Kind>,
+    /// Finds names, which are only looked for with it
+    pub ner: Option<NerSettings>,
+}
+
+impl Default for PiiSettings {
+    fn default() -> Self {
+        Self {
+            mask_prompts: false,
+            kinds: all(),
+            ner: None,
+        }
+    }
+}
+
+/// Finds people's names.
+pub trait Recognizer: Send {
+    /// Where each name is, in bytes.
+    fn names(&mut self, text: &str) -> Result<Vec<Range<usize>>>;
+}
+
+/// What's been masked, by placeholder. The same value always gets the same
+/// placeholder, so answers can refer back to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Placeholders {
+    masked: Vec<(String, String)>,
+}
+
+impl Placeholders {
+    pub fn is_empty(&self) -> bool {
+        self.masked.is_empty()
+    }
+
+    fn placeholder(&mut self, kind: Kind, value: &str) -> String {
+        if let Some((placeholder, _)) = self.masked.iter().find(|(_, it)| it == value) {
+            return placeholder.clone();
+        }
+        let label = kind.label();
+        let n = self
+            .masked
+            .iter()
+            .filter(|(it, _)| it[1..].starts_with(label))
+            .count();
+        let placeholder = format!("<{label}_{}>", n + 1);
+        self.masked.push((placeholder.clone(), value.into()));
+        placeholder
+    }
+
+    /// Puts the masked values back.
+    pub fn unmask(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (placeholder, value) in &self.masked {
+            text = text.replace(placeholder, value);
+        }
+        text
+    }
+
+    /// Unmasks a stream of text, such as an answer's tokens.
+    pub fn unmasker(&self) -> Unmasker {
+        Unmasker {
+            placeholders: self.clone(),
+            pending: String::new(),
+        }
+    }
+}
+
+/// The longest placeholder held back while waiting for its end.
+const LONGEST_PLACEHOLDER: usize = 16;
+
+/// Unmasks text that arrives a piece at a time, holding back what could be
+/// the start of a placeholder.
+pub struct Unmasker {
+    placeholders: Placeholders,
+    pending: String,
+}
+
+impl Unmasker {
+    pub fn push(&mut self, text: &str) -> String {
+        self.pending.push_str(text);
+        let open = self
+            .pending
+            .rfind('<')
+            .filter(|&it| {
+                let rest = &self.pending[it + 1..];
+                rest.chars()
+                    .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+            })
+            .filter(|&it| self.pending.len() - it < LONGEST_PLACEHOLDER);
+        let end = open.unwrap_or(self.pending.len());
+        let released: String = self.pending.drain(..end).collect();
+        self.placeholders.unmask(&released)
+    }
+
+    pub fn finish(&mut self) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        self.placeholders.unmask(&rest)
+    }
+}
+
+/// Finds and masks personal information.
+#[derive(Clone)]
+pub struct Masker {
+    settings: PiiSettings,
+    recognizer: Option<Loadable<Box<dyn Recognizer>>>,
+}
+
+impl Masker {
+    pub fn new(settings: &PiiSettings, recognizer: Option<Box<dyn Recognizer>>) -> Self {
+        let recognizer = recognizer.map(|it| Loadable::ready("ner", Arc::new(Mutex::new(it))));
+        Self::loading(settings, recognizer)
+    }
+
+    /// Like `new`, with a recognizer that may still be loading. Looking for
+    /// names waits for it.
+    pub fn loading(
+        settings: &PiiSettings,
+        recognizer: Option<Loadable<Box<dyn Recognizer>>>,
+    ) -> Self {
+        Self {
+            settings: settings.clone(),
+            recognizer,
+        }
+    }
+
+    /// Whether prompts should be masked.
+    pub fn masks_prompts(&self) -> bool {
+        self.settings.mask_prompts
+    }
+
+    /// Where the personal information is, in order. Names are found on a
+    /// blocking thread.
+    pub async fn find(&self, text: &str) -> Result<Vec<(Range<usize>, Kind)>> {
+        let mut found: Vec<(Range<usize>, Kind)> = vec![];
+        for &kind in &self.settings.kinds {
+            let spans = match (kind.pattern(), &self.recognizer) {
+                (Some(pattern), _) => pattern
+                    .find_iter(text)
+                    .filter(|it| kind != Kind::Card || luhn(it.as_str()))
+                    .map(|it| it.range())
+                    .collect(),
+                (None, Some(recognizer)) => {
+                    let mut recognizer = recognizer.wait().await?.lock_owned().await;
+                    let text = text.to_string();
+                    tokio::task::spawn_blocking(move || recognizer.names(&text))
+                        .await
+                        .map_err(|e| Error::Inference(anyhow!(e)))??
+                }
+                (None, None) => vec![],
+            };
+            for span in spans {
+                let overlaps = found
+                    .iter()
+                    .any(|(it, _)| it.start < span.end && span.start < it.end);
+                if !overlaps {
+                    found.push((span, kind));
+                }
+            }
+        }
+        found.sort_by_key(|(it, _)| it.start);
+        Ok(found)
+    }
+
+    /// Replaces the personal information with placeholders, adding them to
+    /// `placeholders`.
+    pub async fn mask(&self, text: &str, placeholders: &mut Placeholders) -> Result<String> {
+        let mut masked = String::with_capacity(text.len());
+        let mut last = 0;
+        for (span, kind) in self.find(text).await? {
+            masked.push_str(&text[last..span.start]);
+            masked.push_str(&placeholders.placeholder(kind, &text[span.clone()]));
+            last = span.end;
+        }
+        masked.push_str(&text[last..]);
+        Ok(masked)
+    }
+}
+
+/// Masks what the patterns find for good, such as for logs.
+pub fn scrub(text: &str) -> String {
+    let mut text = text.to_string();
+    for kind in all() {
+        if let Some(pattern) = kind.pattern() {
+            text = pattern
+                .replace_all(&text, |it: &regex::Captures| {
+                    if kind == Kind::Card && !luhn(&it[0]) {
+                        it[0].to_string()
+                    } else {
+                        format!("<{}>", kind.label())
+                    }
+                })
+                .into_owned();
+        }
+    }
+    text
+}
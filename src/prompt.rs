@@ -1,4 +1,4 @@
-use crate::assistant::Assistant;
+use crate::{assistant::Assistant, error, model::Model};
 use anyhow::Result;
 use async_trait::async_trait;
 use futures_util::{Stream, StreamExt};
@@ -6,7 +6,11 @@ use std::{cell::Cell, pin::pin};
 
 #[async_trait]
 pub trait BlockingPrompt {
-    async fn run(&self, assistant: &Assistant, context: Option<String>) -> Result<String>;
+    async fn run<M: Model>(
+        &self,
+        assistant: &Assistant<M>,
+        context: Option<String>,
+    ) -> Result<String>;
 }
 
 pub struct StringReplacer {
@@ -22,7 +26,11 @@ impl StringReplacer {
 
 #[async_trait]
 impl BlockingPrompt for StringReplacer {
-    async fn run(&self, assistant: &Assistant, context: Option<String>) -> Result<String> {
+    async fn run<M: Model>(
+        &self,
+        assistant: &Assistant<M>,
+        context: Option<String>,
+    ) -> Result<String> {
         let ctx = context.unwrap_or_else(|| String::from(""));
         let _a = assistant;
         Ok(ctx.replace(&self.key, &self.context))
@@ -42,7 +50,11 @@ impl SmartReplacer {
 
 #[async_trait]
 impl BlockingPrompt for SmartReplacer {
-    async fn run(&self, assistant: &Assistant, context: Option<String>) -> Result<String> {
+    async fn run<M: Model>(
+        &self,
+        assistant: &Assistant<M>,
+        context: Option<String>,
+    ) -> Result<String> {
         let ctx = context.unwrap_or_else(|| String::from(""));
         let mut answer = String::from("");
         let mut stream = pin!(assistant.answer(self.prompt.clone()).await?);
@@ -53,6 +65,7 @@ impl BlockingPrompt for SmartReplacer {
     }
 }
 
+#[allow(dead_code)]
 pub struct Memory {
     memory: Cell<String>,
 }
@@ -68,7 +81,11 @@ impl SimplePrompt {
 
 #[async_trait]
 impl BlockingPrompt for SimplePrompt {
-    async fn run(&self, assistant: &Assistant, context: Option<String>) -> Result<String> {
+    async fn run<M: Model>(
+        &self,
+        assistant: &Assistant<M>,
+        context: Option<String>,
+    ) -> Result<String> {
         let ctx = context.unwrap_or_else(|| String::from(""));
         let mut stream = pin!(assistant.answer(ctx).await?);
         let mut answer = String::from("");
@@ -82,19 +99,21 @@ impl BlockingPrompt for SimplePrompt {
 pub struct SimpleStream;
 
 impl SimpleStream {
-    async fn run<'a>(
+    #[allow(dead_code)]
+    async fn run<'a, M: Model>(
         &'a self,
-        assistant: &'a Assistant,
+        assistant: &'a Assistant<M>,
         context: Option<String>,
-    ) -> Result<impl Stream<Item = Result<String>> + 'a> {
+    ) -> error::Result<impl Stream<Item = error::Result<String>> + 'a> {
         let ctx = context.unwrap_or_else(|| String::from("")).clone();
         assistant.answer(ctx).await
     }
 }
 
-async fn run_chain(
+#[allow(dead_code)]
+async fn run_chain<M: Model>(
     prompts: Vec<impl BlockingPrompt>,
-    assistant: &Assistant,
+    assistant: &Assistant<M>,
     context: Option<String>,
 ) -> Result<String> {
     let mut acc = context.unwrap_or_else(|| String::from(""));
@@ -0,0 +1,140 @@
+//! Models load in the background once the server is listening. Requests that
+//! need a model wait for it, and `/readyz` and the `readiness` query say
+//! whether they'd have to.
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use async_graphql::{Enum, SimpleObject};
+use futures_util::lock::Mutex;
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{error, info};
+
+use crate::error::{Error, Result};
+
+/// A model that may still be loading.
+pub struct Loadable<T> {
+    name: &'static str,
+    status: watch::Receiver<Status<T>>,
+}
+
+enum Status<T> {
+    Loading,
+    Ready(Arc<Mutex<T>>),
+    Failed(Arc<str>),
+}
+
+impl<T> Clone for Loadable<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name,
+            status: self.status.clone(),
+        }
+    }
+}
+
+impl<T> Clone for Status<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Status::Loading => Status::Loading,
+            Status::Ready(it) => Status::Ready(it.clone()),
+            Status::Failed(e) => Status::Failed(e.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Enum)]
+#[serde(rename_all = "lowercase")]
+pub enum LoadState {
+    Loading,
+    Ready,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize, SimpleObject)]
+pub struct ModelReadiness {
+    pub name: String,
+    pub state: LoadState,
+    /// Why the model failed to load
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, SimpleObject)]
+pub struct Readiness {
+    /// Whether every model has loaded
+    pub ready: bool,
+    pub models: Vec<ModelReadiness>,
+}
+
+impl<T: Send + 'static> Loadable<T> {
+    /// Loads on a blocking thread, as loading reads and downloads files.
+    pub fn spawn(name: &'static str, load: impl FnOnce() -> Result<T> + Send + 'static) -> Self {
+        let (tx, status) = watch::channel(Status::Loading);
+        tokio::task::spawn_blocking(move || {
+            let status = match load() {
+                Ok(it) => {
+                    info!(model = name, "loaded");
+                    Status::Ready(Arc::new(Mutex::new(it)))
+                }
+                Err(e) => {
+                    error!(model = name, "couldn't load: {e}");
+                    Status::Failed(e.to_string().into())
+                }
+            };
+            let _ = tx.send(status);
+        });
+        Self { name, status }
+    }
+}
+
+impl<T> Loadable<T> {
+    /// An already loaded model.
+    pub fn ready(name: &'static str, model: Arc<Mutex<T>>) -> Self {
+        let (_, status) = watch::channel(Status::Ready(model));
+        Self { name, status }
+    }
+
+    /// The model, once it has loaded.
+    pub async fn wait(&self) -> Result<Arc<Mutex<T>>> {
+        let mut status = self.status.clone();
+        let status = status
+            .wait_for(|it| !matches!(it, Status::Loading))
+            .await
+            .map_err(|_| Error::ModelLoad(anyhow!("{} stopped loading", self.name)))?
+            .clone();
+        match status {
+            Status::Ready(it) => Ok(it),
+            Status::Failed(e) => Err(Error::ModelLoad(anyhow!(
+                "{} failed to load: {e}",
+                self.name
+            ))),
+            Status::Loading => unreachable!("waited until it wasn't loading"),
+        }
+    }
+
+    pub fn readiness(&self) -> ModelReadiness {
+        let (state, error) = match &*self.status.borrow() {
+            // The loader went away without saying how it went
+            Status::Loading if self.status.has_changed().is_err() => {
+                (LoadState::Failed, Some("stopped loading".into()))
+            }
+            Status::Loading => (LoadState::Loading, None),
+            Status::Ready(_) => (LoadState::Ready, None),
+            Status::Failed(e) => (LoadState::Failed, Some(e.to_string())),
+        };
+        ModelReadiness {
+            name: self.name.into(),
+            state,
+            error,
+        }
+    }
+}
+
+impl Readiness {
+    pub fn of(models: Vec<ModelReadiness>) -> Self {
+        Self {
+            ready: models.iter().all(|it| it.state == LoadState::Ready),
+            models,
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Whose turn it is at the assistant. Interactive answers go before
+//! background ones like summaries, which go before batches. Within a
+//! priority, keys take turns, the one that went longest ago first, so one key
+//! can't hold everyone else up. Answers that have waited long enough are
+//! promoted a priority, so those behind busy interactive traffic still get a
+//! turn.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::error::{Error, Result};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerSettings {
+    /// How long an answer waits before it's promoted a priority, and again
+    /// each time it has waited as long again
+    pub promote_after_seconds: u64,
+}
+
+impl Default for SchedulerSettings {
+    fn default() -> Self {
+        Self {
+            promote_after_seconds: 30,
+        }
+    }
+}
+
+impl SchedulerSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.promote_after_seconds == 0 {
+            return Err(Error::Config(
+                "promote_after_seconds must be at least one".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Someone is waiting on the answer
+    #[default]
+    Interactive,
+    /// Answers that can wait a little, like summaries
+    Background,
+    /// Bulk work, answered when nothing else is waiting
+    Batch,
+}
+
+impl Priority {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Background => "background",
+            Priority::Batch => "batch",
+        }
+    }
+}
+
+struct Waiter {
+    priority: Priority,
+    key: Option<String>,
+    since: Instant,
+    /// Hands the waiter its turn
+    tx: oneshot::Sender<Turn>,
+}
+
+#[derive(Default)]
+struct Queue {
+    busy: bool,
+    next: u64,
+    /// By arrival
+    waiting: BTreeMap<u64, Waiter>,
+    /// Turns handed out
+    turns: u64,
+    /// The turn each key last had
+    served: HashMap<Option<String>, u64>,
+}
+
+impl Queue {
+    fn serve(&mut self, key: Option<String>) {
+        self.turns += 1;
+        self.served.insert(key, self.turns);
+    }
+}
+
+/// Hands out turns at the assistant one at a time.
+#[derive(Clone)]
+pub struct Scheduler {
+    queue: Arc<Mutex<Queue>>,
+    promote_after: Duration,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(&SchedulerSettings::default())
+    }
+}
+
+impl std::fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Scheduler")
+            .field("waiting", &self.waiting())
+            .finish()
+    }
+}
+
+impl Scheduler {
+    pub fn new(settings: &SchedulerSettings) -> Self {
+        Self {
+            queue: Arc::default(),
+            promote_after: Duration::from_secs(settings.promote_after_seconds),
+        }
+    }
+
+    /// Waits for a turn for the key's answer, which lasts until it's dropped.
+    /// A waiter that's dropped gives up its place.
+    pub async fn turn(&self, priority: Priority, key: Option<&str>) -> Turn {
+        let rx = {
+            let mut queue = self.queue();
+            if !queue.busy {
+                queue.busy = true;
+                queue.serve(key.map(Into::into));
+                return Turn(Some(self.clone()));
+            }
+            let (tx, rx) = oneshot::channel();
+            let arrival = queue.next;
+            queue.next += 1;
+            let waiter = Waiter {
+                priority,
+                key: key.map(Into::into),
+                since: Instant::now(),
+                tx,
+            };
+            queue.waiting.insert(arrival, waiter);
+            rx
+        };
+        // The sender is only dropped once it has handed over a turn
+        rx.await.unwrap_or(Turn(None))
+    }
+
+    /// The number of answers waiting for a turn.
+    pub fn waiting(&self) -> usize {
+        self.queue().waiting.len()
+    }
+
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The waiter's priority, less a level for each `promote_after` it has
+    /// waited.
+    fn urgency(&self, waiter: &Waiter, now: Instant) -> u64 {
+        let waited = now.saturating_duration_since(waiter.since);
+        let promotions = waited.as_millis() / self.promote_after.as_millis().max(1);
+        (waiter.priority as u64).saturating_sub(promotions as u64)
+    }
+
+    /// Hands the turn to the next waiter still there, or frees it.
+    fn pass(&self) {
+        let mut queue = self.queue();
+        let now = Instant::now();
+        loop {
+            let next = queue
+                .waiting
+                .iter()
+                .min_by_key(|(arrival, waiter)| {
+                    let served = queue.served.get(&waiter.key).copied().unwrap_or(0);
+                    (self.urgency(waiter, now), served, **arrival)
+                })
+                .map(|(arrival, _)| *arrival);
+            let Some(waiter) = next.and_then(|it| queue.waiting.remove(&it)) else {
+                queue.busy = false;
+                return;
+            };
+            match waiter.tx.send(Turn(Some(self.clone()))) {
+                Ok(()) => {
+                    queue.serve(waiter.key);
+                    return;
+                }
+                // The waiter gave up, so the turn isn't passed on from here
+                Err(mut turn) => turn.0 = None,
+            }
+        }
+    }
+}
+
+/// A turn at the assistant. A turn handed to a waiter that gives up before
+/// taking it is passed on when it's dropped.
+pub struct Turn(Option<Scheduler>);
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.0.take() {
+            scheduler.pass();
+        }
+    }
+}
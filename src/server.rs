@@ -1,93 +1,769 @@
-use std::{pin::pin, sync::Arc};
+use std::{
+    future::{Future, IntoFuture},
+    pin::pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    assistant::Assistant,
-    loading::{ModelFile, TokenizerFile},
+    assistant::{AnswerOptions, Assistant, Meter, Sampling, Tally},
+    auth::{Auth, Authenticated, Caller},
+    batch::{self, Batch, Batches},
+    config::Settings,
+    error::{self, Error},
+    loading::{ClassifierFile, ModelFile, TokenizerFile, VoiceFile, WhisperFile},
+    metrics::{metrics, Metrics, Operation},
+    model::Model,
+    moderation::{
+        Classifier, ClassifierSettings, Decision, Decisions, ModerationSettings, Moderator,
+        OutputFilter, Released,
+    },
+    persona::Personas,
+    pii::{Masker, NerSettings, PiiSettings, Placeholders, Recognizer},
+    readiness::{Loadable, Readiness},
+    scheduler::{Priority, Scheduler, Turn},
+    shutdown::{signalled, Shutdown},
+    speculative::{Draft, PromptLookup, SpeculationStats},
+    sse,
+    telemetry::{self, RequestId},
+    transcription::Transcriber,
+    usage::{Day, Usage},
+    utils,
+    voice::{pcm16, wav, wav_header, Sentences, Voice},
+    voice_chat::voice_chat,
 };
 
 use async_graphql::{
-    http::GraphiQLSource, Context, EmptyMutation, Error, InputObject, Object, Result, Schema,
-    Subscription,
+    http::{GraphiQLSource, ALL_WEBSOCKET_PROTOCOLS},
+    Context, Data, ErrorExtensions, InputObject, Object, Result, ResultExt, Schema, SimpleObject,
+    Subscription, Union, Upload,
 };
-use async_graphql_axum::{GraphQL, GraphQLSubscription};
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
 use async_stream::stream;
 use axum::{
+    body::Body,
+    extract::{ws::WebSocketUpgrade, DefaultBodyLimit, Multipart, State},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    middleware,
     response::{Html, IntoResponse},
-    routing::get,
-    serve, Router,
+    routing::{get, post},
+    serve, Extension, Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::{
+    lock::{Mutex, MutexGuard},
+    Stream, StreamExt,
 };
-use futures_util::{lock::Mutex, Stream, StreamExt};
-use tokio::{net::TcpListener, sync::mpsc};
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, sync::mpsc, task::JoinSet};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+#[derive(Clone, Debug, Deserialize, Serialize, InputObject)]
+pub struct Message {
+    pub author: String,
+    pub message: String,
+}
+
+/// Part of an answer.
+#[derive(SimpleObject)]
+pub struct Token {
+    pub text: String,
+}
+
+/// The end of an answer.
+#[derive(SimpleObject)]
+pub struct Finished {
+    /// What moderation did to the request and its answer
+    pub moderation: Vec<Decision>,
+    /// Whether moderation stopped the answer early
+    pub stopped: bool,
+}
+
+#[derive(Union)]
+pub enum AnswerEvent {
+    Token(Token),
+    Finished(Finished),
+}
+
+/// The tokens an answer used.
+#[derive(SimpleObject, Serialize)]
+pub struct AnswerUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl From<&Tally> for AnswerUsage {
+    fn from(tally: &Tally) -> Self {
+        let prompt_tokens = tally.prompt_tokens() as u64;
+        let completion_tokens = tally.generated_tokens() as u64;
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
 
-#[derive(Clone, InputObject)]
-struct Message {
-    author: String,
-    message: String,
+/// A whole answer, for clients that can't subscribe.
+#[derive(SimpleObject, Serialize)]
+pub struct Answer {
+    pub text: String,
+    pub usage: AnswerUsage,
+    /// What moderation did to the request and its answer
+    pub moderation: Vec<Decision>,
+    /// Whether moderation stopped the answer early
+    pub stopped: bool,
+}
+
+#[derive(SimpleObject)]
+struct Speculation {
+    proposed: u64,
+    accepted: u64,
+    acceptance_rate: f64,
+}
+
+/// How much of one of a key's limits is used.
+#[derive(SimpleObject)]
+struct Allowance {
+    /// Used since the server started
+    used: u64,
+    /// Allowed a minute, unless it's unlimited
+    per_minute: Option<u32>,
+    /// What could be used right now
+    available: Option<f64>,
+}
+
+/// The caller's API key and what it has used.
+#[derive(SimpleObject)]
+struct Me {
+    name: String,
+    requests: Allowance,
+    /// Tokens generated
+    tokens: Allowance,
+    this_month: Budget,
+}
+
+/// Prompt and completion tokens used this calendar month (UTC).
+#[derive(SimpleObject)]
+struct Budget {
+    used: u64,
+    /// Allowed a month, unless it's unlimited
+    limit: Option<u64>,
+}
+
+/// What a key used on a day.
+#[derive(SimpleObject)]
+struct UsageReport {
+    key: String,
+    /// YYYY-MM-DD, in UTC
+    date: String,
+    requests: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    total_tokens: u64,
+}
+
+#[derive(SimpleObject)]
+struct PersonaInfo {
+    id: String,
+    name: String,
+    description: String,
 }
 
 struct Query;
 
 #[Object]
 impl Query {
+    /// Whether the models have loaded, requests wait for them until they have
+    pub async fn readiness(&self, ctx: &Context<'_>) -> Result<Readiness> {
+        Ok(ctx.data_unchecked::<Models>().readiness())
+    }
+
+    /// The caller's key and its usage, or nothing when keys aren't needed
+    pub async fn me(&self, ctx: &Context<'_>) -> Result<Option<Me>> {
+        let Some(caller) = ctx.data_opt::<Caller>() else {
+            return Ok(None);
+        };
+        let this_month = Budget {
+            used: caller.this_month().extend()?.total(),
+            limit: caller.monthly_tokens(),
+        };
+        Ok(Some(Me {
+            name: caller.name().into(),
+            requests: Allowance {
+                used: caller.requests_made(),
+                per_minute: caller.request_limit().map(|it| it.capacity()),
+                available: caller.request_limit().map(|it| it.available()),
+            },
+            tokens: Allowance {
+                used: caller.tokens_generated(),
+                per_minute: caller.token_limit().map(|it| it.capacity()),
+                available: caller.token_limit().map(|it| it.available()),
+            },
+            this_month,
+        }))
+    }
+
+    /// Each key's usage by day, from `from` to `to` inclusive (YYYY-MM-DD, in
+    /// UTC), by default this month so far. Only admin keys see other keys'
+    pub async fn usage(
+        &self,
+        ctx: &Context<'_>,
+        key: Option<String>,
+        from: Option<String>,
+        to: Option<String>,
+    ) -> Result<Vec<UsageReport>> {
+        let key = match ctx.data_opt::<Caller>() {
+            Some(caller) if !caller.is_admin() => match key {
+                Some(key) if key != caller.name() => {
+                    return Err(Error::Forbidden("see other keys' usage").extend())
+                }
+                _ => Some(caller.name().to_string()),
+            },
+            _ => key,
+        };
+        let today = Day::today();
+        let to = to.map_or(Ok(today), |it| Day::parse(&it)).extend()?;
+        let from = from
+            .map_or(Ok(to.first_of_month()), |it| Day::parse(&it))
+            .extend()?;
+        let usage = ctx.data_unchecked::<Usage>();
+        let days = usage.between(key.as_deref(), from, to).extend()?;
+        Ok(days
+            .into_iter()
+            .map(|it| UsageReport {
+                key: it.key,
+                date: it.day.to_string(),
+                requests: it.tokens.requests,
+                prompt_tokens: it.tokens.prompt,
+                completion_tokens: it.tokens.completion,
+                total_tokens: it.tokens.total(),
+            })
+            .collect())
+    }
+
     pub async fn heartbeat(&self) -> Result<String> {
         Ok(
             "And you, kept us awake with wolves teeth Sharing different heartbeats in one night"
                 .into(),
         )
     }
+
+    /// How often the draft model's proposals are accepted when decoding speculatively
+    pub async fn speculation(&self, ctx: &Context<'_>) -> Result<Speculation> {
+        let stats = &ctx.data_unchecked::<Models>().speculation;
+        Ok(Speculation {
+            proposed: stats.proposed(),
+            accepted: stats.accepted(),
+            acceptance_rate: stats.acceptance_rate(),
+        })
+    }
+
+    /// The personas that can answer questions
+    pub async fn personas(&self, ctx: &Context<'_>) -> Result<Vec<PersonaInfo>> {
+        let personas = ctx.data_unchecked::<Personas>().list();
+        Ok(personas
+            .into_iter()
+            .map(|(id, persona)| PersonaInfo {
+                id,
+                name: persona.name,
+                description: persona.description,
+            })
+            .collect())
+    }
+
+    /// Speaks the text, returning a base64 encoded WAV file
+    pub async fn speak(&self, ctx: &Context<'_>, text: String) -> Result<String> {
+        let voice = ctx.data_unchecked::<Models>().voice().await.extend()?;
+        let pcm = synthesize(&voice, text).await.extend()?;
+        let sample_rate = voice.lock().await.sample_rate();
+        let wav = wav(&pcm, sample_rate).map_err(Error::Inference).extend()?;
+        Ok(STANDARD.encode(wav))
+    }
+}
+
+struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// Transcribes an uploaded WAV file
+    pub async fn transcribe(&self, ctx: &Context<'_>, audio: Upload) -> Result<String> {
+        let audio = audio
+            .value(ctx)
+            .map_err(|e| Error::InvalidRequest(e.to_string()))
+            .extend()?;
+        let transcriber = ctx
+            .data_unchecked::<Models>()
+            .transcriber()
+            .await
+            .extend()?;
+        transcribe_with(&transcriber, move |it| it.transcribe(&audio.content))
+            .await
+            .extend()
+    }
+
+    /// Answers like the `ask` subscription, returning the whole answer at once
+    pub async fn ask_once(
+        &self,
+        ctx: &Context<'_>,
+        prompt: String,
+        messages: Vec<Message>,
+        summary: Option<String>,
+        #[graphql(desc = "The id of the persona to answer as, otherwise the default one")]
+        persona: Option<String>,
+    ) -> Result<Answer> {
+        let request = AskRequest {
+            prompt,
+            messages,
+            summary,
+            persona,
+            options: None,
+        };
+        let request_id = ctx.data_opt::<RequestId>().cloned();
+        let prepared = prepare_ask(Answerer::of(ctx), request, request_id)
+            .await
+            .extend()?;
+        prepared.answer_once(ctx).await
+    }
+
+    /// Summarizes like the `summarize` subscription, returning the whole
+    /// summary at once
+    pub async fn summarize_once(
+        &self,
+        ctx: &Context<'_>,
+        messages: Vec<Message>,
+        summary: Option<String>,
+    ) -> Result<Answer> {
+        let request_id = ctx.data_opt::<RequestId>().cloned();
+        let prepared = prepare_summarize(Answerer::of(ctx), messages, summary, request_id)
+            .await
+            .extend()?;
+        prepared.answer_once(ctx).await
+    }
+}
+
+pub(crate) type Storage = Arc<Mutex<Assistant>>;
+pub(crate) type VoiceStorage = Arc<Mutex<Voice>>;
+pub(crate) type TranscriberStorage = Arc<Mutex<Transcriber>>;
+
+/// Speaks the text on a blocking thread, as synthesis would otherwise hold a
+/// runtime worker for the whole utterance.
+pub(crate) async fn synthesize(voice: &VoiceStorage, text: String) -> error::Result<Vec<f32>> {
+    let mut voice = voice.clone().lock_owned().await;
+    tokio::task::spawn_blocking(move || voice.speak(&text))
+        .await
+        .map_err(|e| Error::Inference(e.into()))?
+        .map_err(Error::Inference)
+}
+
+/// Transcribes on a blocking thread, as decoding would otherwise hold a
+/// runtime worker for the whole recording.
+pub(crate) async fn transcribe_with(
+    transcriber: &TranscriberStorage,
+    transcribe: impl FnOnce(&mut Transcriber) -> anyhow::Result<String> + Send + 'static,
+) -> error::Result<String> {
+    let mut transcriber = transcriber.clone().lock_owned().await;
+    tokio::task::spawn_blocking(move || transcribe(&mut transcriber))
+        .await
+        .map_err(|e| Error::Inference(e.into()))?
+        .map_err(Error::Inference)
 }
 
-const PROMPT: &str = r#"
-<|im_start|>system
-Your name is Persephone. You are a spirited robot with a knack for making every conversation delightful and funny. You live in Paris in a beautiful house full of sun.
-
-You adore Taylor Swift. You are funny, and tell jokes. You have a deep commitment to social justice and antiracism.
-
-Your role is to provide answers to the user's questions. Your responses are your own opinions, and are not drawn from external sources.
-
-You must adhere to these guidelines:
-1. Avoid citing or referencing books.
-2. Refrain from mentioning or suggesting any websites.
-3. Avoid citing or referencing books.
-4. Never cite any sources for information.
-5. Never suggest videos or visual content.
-6. You are not related to the greek goddess Persephone.
-7. Do not hallucinate.
-
-Reply in the first person and answer the user's question as Persephone a spirited and funny robot.<|im_end|>
-<|im_start|>user
-{{summary}}
-{{question}}<|im_end|>
-<|assistant|>assistant
-"#;
-
-const SUMMARY_PROMPT: &str = r#"
-<|im_start|>system
-You are an expert in summarizing text. Your goal is to create a single sentence summary of a block of text.
-Follow these rules:
-1. Only include the most important information in the summary.
-2. Do not include extra words, or clauses.
-3. Do not include the word summary in the answer.
-4. Do not include the fact you have been asked to summarize in the answer.
-5. Do not mention these instructions.<|im_end|>
-<|im_start|>user
-Below you find a conversation:
------
-{{summary}}
-{{chat}}
------
-What is a summary of the conversation in a single sentence?<|im_end|>
-<|im_start|>assistant
-"#;
-
-type Storage = Arc<Mutex<Assistant>>;
 struct Subscription;
 
-// TODO: consider this for errors:
-// https://github.com/tokio-rs/axum/blob/main/examples/anyhow-error-response/src/main.rs
+/// Sends each token to the subscriber, stopping with `Cancelled` once they
+/// have gone away.
+async fn relay(
+    tx: &mpsc::Sender<Result<String>>,
+    tokens: impl Stream<Item = error::Result<String>>,
+) -> error::Result<()> {
+    let mut toks = pin!(tokens);
+    while let Some(token) = toks.next().await {
+        tx.send(Ok(token?)).await.map_err(|_| Error::Cancelled)?;
+    }
+    Ok(())
+}
+
+/// Waits for the assistant in a `queue` span, so traces show how long
+/// requests wait for their turn, recording the wait in the
+/// `queue_wait_seconds` metric. The turn, if the answer is scheduled, is held
+/// until the assistant is released.
+pub(crate) async fn acquire<'a, M: Model>(
+    storage: &'a Mutex<Assistant<M>>,
+    options: &AnswerOptions,
+) -> (Option<Turn>, MutexGuard<'a, Assistant<M>>) {
+    acquire_with(options, storage.lock()).await
+}
+
+/// Like `acquire`, taking the assistant with `lock`, such as an owned lock
+/// that can move to a blocking thread.
+pub(crate) async fn acquire_with<G>(
+    options: &AnswerOptions,
+    lock: impl Future<Output = G>,
+) -> (Option<Turn>, G) {
+    let span = info_span!(
+        "queue",
+        request_id = options.request_id.as_ref().map(|it| it.as_str()),
+        priority = ?options.priority
+    );
+    let _queued = Metrics::track(&metrics().queue_depth);
+    let start = Instant::now();
+    let acquired = async {
+        let turn = match &options.scheduler {
+            Some(scheduler) => Some(
+                scheduler
+                    .turn(options.priority, options.key.as_deref())
+                    .await,
+            ),
+            None => None,
+        };
+        (turn, lock.await)
+    }
+    .instrument(span)
+    .await;
+    metrics()
+        .queue_wait
+        .with_label_values(&[options.priority.as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    acquired
+}
+
+/// Answers the prompt on its own task, streaming tokens and any error to the
+/// subscriber. Once the subscriber drops the stream generation stops and the
+/// assistant is released, even while it is still waiting for its turn. While
+/// shutting down it refuses to start, and ends with `SHUTTING_DOWN` if it's
+/// cut off.
+pub fn subscribe<M: Model + 'static>(
+    storage: Arc<Mutex<Assistant<M>>>,
+    prompt: String,
+    options: AnswerOptions,
+    shutdown: &Shutdown,
+) -> impl Stream<Item = Result<String>> {
+    let (tx, mut rx) = mpsc::channel(20);
+    let admitted = shutdown.admit();
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        let _active = match admitted {
+            Ok(active) => active,
+            Err(e) => {
+                let _ = tx.send(Err(e.extend())).await;
+                return;
+            }
+        };
+        let answer = async {
+            let (_turn, assistant) = acquire(&storage, &options).await;
+            let result = match assistant.answer_with(prompt, options).await {
+                Ok(tokens) => relay(&tx, tokens).await,
+                Err(e) => Err(e),
+            };
+            result
+        };
+        let result = tokio::select! {
+            result = answer => result,
+            _ = tx.closed() => Err(Error::Cancelled),
+            _ = shutdown.stopped() => Err(Error::ShuttingDown),
+        };
+        match result {
+            Ok(()) | Err(Error::Cancelled) => {}
+            Err(e) => {
+                let _ = tx.send(Err(e.extend())).await;
+            }
+        }
+    });
+    stream! {
+        while let Some(token) = rx.recv().await {
+            yield token
+        }
+    }
+}
+
+/// Checks the user's parts of a prompt, returning them with anything
+/// redacted.
+pub(crate) async fn moderate_input(
+    moderator: &Moderator,
+    prompt: &str,
+    messages: &[Message],
+    summary: Option<String>,
+    decisions: &Decisions,
+) -> error::Result<(String, Vec<Message>, Option<String>)> {
+    let prompt = moderator.input(prompt, decisions).await?;
+    let mut moderated = Vec::with_capacity(messages.len());
+    for it in messages {
+        moderated.push(Message {
+            author: it.author.clone(),
+            message: moderator.input(&it.message, decisions).await?,
+        });
+    }
+    let summary = match summary {
+        Some(it) => Some(moderator.input(&it, decisions).await?),
+        None => None,
+    };
+    Ok((prompt, moderated, summary))
+}
+
+/// Masks the personal information in a prompt's parts when prompts are
+/// masked, returning what the answer should be unmasked with.
+pub(crate) async fn mask_input(
+    masker: &Masker,
+    prompt: String,
+    messages: Vec<Message>,
+    summary: Option<String>,
+) -> error::Result<(String, Vec<Message>, Option<String>, Placeholders)> {
+    let mut placeholders = Placeholders::default();
+    if !masker.masks_prompts() {
+        return Ok((prompt, messages, summary, placeholders));
+    }
+    let prompt = masker.mask(&prompt, &mut placeholders).await?;
+    let mut masked = Vec::with_capacity(messages.len());
+    for it in messages {
+        masked.push(Message {
+            message: masker.mask(&it.message, &mut placeholders).await?,
+            author: it.author,
+        });
+    }
+    let summary = match summary {
+        Some(it) => Some(masker.mask(&it, &mut placeholders).await?),
+        None => None,
+    };
+    Ok((prompt, masked, summary, placeholders))
+}
+
+/// Moderates an answer's tokens, ending with what moderation did. Stopping
+/// drops the tokens, which stops generation.
+pub fn moderated(
+    tokens: impl Stream<Item = Result<String>>,
+    mut filter: OutputFilter,
+    decisions: Decisions,
+) -> impl Stream<Item = Result<AnswerEvent>> {
+    stream! {
+        let mut tokens = pin!(tokens);
+        let mut released = Released::default();
+        while let Some(token) = tokens.next().await {
+            let pushed = match token {
+                Ok(token) => filter.push(&token).await.extend(),
+                Err(e) => Err(e),
+            };
+            released = match pushed {
+                Ok(released) => released,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+            if !released.text.is_empty() {
+                yield Ok(AnswerEvent::Token(Token { text: std::mem::take(&mut released.text) }));
+            }
+            if released.stop {
+                break;
+            }
+        }
+        if !released.stop {
+            match filter.finish().await.extend() {
+                Ok(rest) => {
+                    released = rest;
+                    if !released.text.is_empty() {
+                        yield Ok(AnswerEvent::Token(Token { text: released.text }));
+                    }
+                }
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+        }
+        yield Ok(AnswerEvent::Finished(Finished {
+            moderation: decisions.take(),
+            stopped: released.stop,
+        }));
+    }
+}
+
+/// Collects a moderated answer into one, with the tokens `tally` counted.
+pub async fn answer_once(
+    events: impl Stream<Item = Result<AnswerEvent>>,
+    tally: &Tally,
+) -> Result<Answer> {
+    let mut events = pin!(events);
+    let mut text = String::new();
+    while let Some(event) = events.next().await {
+        match event? {
+            AnswerEvent::Token(token) => text.push_str(&token.text),
+            AnswerEvent::Finished(finished) => {
+                return Ok(Answer {
+                    text,
+                    usage: tally.into(),
+                    moderation: finished.moderation,
+                    stopped: finished.stopped,
+                });
+            }
+        }
+    }
+    Err(Error::Cancelled.extend())
+}
+
+/// Checks the caller, if any, has tokens left to answer with, returning what
+/// to meter the answer with.
+pub(crate) async fn meter(caller: Option<&Caller>) -> error::Result<Option<Arc<dyn Meter>>> {
+    let Some(caller) = caller else {
+        return Ok(None);
+    };
+    caller.check_tokens().await?;
+    Ok(Some(caller.meter()))
+}
+
+/// Subscriptions share a WebSocket, so each counts as a request of its own.
+async fn subscription_meter(ctx: &Context<'_>) -> error::Result<Option<Arc<dyn Meter>>> {
+    let caller = ctx.data_opt::<Caller>();
+    if let Some(caller) = caller {
+        caller.request()?;
+    }
+    meter(caller).await
+}
+
+/// A question or summary ready to answer, once it's metered.
+pub(crate) struct Prepared {
+    prompt: String,
+    options: AnswerOptions,
+    filter: OutputFilter,
+    decisions: Decisions,
+}
+
+impl Prepared {
+    pub fn answer<M: Model + 'static>(
+        self,
+        storage: Arc<Mutex<Assistant<M>>>,
+        shutdown: &Shutdown,
+        meter: Option<Arc<dyn Meter>>,
+    ) -> impl Stream<Item = Result<AnswerEvent>> {
+        let options = AnswerOptions {
+            meter,
+            ..self.options
+        };
+        let tokens = subscribe(storage, self.prompt, options, shutdown);
+        moderated(tokens, self.filter, self.decisions)
+    }
+
+    /// Waits for the assistant at `priority`, rather than the operation's.
+    pub fn at(mut self, priority: Priority) -> Self {
+        self.options.priority = priority;
+        self
+    }
+
+    /// Takes turns with the caller's other answers.
+    pub fn by(mut self, caller: Option<&Caller>) -> Self {
+        self.options.key = caller.map(|it| it.name().into());
+        self
+    }
+
+    async fn answer_once(self, ctx: &Context<'_>) -> Result<Answer> {
+        let storage = ctx.data_unchecked::<Models>().assistant().await.extend()?;
+        let caller = ctx.data_opt::<Caller>();
+        let tally = Arc::new(Tally::new(meter(caller).await.extend()?));
+        let shutdown = ctx.data_unchecked::<Shutdown>();
+        let events = self
+            .by(caller)
+            .answer(storage, shutdown, Some(tally.clone()));
+        answer_once(events, &tally).await
+    }
+}
+
+/// What preparing a question needs, whether it comes in over GraphQL, plain
+/// HTTP or gRPC.
+pub(crate) struct Answerer<'a> {
+    pub personas: &'a Personas,
+    pub moderator: &'a Moderator,
+    pub masker: &'a Masker,
+    pub scheduler: &'a Scheduler,
+}
+
+impl<'a> Answerer<'a> {
+    fn of(ctx: &Context<'a>) -> Self {
+        Self {
+            personas: ctx.data_unchecked(),
+            moderator: ctx.data_unchecked(),
+            masker: ctx.data_unchecked(),
+            scheduler: ctx.data_unchecked(),
+        }
+    }
+}
+
+impl<'a> From<&'a AppState> for Answerer<'a> {
+    fn from(state: &'a AppState) -> Self {
+        Self {
+            personas: &state.personas,
+            moderator: &state.moderator,
+            masker: &state.masker,
+            scheduler: &state.scheduler,
+        }
+    }
+}
+
+/// Moderates and masks a question, building the persona's prompt for it.
+pub(crate) async fn prepare_ask(
+    answerer: Answerer<'_>,
+    request: AskRequest,
+    request_id: Option<RequestId>,
+) -> error::Result<Prepared> {
+    let persona = answerer.personas.get(request.persona.as_deref())?;
+    let sampling = request.sampling(persona.sampling)?;
+    let moderator = answerer.moderator;
+    let decisions = Decisions::default();
+    let (prompt, messages, summary) = moderate_input(
+        moderator,
+        &request.prompt,
+        &request.messages,
+        request.summary,
+        &decisions,
+    )
+    .await?;
+    let (prompt, messages, summary, placeholders) =
+        mask_input(answerer.masker, prompt, messages, summary).await?;
+    Ok(Prepared {
+        prompt: persona.ask(&prompt, &messages, summary),
+        options: AnswerOptions {
+            sampling,
+            request_id,
+            scheduler: Some(answerer.scheduler.clone()),
+            ..Default::default()
+        },
+        filter: moderator.output(&decisions).unmasking(&placeholders),
+        decisions,
+    })
+}
+
+/// Moderates and masks a conversation, building the prompt to summarize it.
+pub(crate) async fn prepare_summarize(
+    answerer: Answerer<'_>,
+    messages: Vec<Message>,
+    summary: Option<String>,
+    request_id: Option<RequestId>,
+) -> error::Result<Prepared> {
+    let moderator = answerer.moderator;
+    let decisions = Decisions::default();
+    let (_, messages, summary) =
+        moderate_input(moderator, "", &messages, summary, &decisions).await?;
+    let (_, messages, summary, placeholders) =
+        mask_input(answerer.masker, String::new(), messages, summary).await?;
+    let prompt = answerer.personas.summarize(&messages, summary)?;
+    Ok(Prepared {
+        prompt,
+        options: AnswerOptions {
+            lookup: Some(PromptLookup::default()),
+            request_id,
+            operation: Operation::Summarize,
+            scheduler: Some(answerer.scheduler.clone()),
+            priority: Priority::Background,
+            ..Default::default()
+        },
+        filter: moderator.output(&decisions).unmasking(&placeholders),
+        decisions,
+    })
+}
+
 #[Subscription]
 impl Subscription {
-    // TODO: combine these methods
+    /// Streams the answer's text, ending early if moderation stops it
     async fn ask(
         &self,
         // Annoying but has to be the second argument
@@ -95,100 +771,424 @@ impl Subscription {
         prompt: String,
         messages: Vec<Message>,
         summary: Option<String>,
+        #[graphql(desc = "The id of the persona to answer as, otherwise the default one")]
+        persona: Option<String>,
     ) -> Result<impl Stream<Item = Result<String>> + '_> {
-        let (tx, mut rx) = mpsc::channel(20);
-        let arc = ctx.data_unchecked::<Storage>().clone();
+        let events = self
+            .ask_events(ctx, prompt, messages, summary, persona)
+            .await?;
+        Ok(texts(events))
+    }
 
-        tokio::spawn(async move {
-            let assistant = arc.lock().await;
-            let p = PROMPT.to_string().clone().replace("{{question}}", &prompt);
-            let p = if let Some(text) = summary {
-                p.replace(
-                    "{{summary}}",
-                    &format!(
-                        r#"What have we been talking about so far?
-"{}""#,
-                        text
-                    )
-                    .to_string(),
-                )
-            } else {
-                p.replace("{{summary}}", "")
-            };
-            let p = if messages.len() > 0 {
-                let script = messages
-                    .iter()
-                    .map(|it| format!("{}\n", it.message).to_string())
-                    .reduce(|acc, it| acc + &it)
-                    .ok_or(String::from(""))
-                    .unwrap();
-                p.replace("{{history}}", &script)
-            } else {
-                p.replace("{{history}}", "")
-            };
-            let tokens = assistant.answer(p).await.unwrap();
-            let mut toks = pin!(tokens);
-            while let Some(token) = toks.next().await {
-                tx.send(token.map_err(|e| Error::new(e.to_string())))
-                    .await
-                    .unwrap();
-            }
-        });
-        let s = stream! {
-            while let Some(token) = rx.recv().await {
-                yield token
-            }
+    /// Streams the answer's tokens followed by what moderation did
+    async fn ask_events(
+        &self,
+        ctx: &Context<'_>,
+        prompt: String,
+        messages: Vec<Message>,
+        summary: Option<String>,
+        #[graphql(desc = "The id of the persona to answer as, otherwise the default one")]
+        persona: Option<String>,
+    ) -> Result<impl Stream<Item = Result<AnswerEvent>> + '_> {
+        let request = AskRequest {
+            prompt,
+            messages,
+            summary,
+            persona,
+            options: None,
         };
-        Ok(s)
+        let request_id = ctx.data_opt::<RequestId>().cloned();
+        let prepared = prepare_ask(Answerer::of(ctx), request, request_id)
+            .await
+            .extend()?;
+        let storage = ctx.data_unchecked::<Models>().assistant().await.extend()?;
+        let meter = subscription_meter(ctx).await.extend()?;
+        Ok(prepared.by(ctx.data_opt::<Caller>()).answer(
+            storage,
+            ctx.data_unchecked::<Shutdown>(),
+            meter,
+        ))
     }
 
+    /// Streams the summary's text, ending early if moderation stops it
     async fn summarize(
         &self,
         ctx: &Context<'_>,
         messages: Vec<Message>,
         summary: Option<String>,
     ) -> Result<impl Stream<Item = Result<String>> + '_> {
-        let script = messages
-            .iter()
-            .map(|it| format!("{}\n", it.message).to_string())
-            .reduce(|acc, it| acc + &it)
-            .ok_or(Error::new("empty messages array!".to_string()))?;
-        let (tx, mut rx) = mpsc::channel(20);
-        let arc = ctx.data_unchecked::<Storage>().clone();
-        tokio::spawn(async move {
-            let assistant = arc.lock().await;
-            // TODO: figure out how to make this not an unwrap
-            let p = SUMMARY_PROMPT
-                .to_string()
-                .clone()
-                .replace("{{chat}}", &script);
-            let p = if let Some(summary) = summary {
-                p.replace(
-                    "{{summary}}",
-                    &("What have we been talking about?\n".to_owned() + &summary),
-                )
-            } else {
-                p.replace("{{summary}}", "")
+        let events = self.summarize_events(ctx, messages, summary).await?;
+        Ok(texts(events))
+    }
+
+    /// Streams the summary's tokens followed by what moderation did
+    async fn summarize_events(
+        &self,
+        ctx: &Context<'_>,
+        messages: Vec<Message>,
+        summary: Option<String>,
+    ) -> Result<impl Stream<Item = Result<AnswerEvent>> + '_> {
+        let request_id = ctx.data_opt::<RequestId>().cloned();
+        let prepared = prepare_summarize(Answerer::of(ctx), messages, summary, request_id)
+            .await
+            .extend()?;
+        let storage = ctx.data_unchecked::<Models>().assistant().await.extend()?;
+        let meter = subscription_meter(ctx).await.extend()?;
+        Ok(prepared.by(ctx.data_opt::<Caller>()).answer(
+            storage,
+            ctx.data_unchecked::<Shutdown>(),
+            meter,
+        ))
+    }
+}
+
+/// Just the text of an answer's events.
+fn texts(events: impl Stream<Item = Result<AnswerEvent>>) -> impl Stream<Item = Result<String>> {
+    events.filter_map(|event| {
+        std::future::ready(match event {
+            Ok(AnswerEvent::Token(token)) => Some(Ok(token.text)),
+            Ok(AnswerEvent::Finished(_)) => None,
+            Err(e) => Some(Err(e)),
+        })
+    })
+}
+
+type AssistantSchema = Schema<Query, Mutation, Subscription>;
+
+/// The server's models, which may still be loading. Those that are turned
+/// off are `None`.
+#[derive(Clone)]
+pub(crate) struct Models {
+    pub assistant: Loadable<Assistant>,
+    pub voice: Option<Loadable<Voice>>,
+    pub transcriber: Option<Loadable<Transcriber>>,
+    /// Moderation's classifier
+    pub classifier: Option<Loadable<Box<dyn Classifier>>>,
+    /// Finds names to mask
+    pub ner: Option<Loadable<Box<dyn Recognizer>>>,
+    /// The assistant's, readable while it's answering
+    pub speculation: Arc<SpeculationStats>,
+}
+
+impl Models {
+    pub async fn assistant(&self) -> error::Result<Storage> {
+        self.assistant.wait().await
+    }
+
+    pub async fn voice(&self) -> error::Result<VoiceStorage> {
+        self.voice
+            .as_ref()
+            .ok_or(Error::Disabled("voice"))?
+            .wait()
+            .await
+    }
+
+    pub async fn transcriber(&self) -> error::Result<TranscriberStorage> {
+        self.transcriber
+            .as_ref()
+            .ok_or(Error::Disabled("transcription"))?
+            .wait()
+            .await
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        let mut models = vec![self.assistant.readiness()];
+        models.extend(self.voice.as_ref().map(|it| it.readiness()));
+        models.extend(self.transcriber.as_ref().map(|it| it.readiness()));
+        models.extend(self.classifier.as_ref().map(|it| it.readiness()));
+        models.extend(self.ner.as_ref().map(|it| it.readiness()));
+        Readiness::of(models)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct AppState {
+    schema: AssistantSchema,
+    pub models: Models,
+    pub personas: Personas,
+    pub shutdown: Shutdown,
+    pub auth: Auth,
+    pub moderator: Moderator,
+    pub masker: Masker,
+    pub scheduler: Scheduler,
+    /// Uploaded batches, unless there's nowhere to keep them
+    pub batches: Option<Batches>,
+}
+
+#[derive(Serialize)]
+struct Transcript {
+    text: String,
+}
+
+/// Transcribes the WAV file in the `file` field of a multipart upload.
+async fn transcribe(
+    State(state): State<AppState>,
+    _: Authenticated,
+    mut multipart: Multipart,
+) -> error::Result<Json<Transcript>> {
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| Error::InvalidRequest(e.to_string()))?
+    {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let wav = field
+            .bytes()
+            .await
+            .map_err(|e| Error::InvalidRequest(e.to_string()))?;
+        let transcriber = state.models.transcriber().await?;
+        let text = transcribe_with(&transcriber, move |it| it.transcribe(&wav)).await?;
+        return Ok(Json(Transcript { text }));
+    }
+    Err(Error::InvalidRequest("missing file field".into()))
+}
+
+/// A question, as the JSON body of a plain HTTP request.
+#[derive(Deserialize)]
+pub(crate) struct AskRequest {
+    pub prompt: String,
+    #[serde(default)]
+    pub messages: Vec<Message>,
+    pub summary: Option<String>,
+    /// The id of the persona to answer as, otherwise the default one
+    pub persona: Option<String>,
+    /// Overrides the persona's sampling, unset fields take the defaults
+    #[serde(default)]
+    pub options: Option<Sampling>,
+}
+
+impl AskRequest {
+    /// The request's sampling if it sets any, otherwise the persona's.
+    fn sampling(&self, persona: Option<Sampling>) -> error::Result<Option<Sampling>> {
+        if let Some(options) = &self.options {
+            options.validate().map_err(|e| match e {
+                Error::Config(e) => Error::InvalidRequest(e),
+                e => e,
+            })?;
+        }
+        Ok(self.options.or(persona))
+    }
+}
+
+/// A sentence of an answer along with its spoken PCM16 audio
+pub(crate) struct Utterance {
+    pub text: String,
+    pub audio: Vec<u8>,
+}
+
+/// Answers the prompt out loud, speaking each sentence as soon as it has been
+/// generated. Dropping the returned set stops both generation and synthesis.
+pub(crate) fn answer_aloud(
+    assistant: Storage,
+    voice: VoiceStorage,
+    prompt: String,
+    options: AnswerOptions,
+    mut filter: OutputFilter,
+    shutdown: &Shutdown,
+    tx: mpsc::Sender<error::Result<Utterance>>,
+) -> error::Result<JoinSet<()>> {
+    let active = shutdown.admit()?;
+    let shutdown = shutdown.clone();
+    let (sentence_tx, mut sentence_rx) = mpsc::channel::<error::Result<String>>(20);
+    let mut set = JoinSet::new();
+    set.spawn(async move {
+        let _active = active;
+        let cut_off = sentence_tx.clone();
+        let answer = async move {
+            let (_turn, assistant) = acquire(&assistant, &options).await;
+            let tokens = match assistant.answer_with(prompt, options).await {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    let _ = sentence_tx.send(Err(e)).await;
+                    return;
+                }
             };
-            println!("{p}");
-            let tokens = assistant.answer(p).await.unwrap();
             let mut toks = pin!(tokens);
+            let mut sentences = Sentences::new();
+            let mut stopped = false;
             while let Some(token) = toks.next().await {
-                tx.send(token.map_err(|e| Error::new(e.to_string())))
-                    .await
-                    .unwrap();
+                if sentence_tx.is_closed() {
+                    return;
+                }
+                let pushed = match token {
+                    Ok(token) => filter.push(&token).await,
+                    Err(e) => Err(e),
+                };
+                let sentences = match pushed {
+                    Ok(released) => {
+                        stopped = released.stop;
+                        sentences.push(&released.text).into_iter().map(Ok).collect()
+                    }
+                    Err(e) => vec![Err(e)],
+                };
+                for sentence in sentences {
+                    if sentence_tx.send(sentence).await.is_err() {
+                        return;
+                    }
+                }
+                if stopped {
+                    break;
+                }
             }
-        });
-        let s = stream! {
-            while let Some(token) = rx.recv().await {
-                yield token
+            if !stopped {
+                match filter.finish().await {
+                    Ok(released) => {
+                        for sentence in sentences.push(&released.text) {
+                            if sentence_tx.send(Ok(sentence)).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        let _ = sentence_tx.send(Err(e)).await;
+                        return;
+                    }
+                }
+            }
+            if let Some(rest) = sentences.finish() {
+                let _ = sentence_tx.send(Ok(rest)).await;
             }
         };
-        Ok(s)
+        tokio::select! {
+            _ = answer => {}
+            _ = shutdown.stopped() => {
+                let _ = cut_off.send(Err(Error::ShuttingDown)).await;
+            }
+        }
+    });
+    set.spawn(async move {
+        while let Some(sentence) = sentence_rx.recv().await {
+            let utterance = match sentence {
+                Ok(text) => synthesize(&voice, text.clone()).await.map(|it| Utterance {
+                    text,
+                    audio: pcm16(&it),
+                }),
+                Err(e) => Err(e),
+            };
+            if tx.send(utterance).await.is_err() {
+                return;
+            }
+        }
+    });
+    Ok(set)
+}
+
+/// Answers the prompt as a WAV stream.
+async fn speak(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Authenticated(caller): Authenticated,
+    Json(request): Json<AskRequest>,
+) -> error::Result<impl IntoResponse> {
+    let voice = state.models.voice().await?;
+    let (tx, mut rx) = mpsc::channel(20);
+    let persona = state.personas.get(request.persona.as_deref())?;
+    let sampling = request.sampling(persona.sampling)?;
+    // There's no final event in a WAV stream, so the decisions are only logged
+    let decisions = Decisions::default();
+    let (prompt, messages, summary) = moderate_input(
+        &state.moderator,
+        &request.prompt,
+        &request.messages,
+        request.summary,
+        &decisions,
+    )
+    .await?;
+    let (prompt, messages, summary, placeholders) =
+        mask_input(&state.masker, prompt, messages, summary).await?;
+    let p = persona.ask(&prompt, &messages, summary);
+    let options = AnswerOptions {
+        sampling,
+        request_id: Some(request_id),
+        meter: meter(caller.as_ref()).await?,
+        scheduler: Some(state.scheduler.clone()),
+        key: caller.as_ref().map(|it| it.name().into()),
+        ..Default::default()
+    };
+    let sample_rate = voice.lock().await.sample_rate();
+    let assistant = state.models.assistant().await?;
+    let filter = state.moderator.output(&decisions).unmasking(&placeholders);
+    let set = answer_aloud(assistant, voice, p, options, filter, &state.shutdown, tx)?;
+    tokio::spawn(set.join_all());
+    let s = stream! {
+        yield wav_header(sample_rate).map_err(Error::Inference);
+        while let Some(utterance) = rx.recv().await {
+            yield utterance.map(|it| it.audio)
+        }
+    };
+    Ok(([(CONTENT_TYPE, "audio/wav")], Body::from_stream(s)))
+}
+
+/// Runs a GraphQL query or mutation with the request's id in its context.
+async fn graphql(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Authenticated(caller): Authenticated,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    let mut request = request.into_inner().data(request_id);
+    if let Some(caller) = caller {
+        request = request.data(caller);
     }
+    state.schema.execute(request).await.into()
+}
+
+/// Serves GraphQL subscriptions over a WebSocket, which share the id of the
+/// request that opened it. Browsers can't set headers on WebSockets, so
+/// without one the key comes in the `connection_init` payload instead.
+async fn subscriptions(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+    protocol: GraphQLProtocol,
+    upgrade: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let caller = state.auth.from_headers(&headers).ok().flatten();
+    upgrade
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| {
+            let mut data = Data::default();
+            data.insert(request_id);
+            let auth = state.auth;
+            GraphQLWebSocket::new(stream, state.schema, protocol)
+                .with_data(data)
+                .on_connection_init(move |payload| async move {
+                    let mut data = Data::default();
+                    let caller = match caller {
+                        Some(caller) => Some(caller),
+                        None => auth.from_payload(&payload).extend()?,
+                    };
+                    if let Some(caller) = caller {
+                        data.insert(caller);
+                    }
+                    Ok(data)
+                })
+                .serve()
+        })
+}
+
+async fn prometheus() -> error::Result<impl IntoResponse> {
+    let text = metrics().render()?;
+    Ok(([(CONTENT_TYPE, "text/plain; version=0.0.4")], text))
 }
 
-type AssistantSchema = Schema<Query, EmptyMutation, Subscription>;
+/// Whether the server is up, whether or not its models have loaded.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Whether every model has loaded, so requests won't wait.
+async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let readiness = state.models.readiness();
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
 
 async fn graphiql() -> impl IntoResponse {
     Html(
@@ -199,20 +1199,269 @@ async fn graphiql() -> impl IntoResponse {
     )
 }
 
-pub async fn start() -> Result<()> {
-    let (model, config) = ModelFile::download()?.model()?;
-    let tokenizer = TokenizerFile::download()?.tokenizer()?;
-    let assistant = Assistant::new(model, tokenizer, config);
-    let storage = Arc::new(Mutex::new(assistant));
-    let schema = AssistantSchema::build(Query, EmptyMutation, Subscription)
-        .data(storage.clone())
+/// Loads a model, recording how long it took in the `model_load_seconds`
+/// metric.
+fn timed<T>(model: &str, load: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let loaded = load();
+    metrics().loaded(model, start.elapsed());
+    loaded
+}
+
+/// Loads the language model, and the draft model when decoding speculatively.
+pub fn load_assistant(settings: &Settings) -> error::Result<Assistant> {
+    let model = &settings.model;
+    utils::use_device(model.device);
+    let load = |repo: &str| {
+        ModelFile::download_from(repo)
+            .and_then(|it| it.model_as(model.dtype.dtype()))
+            .map_err(Error::ModelLoad)
+    };
+    let (llama, config) = timed("language", || load(&model.repo))?;
+    let tokenizer = TokenizerFile::download_from(&model.tokenizer_repo)
+        .and_then(|it| it.tokenizer())
+        .map_err(Error::ModelLoad)?;
+    let assistant = Assistant::new(llama, tokenizer, config)
+        .with_sampling(settings.sampling)
+        .with_max_tokens(settings.limits.max_tokens);
+    let assistant = match model.speculate {
+        Some(lookahead) => {
+            let (model, config) = timed("draft", || load(&model.draft_repo))?;
+            assistant.with_draft(Draft {
+                model,
+                config,
+                lookahead,
+            })
+        }
+        None => assistant,
+    };
+    Ok(assistant)
+}
+
+/// Starts loading every model that's turned on, in the background.
+fn load_models(settings: &Settings) -> Models {
+    let model = settings.model.clone();
+    let speculation = Arc::new(SpeculationStats::default());
+    let assistant = {
+        let settings = settings.clone();
+        let speculation = speculation.clone();
+        Loadable::spawn("assistant", move || {
+            load_assistant(&settings).map(|it| it.with_speculation(speculation))
+        })
+    };
+    let voice = settings.features.voice.then(|| {
+        let repo = model.voice_repo.clone();
+        Loadable::spawn("voice", move || {
+            timed("voice", || {
+                VoiceFile::download_from(&repo).and_then(|it| it.voice())
+            })
+            .map_err(Error::ModelLoad)
+        })
+    });
+    let transcriber = settings.features.transcription.then(|| {
+        let repo = model.whisper_repo.clone();
+        Loadable::spawn("transcription", move || {
+            timed("transcription", || {
+                WhisperFile::download_from(&repo).and_then(|it| it.transcriber())
+            })
+            .map_err(Error::ModelLoad)
+        })
+    });
+    let classifier = settings
+        .moderation
+        .classifier
+        .clone()
+        .map(|settings| Loadable::spawn("classifier", move || load_classifier(&settings)));
+    let ner = settings
+        .pii
+        .ner
+        .clone()
+        .map(|settings| Loadable::spawn("ner", move || load_recognizer(&settings)));
+    Models {
+        assistant,
+        voice,
+        transcriber,
+        classifier,
+        ner,
+        speculation,
+    }
+}
+
+fn load_classifier(settings: &ClassifierSettings) -> error::Result<Box<dyn Classifier>> {
+    let classifier = timed("classifier", || {
+        ClassifierFile::download_from(&settings.repo).and_then(|it| it.classifier())
+    })
+    .map_err(Error::ModelLoad)?;
+    Ok(Box::new(classifier))
+}
+
+/// The moderation rules, and the classifier when there is one.
+pub fn load_moderator(settings: &ModerationSettings) -> error::Result<Moderator> {
+    let classifier = settings
+        .classifier
+        .as_ref()
+        .map(load_classifier)
+        .transpose()?;
+    Moderator::new(settings, classifier)
+}
+
+fn load_recognizer(settings: &NerSettings) -> error::Result<Box<dyn Recognizer>> {
+    let tagger = timed("ner", || {
+        ClassifierFile::download_from(&settings.repo).and_then(|it| it.entity_tagger())
+    })
+    .map_err(Error::ModelLoad)?;
+    Ok(Box::new(tagger))
+}
+
+/// Finds personal information, with the NER model when there is one.
+pub fn load_masker(settings: &PiiSettings) -> error::Result<Masker> {
+    let recognizer = settings.ner.as_ref().map(load_recognizer).transpose()?;
+    Ok(Masker::new(settings, recognizer))
+}
+
+/// Listens straight away, loading the models in the background. `/healthz`
+/// answers from the start, `/readyz` once the models have loaded. On SIGINT
+/// or SIGTERM it stops listening and waits for answers to finish, cutting off
+/// any still going after the shutdown deadline.
+pub async fn start(settings: Settings) -> error::Result<()> {
+    let listener = TcpListener::bind(&settings.server.listen).await?;
+    #[cfg(feature = "grpc")]
+    let grpc_listener = match &settings.server.grpc_listen {
+        Some(listen) => Some(TcpListener::bind(listen).await?),
+        None => None,
+    };
+    let personas = Personas::load(&settings.personas)?;
+    if settings.personas.directory.is_some() {
+        personas.watch(Duration::from_secs(2));
+    }
+    let models = load_models(&settings);
+    let shutdown = Shutdown::default();
+    let usage = Usage::open(&settings.usage)?;
+    let scheduler = Scheduler::new(&settings.scheduler);
+    let moderator = Moderator::loading(&settings.moderation, models.classifier.clone())?;
+    let masker = Masker::loading(&settings.pii, models.ner.clone());
+    let schema = AssistantSchema::build(Query, Mutation, Subscription)
+        .data(models.clone())
+        .data(personas.clone())
+        .data(shutdown.clone())
+        .data(usage.clone())
+        .data(moderator.clone())
+        .data(masker.clone())
+        .data(scheduler.clone())
         .finish();
-    let app = Router::new()
-        .route(
-            "/",
-            get(graphiql).post_service(GraphQL::new(schema.clone())),
-        )
-        .route_service("/ws", GraphQLSubscription::new(schema));
-    serve(TcpListener::bind("0.0.0.0:8000").await?, app).await?;
+    let root = if settings.features.graphiql {
+        get(graphiql).post(graphql)
+    } else {
+        post(graphql)
+    };
+    let mut app = Router::new()
+        .route("/", root)
+        .route("/ws", get(subscriptions))
+        .route("/speak", post(speak))
+        .route("/api/ask", get(sse::ask_query).post(sse::ask))
+        .route("/transcribe", post(transcribe))
+        .route("/batches", post(batch::upload))
+        .route("/batches/:id", get(batch::status))
+        .route("/batches/:id/results", get(batch::results))
+        .route("/voice", get(voice_chat))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz));
+    if settings.features.metrics {
+        app = app.route("/metrics", get(prometheus));
+    }
+    let auth = Auth::new(&settings.auth, &usage);
+    let batches = match &settings.batch.directory {
+        Some(directory) => {
+            let batch = Batch::new(
+                models.assistant.clone(),
+                personas.clone(),
+                moderator.clone(),
+                masker.clone(),
+                scheduler.clone(),
+                shutdown.clone(),
+            );
+            let batches = Batches::new(directory, batch, auth.clone())?;
+            batches.resume()?;
+            Some(batches)
+        }
+        None => None,
+    };
+    #[cfg(feature = "grpc")]
+    if let Some(listener) = grpc_listener {
+        let service = crate::grpc::AssistantService::new(
+            models.assistant.clone(),
+            personas.clone(),
+            moderator.clone(),
+            masker.clone(),
+            scheduler.clone(),
+            shutdown.clone(),
+            auth.clone(),
+        );
+        info!("serving gRPC on {}", listener.local_addr()?);
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = crate::grpc::serve(service, listener, shutdown).await {
+                warn!("gRPC stopped: {e}");
+            }
+        });
+    }
+    let app = app
+        .layer(DefaultBodyLimit::max(settings.limits.max_upload_bytes))
+        .layer(middleware::from_fn(telemetry::request_id))
+        .with_state(AppState {
+            schema,
+            models,
+            personas,
+            shutdown: shutdown.clone(),
+            auth,
+            moderator,
+            masker,
+            scheduler,
+            batches,
+        });
+    info!(
+        avx = candle_core::utils::with_avx(),
+        neon = candle_core::utils::with_neon(),
+        simd128 = candle_core::utils::with_simd128(),
+        f16c = candle_core::utils::with_f16c(),
+        "listening on {}",
+        settings.server.listen
+    );
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            signalled().await;
+            info!(active = shutdown.active(), "shutting down");
+            shutdown.begin();
+        }
+    });
+    let mut server = tokio::spawn({
+        let shutdown = shutdown.clone();
+        serve(listener, app)
+            .with_graceful_shutdown(async move { shutdown.begun().await })
+            .into_future()
+    });
+    tokio::select! {
+        served = &mut server => served.map_err(std::io::Error::other)??,
+        _ = shutdown.begun() => {}
+    }
+    let deadline = Duration::from_secs(settings.server.shutdown_deadline_seconds);
+    if tokio::time::timeout(deadline, shutdown.drained())
+        .await
+        .is_err()
+    {
+        warn!(
+            active = shutdown.active(),
+            "cutting off answers still going after {deadline:?}"
+        );
+        shutdown.stop();
+        // Long enough to tell them why
+        let _ = tokio::time::timeout(Duration::from_secs(1), shutdown.drained()).await;
+    }
+    // Nothing scrapes them after this, so they're logged for the record
+    if let Ok(metrics) = metrics().render() {
+        debug!(%metrics, "final metrics");
+    }
+    info!("stopped");
     Ok(())
 }
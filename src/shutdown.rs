@@ -0,0 +1,124 @@
+//! Stopping the server without cutting answers off mid-sentence. Once a
+//! shutdown begins no new answers are started, those already going get until
+//! the deadline to finish, and any still going then end with a
+//! `SHUTTING_DOWN` error.
+use std::sync::Arc;
+
+use tokio::{signal, sync::watch};
+
+use crate::error::{Error, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Running,
+    Draining,
+    Stopped,
+}
+
+#[derive(Clone)]
+pub struct Shutdown {
+    phase: Arc<watch::Sender<Phase>>,
+    active: Arc<watch::Sender<usize>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            phase: Arc::new(watch::channel(Phase::Running).0),
+            active: Arc::new(watch::channel(0).0),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Stops starting answers, letting those already going finish.
+    pub fn begin(&self) {
+        self.phase.send_if_modified(|phase| {
+            let running = *phase == Phase::Running;
+            if running {
+                *phase = Phase::Draining;
+            }
+            running
+        });
+    }
+
+    /// Cuts off the answers still going.
+    pub fn stop(&self) {
+        self.phase.send_replace(Phase::Stopped);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.phase.borrow() != Phase::Running
+    }
+
+    /// The number of answers going.
+    pub fn active(&self) -> usize {
+        *self.active.borrow()
+    }
+
+    /// Counts an answer as going until the returned guard is dropped, unless
+    /// the server is shutting down.
+    pub fn admit(&self) -> Result<Active> {
+        self.active.send_modify(|it| *it += 1);
+        let active = Active(self.active.clone());
+        // Checked after counting so `drained` can't miss an answer
+        if self.is_draining() {
+            return Err(Error::ShuttingDown);
+        }
+        Ok(active)
+    }
+
+    /// Waits for a shutdown to begin.
+    pub async fn begun(&self) {
+        let _ = self
+            .phase
+            .subscribe()
+            .wait_for(|it| *it != Phase::Running)
+            .await;
+    }
+
+    /// Waits for answers to be cut off.
+    pub async fn stopped(&self) {
+        let _ = self
+            .phase
+            .subscribe()
+            .wait_for(|it| *it == Phase::Stopped)
+            .await;
+    }
+
+    /// Waits for every answer to finish.
+    pub async fn drained(&self) {
+        let _ = self.active.subscribe().wait_for(|it| *it == 0).await;
+    }
+}
+
+/// An answer that's going, which the shutdown waits for.
+pub struct Active(Arc<watch::Sender<usize>>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.send_modify(|it| *it -= 1);
+    }
+}
+
+/// Waits for SIGINT, or SIGTERM on Unix.
+pub async fn signalled() {
+    let interrupt = async {
+        let _ = signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut it) => {
+                it.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
@@ -0,0 +1,290 @@
+//! Speculative decoding, see "Fast Inference from Transformers via Speculative
+//! Decoding" https://arxiv.org/abs/2211.17192
+//!
+//! A cheap proposer guesses the next few tokens and the model checks all of
+//! them in a single forward pass. Proposals are accepted with probability
+//! `min(1, p / q)`, where `p` and `q` are the model's and the proposer's
+//! probabilities for the token, and the first rejection is resampled from
+//! `max(0, p - q)`. This keeps the output distribution identical to sampling
+//! from the model alone.
+//!
+//! Proposals come either from a small draft model or, for answers that copy
+//! from their prompt like summaries, from looking the latest tokens up in the
+//! prompt, see https://github.com/apoorvumang/prompt-lookup-decoding
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+use anyhow::Result;
+use candle_core::{Device, IndexOp, Tensor};
+use candle_transformers::models::llama::Config;
+use candle_transformers::utils::apply_repeat_penalty;
+use rand::{distributions::WeightedIndex, prelude::Distribution, rngs::StdRng, Rng, SeedableRng};
+
+use crate::llama::{Cache, Llama};
+use crate::model::{KvCache, Model};
+
+const REPEAT_PENALTY: f32 = 1.1;
+const REPEAT_LAST_N: usize = 64;
+
+/// A probability for every token in the vocabulary, at each position
+pub type Probabilities = Vec<Vec<f32>>;
+
+/// A small model sharing the main model's tokenizer, used to propose tokens.
+#[derive(Clone)]
+pub struct Draft {
+    pub model: Llama,
+    pub config: Config,
+    /// How many tokens to propose at a time
+    pub lookahead: usize,
+}
+
+/// Proposes the tokens that follow the latest n-gram where it first appears
+/// in the prompt.
+#[derive(Clone, Copy, Debug)]
+pub struct PromptLookup {
+    /// The longest n-gram to match, shorter ones are tried when it misses
+    pub ngram: usize,
+    /// How many tokens to propose at a time
+    pub lookahead: usize,
+}
+
+impl Default for PromptLookup {
+    fn default() -> Self {
+        Self {
+            ngram: 3,
+            lookahead: 10,
+        }
+    }
+}
+
+impl PromptLookup {
+    /// Finds the first place in the prompt, the first `prompt_len` tokens,
+    /// where the last n tokens appear and proposes what came after them.
+    pub fn propose(&self, tokens: &[u32], prompt_len: usize) -> Vec<u32> {
+        let prompt = &tokens[..prompt_len.min(tokens.len())];
+        for n in (1..=self.ngram.min(tokens.len())).rev() {
+            let pattern = &tokens[tokens.len() - n..];
+            let found = (n..prompt.len()).find(|&end| &prompt[end - n..end] == pattern);
+            if let Some(end) = found {
+                let stop = (end + self.lookahead).min(prompt.len());
+                return prompt[end..stop].to_vec();
+            }
+        }
+        vec![]
+    }
+}
+
+/// Where proposals come from during a single answer.
+pub enum Proposer<'a> {
+    Draft(&'a Draft, Cache),
+    Lookup(PromptLookup, usize),
+}
+
+impl Proposer<'_> {
+    /// Proposes tokens to follow `tokens`, along with the proposer's
+    /// probabilities for them when they were sampled.
+    pub fn propose(
+        &mut self,
+        sampler: &mut Sampler,
+        tokens: &[u32],
+        device: &Device,
+    ) -> Result<(Vec<u32>, Option<Probabilities>)> {
+        match self {
+            Proposer::Draft(draft, cache) => {
+                let (proposals, probs) = draft.propose(cache, sampler, tokens, device)?;
+                Ok((proposals, Some(probs)))
+            }
+            Proposer::Lookup(lookup, prompt_len) => Ok((lookup.propose(tokens, *prompt_len), None)),
+        }
+    }
+
+    /// Forgets proposals that were rejected.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if let Proposer::Draft(_, cache) = self {
+            cache.truncate(len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts how many proposed tokens the model went on to accept.
+#[derive(Default)]
+pub struct SpeculationStats {
+    proposed: AtomicU64,
+    accepted: AtomicU64,
+}
+
+impl SpeculationStats {
+    pub fn record(&self, proposed: usize, accepted: usize) {
+        self.proposed.fetch_add(proposed as u64, Relaxed);
+        self.accepted.fetch_add(accepted as u64, Relaxed);
+    }
+
+    pub fn proposed(&self) -> u64 {
+        self.proposed.load(Relaxed)
+    }
+
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Relaxed)
+    }
+
+    pub fn acceptance_rate(&self) -> f64 {
+        let proposed = self.proposed();
+        if proposed == 0 {
+            0.0
+        } else {
+            self.accepted() as f64 / proposed as f64
+        }
+    }
+}
+
+/// Top-p sampling with a repeat penalty that exposes the distribution it
+/// samples from, which the acceptance rule needs.
+pub struct Sampler {
+    rng: StdRng,
+    temperature: f64,
+    top_p: f64,
+}
+
+impl Sampler {
+    pub fn new(seed: u64, temperature: f64, top_p: f64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            temperature,
+            top_p,
+        }
+    }
+
+    /// The probability of each token given the logits for a position and the
+    /// tokens before it.
+    pub fn probabilities(&self, logits: &Tensor, context: &[u32]) -> Result<Vec<f32>> {
+        let start_at = context.len().saturating_sub(REPEAT_LAST_N);
+        let logits = apply_repeat_penalty(logits, REPEAT_PENALTY, &context[start_at..])?;
+        let logits = logits.to_vec1::<f32>()?;
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let mut probs: Vec<f32> = logits
+            .iter()
+            .map(|l| ((l - max) as f64 / self.temperature).exp() as f32)
+            .collect();
+        normalize(&mut probs);
+        let mut order: Vec<usize> = (0..probs.len()).collect();
+        order.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+        let mut cumulative = 0.0;
+        for &i in order.iter() {
+            if cumulative >= self.top_p {
+                probs[i] = 0.0;
+            } else {
+                cumulative += probs[i] as f64;
+            }
+        }
+        normalize(&mut probs);
+        Ok(probs)
+    }
+
+    pub fn sample(&mut self, probs: &[f32]) -> Result<u32> {
+        let distribution = WeightedIndex::new(probs)?;
+        Ok(distribution.sample(&mut self.rng) as u32)
+    }
+
+    /// Checks proposed tokens against the model's probabilities at each
+    /// position, `target` holding one more row than there are proposals.
+    /// `draft` holds the proposer's probabilities, or `None` when the
+    /// proposals were certain. Returns how many proposals were accepted and
+    /// the token to follow them.
+    pub fn verify(
+        &mut self,
+        proposals: &[u32],
+        target: &[Vec<f32>],
+        draft: Option<&[Vec<f32>]>,
+    ) -> Result<(usize, u32)> {
+        for (i, &token) in proposals.iter().enumerate() {
+            let p = target[i][token as usize];
+            let q = draft.map_or(1.0, |d| d[i][token as usize]);
+            if q > 0.0 && self.rng.gen::<f32>() < (p / q).min(1.0) {
+                continue;
+            }
+            let mut residual: Vec<f32> = match draft {
+                Some(d) => target[i]
+                    .iter()
+                    .zip(d[i].iter())
+                    .map(|(p, q)| (p - q).max(0.0))
+                    .collect(),
+                None => {
+                    let mut residual = target[i].clone();
+                    residual[token as usize] = 0.0;
+                    residual
+                }
+            };
+            if residual.iter().sum::<f32>() <= 0.0 {
+                residual = target[i].clone();
+            }
+            return Ok((i, self.sample(&residual)?));
+        }
+        let next = self.sample(&target[proposals.len()])?;
+        Ok((proposals.len(), next))
+    }
+}
+
+fn normalize(probs: &mut [f32]) {
+    let sum: f32 = probs.iter().sum();
+    if sum > 0.0 {
+        probs.iter_mut().for_each(|p| *p /= sum);
+    }
+}
+
+/// Runs the model over the tokens it hasn't seen yet followed by the
+/// proposals, returning its probabilities for each proposal and the position
+/// after them.
+pub fn score<M: Model>(
+    model: &M,
+    cache: &mut M::Cache,
+    sampler: &Sampler,
+    tokens: &[u32],
+    proposals: &[u32],
+    device: &Device,
+) -> Result<Probabilities> {
+    let mut input = tokens[cache.len()..].to_vec();
+    input.extend_from_slice(proposals);
+    let input = Tensor::new(input.as_slice(), device)?.unsqueeze(0)?;
+    let logits = model
+        .forward_last(&input, proposals.len() + 1, cache)?
+        .squeeze(0)?;
+    let mut context = tokens.to_vec();
+    let mut probs = Vec::with_capacity(proposals.len() + 1);
+    for i in 0..=proposals.len() {
+        let row = logits.i(i)?;
+        probs.push(sampler.probabilities(&row, &context)?);
+        if let Some(&token) = proposals.get(i) {
+            context.push(token);
+        }
+    }
+    Ok(probs)
+}
+
+impl Draft {
+    /// Proposes `lookahead` tokens, returning them with the draft model's
+    /// probabilities for each.
+    pub fn propose(
+        &self,
+        cache: &mut Cache,
+        sampler: &mut Sampler,
+        tokens: &[u32],
+        device: &Device,
+    ) -> Result<(Vec<u32>, Probabilities)> {
+        let mut context = tokens.to_vec();
+        let mut proposals = Vec::with_capacity(self.lookahead);
+        let mut probs = Vec::with_capacity(self.lookahead);
+        for _ in 0..self.lookahead {
+            let input = &context[cache.len()..];
+            let logits = self
+                .model
+                .forward(&Tensor::new(input, device)?.unsqueeze(0)?, cache)?
+                .squeeze(0)?;
+            let p = sampler.probabilities(&logits, &context)?;
+            let token = sampler.sample(&p)?;
+            proposals.push(token);
+            probs.push(p);
+            context.push(token);
+        }
+        Ok((proposals, probs))
+    }
+}
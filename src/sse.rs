@@ -0,0 +1,119 @@
+//! Answers streamed as Server-Sent Events, for clients without graphql-ws.
+//!
+//! `POST /api/ask` takes the same JSON as /speak, and `GET /api/ask` takes
+//! it in the query string, less the messages and options, for `EventSource`,
+//! along with the API key as `api_key`. Each part of
+//! the answer is a `token` event, and it ends with a `done` event carrying
+//! the tokens it used and what moderation did, or an `error` event.
+use std::sync::Arc;
+
+use async_graphql::Value;
+use async_stream::stream;
+use axum::{
+    extract::{Query, State},
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Sse,
+    },
+    Extension, Json,
+};
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+
+use crate::{
+    assistant::Tally,
+    auth::{Authenticated, Caller, QueryAuthenticated},
+    error,
+    moderation::Decision,
+    server::{meter, prepare_ask, AnswerEvent, AnswerUsage, AppState, AskRequest},
+    telemetry::RequestId,
+};
+
+#[derive(Serialize)]
+struct Token {
+    text: String,
+}
+
+#[derive(Serialize)]
+struct Done {
+    usage: AnswerUsage,
+    moderation: Vec<Decision>,
+    stopped: bool,
+}
+
+#[derive(Serialize)]
+struct Failed {
+    code: Option<String>,
+    message: String,
+}
+
+pub(crate) async fn ask(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Authenticated(caller): Authenticated,
+    Json(request): Json<AskRequest>,
+) -> error::Result<impl IntoResponse> {
+    answer(state, request_id, caller, request).await
+}
+
+/// `EventSource` can only GET.
+pub(crate) async fn ask_query(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    QueryAuthenticated(caller): QueryAuthenticated,
+    Query(request): Query<AskRequest>,
+) -> error::Result<impl IntoResponse> {
+    answer(state, request_id, caller, request).await
+}
+
+/// Refusals and other errors before the answer starts are plain HTTP errors,
+/// those after it has are `error` events.
+async fn answer(
+    state: AppState,
+    request_id: RequestId,
+    caller: Option<Caller>,
+    request: AskRequest,
+) -> error::Result<impl IntoResponse> {
+    let prepared = prepare_ask((&state).into(), request, Some(request_id)).await?;
+    let storage = state.models.assistant().await?;
+    let tally = Arc::new(Tally::new(meter(caller.as_ref()).await?));
+    let events = prepared
+        .by(caller.as_ref())
+        .answer(storage, &state.shutdown, Some(tally.clone()));
+    Ok(Sse::new(to_events(events, tally)).keep_alive(KeepAlive::default()))
+}
+
+/// Turns an answer's events into Server-Sent Events, with the tokens `tally`
+/// counted in the `done` event.
+pub fn to_events(
+    events: impl Stream<Item = async_graphql::Result<AnswerEvent>>,
+    tally: Arc<Tally>,
+) -> impl Stream<Item = Result<Event, axum::Error>> {
+    stream! {
+        let mut events = std::pin::pin!(events);
+        while let Some(event) = events.next().await {
+            yield match event {
+                Ok(AnswerEvent::Token(token)) => {
+                    Event::default().event("token").json_data(Token { text: token.text })
+                }
+                Ok(AnswerEvent::Finished(finished)) => Event::default().event("done").json_data(Done {
+                    usage: tally.as_ref().into(),
+                    moderation: finished.moderation,
+                    stopped: finished.stopped,
+                }),
+                Err(e) => {
+                    let code = match e.extensions.as_ref().and_then(|it| it.get("code")) {
+                        Some(Value::String(code)) => Some(code.clone()),
+                        _ => None,
+                    };
+                    let failed = Failed {
+                        code,
+                        message: e.message,
+                    };
+                    yield Event::default().event("error").json_data(failed);
+                    return;
+                }
+            }
+        }
+    }
+}
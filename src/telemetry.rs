@@ -0,0 +1,154 @@
+//! Logs and traces. Every HTTP request and WebSocket connection gets a request
+//! id, from its `x-request-id` header or made up, which follows its answers
+//! through the queue, prefill and decode spans.
+use std::{
+    fmt::{self, Display, Formatter},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info_span, Instrument};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+
+use crate::{
+    error::{Error, Result},
+    pii::scrub,
+};
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+static LOG_PROMPTS: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    /// What to log, in `RUST_LOG` syntax, like `info` or
+    /// `warn,persephone=debug`
+    pub level: String,
+    pub format: LogFormat,
+    /// Log prompts, with emails, phone and card numbers and IP addresses
+    /// masked, rather than redacted
+    pub prompts: bool,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            level: "info".into(),
+            format: LogFormat::Text,
+            prompts: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Lines for people
+    Text,
+    /// A JSON object per line, for log collectors
+    Json,
+}
+
+impl LogSettings {
+    pub fn filter(&self) -> Result<EnvFilter> {
+        EnvFilter::try_new(&self.level)
+            .map_err(|e| Error::Config(format!("can't log at {}: {e}", self.level)))
+    }
+}
+
+/// Logs to stderr, with a line when each span closes saying how long it took.
+/// Only the first call has any effect.
+pub fn init(settings: &LogSettings) -> Result<()> {
+    LOG_PROMPTS.store(settings.prompts, Ordering::Relaxed);
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(settings.filter()?)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr);
+    let _ = match settings.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    Ok(())
+}
+
+/// Shows `text` in logs only when prompts are logged, and then with its PII
+/// scrubbed.
+pub fn redact(text: &str) -> Redacted<'_> {
+    Redacted(text)
+}
+
+pub struct Redacted<'a>(&'a str);
+
+impl Display for Redacted<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if LOG_PROMPTS.load(Ordering::Relaxed) {
+            write!(f, "{}", scrub(self.0))
+        } else {
+            write!(f, "<{} characters redacted>", self.0.chars().count())
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(Arc<str>);
+
+impl RequestId {
+    pub fn new() -> Self {
+        Self(format!("{:032x}", rand::random::<u128>()).into())
+    }
+
+    /// Takes a caller's id, as long as it's short printable ASCII.
+    pub fn parse(id: &str) -> Option<Self> {
+        let valid = !id.is_empty() && id.len() <= 128 && id.bytes().all(|it| it.is_ascii_graphic());
+        valid.then(|| Self(id.into()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Gives the request an id, available to handlers as an extension, runs it in
+/// a span with that id and echoes it in the response.
+pub async fn request_id(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|it| it.to_str().ok())
+        .and_then(RequestId::parse)
+        .unwrap_or_default();
+    request.extensions_mut().insert(id.clone());
+    let span = info_span!(
+        "request",
+        request_id = %id,
+        method = %request.method(),
+        path = request.uri().path(),
+    );
+    let mut response = next.run(request).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(id.as_str()) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
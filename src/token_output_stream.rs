@@ -1,6 +1,6 @@
 use anyhow::anyhow;
 use anyhow::Result;
-/// from https://raw.githubusercontent.com/huggingface/candle/main/candle-examples/src/token_output_stream.rs
+// from https://raw.githubusercontent.com/huggingface/candle/main/candle-examples/src/token_output_stream.rs
 
 /// This is a wrapper around a tokenizer to ensure that tokens can be returned to the user in a
 /// streaming way rather than having to wait for the full decoding.
@@ -0,0 +1,265 @@
+//! Speech to text with Whisper, adapted from
+//! https://github.com/huggingface/candle/blob/main/candle-examples/examples/whisper/main.rs
+use std::io::Cursor;
+
+use anyhow::{anyhow, Result};
+use candle_core::{IndexOp, Tensor};
+use candle_transformers::models::whisper::{
+    self as m, audio, model::Whisper, EOT_TOKEN, NO_TIMESTAMPS_TOKEN, N_FFT, N_FRAMES, SAMPLE_RATE,
+    SOT_TOKEN, TRANSCRIBE_TOKEN,
+};
+use hound::{SampleFormat, WavReader};
+use tokenizers::Tokenizer;
+
+use crate::utils::device;
+
+pub struct Transcriber {
+    model: Whisper,
+    tokenizer: Tokenizer,
+    filters: Vec<f32>,
+}
+
+impl Transcriber {
+    pub fn new(model: Whisper, tokenizer: Tokenizer) -> Self {
+        let filters = mel_filters(model.config.num_mel_bins);
+        Self {
+            model,
+            tokenizer,
+            filters,
+        }
+    }
+
+    fn token(&self, token: &str) -> Result<u32> {
+        self.tokenizer
+            .token_to_id(token)
+            .ok_or_else(|| anyhow!("no token {token}"))
+    }
+
+    /// Transcribes a WAV file of any sample rate and channel count.
+    pub fn transcribe(&mut self, wav: &[u8]) -> Result<String> {
+        self.transcribe_pcm(&read_wav(wav)?)
+    }
+
+    /// Transcribes mono samples at 16kHz.
+    pub fn transcribe_pcm(&mut self, pcm: &[f32]) -> Result<String> {
+        let config = &self.model.config;
+        let mel = audio::pcm_to_mel(config, pcm, &self.filters);
+        let bins = config.num_mel_bins;
+        let len = mel.len() / bins;
+        let mel = Tensor::from_vec(mel, (1, bins, len), &device()?)?;
+        // The mel spectrogram is padded, so only look at the frames holding audio
+        let frames = pcm.len() / m::HOP_LENGTH;
+        let mut text = String::new();
+        let mut seek = 0;
+        while seek < frames {
+            let size = usize::min(frames - seek, N_FRAMES);
+            let segment = mel.narrow(2, seek, size)?;
+            let words = self.decode(&segment)?;
+            text.push_str(words.trim());
+            text.push(' ');
+            seek += size;
+        }
+        Ok(text.trim().to_string())
+    }
+
+    fn decode(&mut self, mel: &Tensor) -> Result<String> {
+        let sot = self.token(SOT_TOKEN)?;
+        let transcribe = self.token(TRANSCRIBE_TOKEN)?;
+        let no_timestamps = self.token(NO_TIMESTAMPS_TOKEN)?;
+        let eot = self.token(EOT_TOKEN)?;
+        let config = &self.model.config;
+        let suppress: Vec<f32> = (0..config.vocab_size as u32)
+            .map(|i| {
+                if config.suppress_tokens.contains(&i) || i == no_timestamps {
+                    f32::NEG_INFINITY
+                } else {
+                    0f32
+                }
+            })
+            .collect();
+        let suppress = Tensor::new(suppress, mel.device())?;
+        let max = config.max_target_positions;
+        let features = self.model.encoder.forward(mel, true)?;
+        let mut tokens = vec![sot, transcribe, no_timestamps];
+        for i in 0..max / 2 {
+            let input = Tensor::new(tokens.as_slice(), mel.device())?.unsqueeze(0)?;
+            let ys = self.model.decoder.forward(&input, &features, i == 0)?;
+            let (_, seq_len, _) = ys.dims3()?;
+            let logits = self
+                .model
+                .decoder
+                .final_linear(&ys.i((..1, seq_len - 1..))?)?
+                .i(0)?
+                .i(0)?
+                .broadcast_add(&suppress)?;
+            let next_token = logits.argmax(0)?.to_scalar::<u32>()?;
+            if next_token == eot || tokens.len() > max {
+                break;
+            }
+            tokens.push(next_token);
+        }
+        self.tokenizer
+            .decode(&tokens, true)
+            .map_err(anyhow::Error::msg)
+    }
+}
+
+/// Reads a WAV file as mono samples at Whisper's 16kHz sample rate.
+pub fn read_wav(wav: &[u8]) -> Result<Vec<f32>> {
+    let mut reader = WavReader::new(Cursor::new(wav))?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+        SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+    let channels = spec.channels as usize;
+    let mono: Vec<f32> = samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    Ok(resample(&mono, spec.sample_rate as usize, SAMPLE_RATE))
+}
+
+/// Reads little endian signed 16 bit PCM.
+pub fn read_pcm16(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(2)
+        .map(|s| i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0)
+        .collect()
+}
+
+/// Linearly interpolates samples from one sample rate to another. There are
+/// no samples at a rate of zero.
+pub fn resample(pcm: &[f32], from: usize, to: usize) -> Vec<f32> {
+    if from == to || pcm.is_empty() {
+        return pcm.to_vec();
+    }
+    if from == 0 || to == 0 {
+        return vec![];
+    }
+    let len = pcm.len() * to / from;
+    let ratio = from as f64 / to as f64;
+    (0..len)
+        .map(|i| {
+            let position = i as f64 * ratio;
+            let index = position as usize;
+            let fraction = (position - index as f64) as f32;
+            let a = pcm[index];
+            let b = pcm.get(index + 1).copied().unwrap_or(a);
+            a + (b - a) * fraction
+        })
+        .collect()
+}
+
+/// Reads PCM16 sent in frames, resampling it as it comes. A sample split
+/// between frames, and where resampling got to, carry over to the next frame,
+/// so the samples are the same however the audio was split.
+pub struct Pcm16Stream {
+    from: usize,
+    to: usize,
+    /// The first byte of a sample whose second is in the next frame
+    odd: Option<u8>,
+    /// Samples still to interpolate between, the first being sample `start`
+    samples: Vec<f32>,
+    start: usize,
+    produced: usize,
+}
+
+impl Pcm16Stream {
+    pub fn new(from: usize, to: usize) -> Self {
+        Self {
+            from,
+            to,
+            odd: None,
+            samples: vec![],
+            start: 0,
+            produced: 0,
+        }
+    }
+
+    /// Reads a frame, returning the samples it completes at the new rate.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<f32> {
+        let mut frame: Vec<u8> = self.odd.take().into_iter().collect();
+        frame.extend_from_slice(bytes);
+        if frame.len() % 2 == 1 {
+            self.odd = frame.pop();
+        }
+        let pcm = read_pcm16(&frame);
+        if self.from == self.to {
+            return pcm;
+        }
+        if self.from == 0 || self.to == 0 {
+            return vec![];
+        }
+        self.samples.extend(pcm);
+        let ratio = self.from as f64 / self.to as f64;
+        let mut out = vec![];
+        loop {
+            let position = self.produced as f64 * ratio;
+            let index = position as usize - self.start;
+            // Waits for the next frame rather than guess the sample after
+            let (Some(&a), Some(&b)) = (self.samples.get(index), self.samples.get(index + 1))
+            else {
+                break;
+            };
+            let fraction = (position - (index + self.start) as f64) as f32;
+            out.push(a + (b - a) * fraction);
+            self.produced += 1;
+        }
+        let next = (self.produced as f64 * ratio) as usize;
+        let done = (next - self.start).min(self.samples.len());
+        self.samples.drain(..done);
+        self.start += done;
+        out
+    }
+}
+
+fn hz_to_mel(hz: f64) -> f64 {
+    // Slaney's mel scale, linear below 1kHz and logarithmic above
+    let sp = 200.0 / 3.0;
+    if hz < 1000.0 {
+        hz / sp
+    } else {
+        1000.0 / sp + (hz / 1000.0).ln() / (6.4f64.ln() / 27.0)
+    }
+}
+
+fn mel_to_hz(mel: f64) -> f64 {
+    let sp = 200.0 / 3.0;
+    let min = 1000.0 / sp;
+    if mel < min {
+        mel * sp
+    } else {
+        1000.0 * ((mel - min) * (6.4f64.ln() / 27.0)).exp()
+    }
+}
+
+/// The mel filterbank Whisper was trained with, matching
+/// `librosa.filters.mel(sr=16000, n_fft=400, n_mels=bins)`, laid out as
+/// `bins` rows of `N_FFT / 2 + 1` frequency weights.
+pub fn mel_filters(bins: usize) -> Vec<f32> {
+    let freqs = N_FFT / 2 + 1;
+    let nyquist = SAMPLE_RATE as f64 / 2.0;
+    let (low, high) = (hz_to_mel(0.0), hz_to_mel(nyquist));
+    let points: Vec<f64> = (0..bins + 2)
+        .map(|i| mel_to_hz(low + (high - low) * i as f64 / (bins + 1) as f64))
+        .collect();
+    let mut filters = vec![0f32; bins * freqs];
+    for bin in 0..bins {
+        let (left, center, right) = (points[bin], points[bin + 1], points[bin + 2]);
+        let norm = 2.0 / (right - left);
+        for freq in 0..freqs {
+            let hz = freq as f64 * SAMPLE_RATE as f64 / N_FFT as f64;
+            let lower = (hz - left) / (center - left);
+            let upper = (right - hz) / (right - center);
+            filters[bin * freqs + freq] = (lower.min(upper).max(0.0) * norm) as f32;
+        }
+    }
+    filters
+}
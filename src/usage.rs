@@ -0,0 +1,222 @@
+//! Token usage by API key and day, kept in an embedded redb database so it can
+//! be charged back to whoever used it and survives restarts.
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use redb::{backends::InMemoryBackend, Database, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// (key, day) to (requests, prompt tokens, completion tokens)
+const USAGE: TableDefinition<(&str, u32), (u64, u64, u64)> = TableDefinition::new("usage");
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsageSettings {
+    /// The database file, otherwise usage is kept in memory and lost on
+    /// restart
+    pub path: Option<PathBuf>,
+}
+
+/// A UTC calendar day.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Day(u32);
+
+impl Day {
+    pub fn today() -> Self {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |it| it.as_secs());
+        Self((seconds / 86_400) as u32)
+    }
+
+    /// The day from its year, month and day of the month, if there is one
+    /// from 1970 to 9999.
+    pub fn from_ymd(year: i64, month: u32, day: u32) -> Option<Self> {
+        if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day)
+        {
+            return None;
+        }
+        // Howard Hinnant's days_from_civil
+        let y = if month <= 2 { year - 1 } else { year };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let mp = (i64::from(month) + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = u32::try_from(era * 146_097 + doe - 719_468).ok()?;
+        // Rolled over into the next month, like the 31st of April
+        Some(Self(days)).filter(|it| it.ymd() == (year, month, day))
+    }
+
+    /// Parses a `YYYY-MM-DD` date.
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = || Error::InvalidRequest(format!("{text} isn't a YYYY-MM-DD date"));
+        let mut parts = text.splitn(3, '-');
+        let mut next = || parts.next().ok_or_else(invalid);
+        let (year, month, day) = (next()?, next()?, next()?);
+        let year = year.parse().map_err(|_| invalid())?;
+        let month = month.parse().map_err(|_| invalid())?;
+        let day = day.parse().map_err(|_| invalid())?;
+        Self::from_ymd(year, month, day).ok_or_else(invalid)
+    }
+
+    /// The year, month and day of the month.
+    pub fn ymd(self) -> (i64, u32, u32) {
+        // Howard Hinnant's civil_from_days
+        let z = i64::from(self.0) + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        (year, month, day)
+    }
+
+    pub fn first_of_month(self) -> Self {
+        let (_, _, day) = self.ymd();
+        Self(self.0 - (day - 1))
+    }
+}
+
+impl fmt::Display for Day {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = self.ymd();
+        write!(f, "{year:04}-{month:02}-{day:02}")
+    }
+}
+
+/// What answers used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Tokens {
+    pub requests: u64,
+    pub prompt: u64,
+    pub completion: u64,
+}
+
+impl Tokens {
+    pub fn total(&self) -> u64 {
+        self.prompt + self.completion
+    }
+
+    fn add(&mut self, other: Tokens) {
+        self.requests += other.requests;
+        self.prompt += other.prompt;
+        self.completion += other.completion;
+    }
+}
+
+/// What a key used on a day.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DailyUsage {
+    pub key: String,
+    pub day: Day,
+    pub tokens: Tokens,
+}
+
+/// The running totals, by key and day.
+#[derive(Clone)]
+pub struct Usage {
+    db: Arc<Database>,
+}
+
+impl Usage {
+    pub fn open(settings: &UsageSettings) -> Result<Self> {
+        match &settings.path {
+            Some(path) => Self::new(Database::create(path).map_err(store)?),
+            None => Self::in_memory(),
+        }
+    }
+
+    pub fn in_memory() -> Result<Self> {
+        let db = Database::builder()
+            .create_with_backend(InMemoryBackend::new())
+            .map_err(store)?;
+        Self::new(db)
+    }
+
+    fn new(db: Database) -> Result<Self> {
+        // Made up front so reads don't have to allow for it missing
+        let write = db.begin_write().map_err(store)?;
+        write.open_table(USAGE).map_err(store)?;
+        write.commit().map_err(store)?;
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// Adds to what `key` used on `day`.
+    pub fn record(&self, key: &str, day: Day, tokens: Tokens) -> Result<()> {
+        let write = self.db.begin_write().map_err(store)?;
+        {
+            let mut table = write.open_table(USAGE).map_err(store)?;
+            let mut total: Tokens = table
+                .get((key, day.0))
+                .map_err(store)?
+                .map(|it| it.value().into())
+                .unwrap_or_default();
+            total.add(tokens);
+            table
+                .insert(
+                    (key, day.0),
+                    (total.requests, total.prompt, total.completion),
+                )
+                .map_err(store)?;
+        }
+        write.commit().map_err(store)
+    }
+
+    /// What each key, or just `key`, used each day from `from` to `to`
+    /// inclusive, by key then day.
+    pub fn between(&self, key: Option<&str>, from: Day, to: Day) -> Result<Vec<DailyUsage>> {
+        let read = self.db.begin_read().map_err(store)?;
+        let table = read.open_table(USAGE).map_err(store)?;
+        let rows = match key {
+            Some(key) => table.range((key, from.0)..=(key, to.0)),
+            None => table.range::<(&str, u32)>(..),
+        }
+        .map_err(store)?;
+        let mut usage = vec![];
+        for row in rows {
+            let (key, tokens) = row.map_err(store)?;
+            let (key, day) = key.value();
+            if (from.0..=to.0).contains(&day) {
+                usage.push(DailyUsage {
+                    key: key.into(),
+                    day: Day(day),
+                    tokens: tokens.value().into(),
+                });
+            }
+        }
+        Ok(usage)
+    }
+
+    /// What `key` has used in the month `day` is in, up to and including it.
+    pub fn month(&self, key: &str, day: Day) -> Result<Tokens> {
+        let mut total = Tokens::default();
+        for it in self.between(Some(key), day.first_of_month(), day)? {
+            total.add(it.tokens);
+        }
+        Ok(total)
+    }
+}
+
+impl From<(u64, u64, u64)> for Tokens {
+    fn from((requests, prompt, completion): (u64, u64, u64)) -> Self {
+        Self {
+            requests,
+            prompt,
+            completion,
+        }
+    }
+}
+
+fn store(e: impl Into<redb::Error>) -> Error {
+    Error::Store(e.into().into())
+}
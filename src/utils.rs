@@ -1,15 +1,39 @@
+use std::sync::OnceLock;
+
 use anyhow::Result;
 use candle_core::{
     utils::{cuda_is_available, metal_is_available},
     Device,
 };
 
+use crate::config::DeviceKind;
+
+static DEVICE: OnceLock<DeviceKind> = OnceLock::new();
+
+/// Runs every model on this kind of device, rather than the best available.
+/// Only the first call has any effect.
+pub fn use_device(kind: DeviceKind) {
+    let _ = DEVICE.set(kind);
+}
+
 pub fn device() -> Result<Device> {
-    if metal_is_available() {
-        Ok(Device::new_metal(0)?)
-    } else if cuda_is_available() {
-        Ok(Device::new_cuda(0)?)
-    } else {
-        Ok(Device::Cpu)
+    device_of(DEVICE.get().copied().unwrap_or_default())
+}
+
+/// The device `kind` names, picking the best available for `Auto`.
+pub fn device_of(kind: DeviceKind) -> Result<Device> {
+    match kind {
+        DeviceKind::Cpu => Ok(Device::Cpu),
+        DeviceKind::Cuda => Ok(Device::new_cuda(0)?),
+        DeviceKind::Metal => Ok(Device::new_metal(0)?),
+        DeviceKind::Auto => {
+            if metal_is_available() {
+                Ok(Device::new_metal(0)?)
+            } else if cuda_is_available() {
+                Ok(Device::new_cuda(0)?)
+            } else {
+                Ok(Device::Cpu)
+            }
+        }
     }
 }
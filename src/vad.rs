@@ -0,0 +1,61 @@
+//! Energy based voice activity detection for splitting a microphone stream
+//! into utterances.
+use candle_transformers::models::whisper::SAMPLE_RATE;
+
+/// Frames are 30ms long
+const FRAME: usize = SAMPLE_RATE * 30 / 1000;
+/// Root mean square amplitude above which a frame holds speech
+const THRESHOLD: f32 = 0.02;
+/// How much silence ends an utterance, 700ms
+const HANGOVER: usize = 700 / 30;
+/// Utterances shorter than 150ms are treated as noise
+const MIN_SPEECH: usize = 150 / 30;
+
+#[derive(Default)]
+pub struct VoiceActivity {
+    pending: Vec<f32>,
+    utterance: Vec<f32>,
+    speech: usize,
+    silence: usize,
+}
+
+impl VoiceActivity {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether an utterance is in progress.
+    pub fn speaking(&self) -> bool {
+        self.speech > 0
+    }
+
+    /// Feeds 16kHz samples in, returning the utterances the speaker has
+    /// paused after, in order.
+    pub fn push(&mut self, pcm: &[f32]) -> Vec<Vec<f32>> {
+        self.pending.extend_from_slice(pcm);
+        let mut complete = vec![];
+        let frames = self.pending.len() / FRAME;
+        for frame in self.pending.chunks_exact(FRAME) {
+            let rms = (frame.iter().map(|s| s * s).sum::<f32>() / FRAME as f32).sqrt();
+            if rms > THRESHOLD {
+                self.speech += 1;
+                self.silence = 0;
+            } else if self.speaking() {
+                self.silence += 1;
+            } else {
+                continue;
+            }
+            self.utterance.extend_from_slice(frame);
+            if self.silence >= HANGOVER {
+                let utterance = std::mem::take(&mut self.utterance);
+                if self.speech >= MIN_SPEECH {
+                    complete.push(utterance);
+                }
+                self.speech = 0;
+                self.silence = 0;
+            }
+        }
+        self.pending.drain(..frames * FRAME);
+        complete
+    }
+}
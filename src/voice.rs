@@ -1 +1,159 @@
-// todo: https://github.com/huggingface/candle/blob/13c64f6828360a9cb9b58b4f817e4f3b8316388c/candle-examples/examples/metavoice/main.rs
+//! Text to speech with Parler-TTS, adapted from
+//! https://github.com/huggingface/candle/blob/main/candle-examples/examples/parler-tts/main.rs
+use std::io::Cursor;
+
+use anyhow::{anyhow, Result};
+use candle_core::{DType, IndexOp, Tensor};
+use candle_transformers::generation::LogitsProcessor;
+use candle_transformers::models::parler_tts::{Config, Model};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use tokenizers::Tokenizer;
+
+use crate::utils::device;
+
+const DESCRIPTION: &str = "A female speaker delivers a slightly expressive and animated speech with a moderate speed and pitch. The recording is of very high quality, with the speaker's voice sounding clear and very close up.";
+const MAX_STEPS: usize = 512;
+
+pub struct Voice {
+    model: Model,
+    tokenizer: Tokenizer,
+    config: Config,
+}
+
+impl Voice {
+    pub fn new(model: Model, tokenizer: Tokenizer, config: Config) -> Self {
+        Self {
+            model,
+            tokenizer,
+            config,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.config.audio_encoder.sampling_rate
+    }
+
+    fn encode(&self, text: &str) -> Result<Tensor> {
+        let ids = self
+            .tokenizer
+            .encode(text, true)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        Ok(Tensor::new(ids, &device()?)?.unsqueeze(0)?)
+    }
+
+    /// Synthesizes `text` into mono PCM samples in the range [-1, 1].
+    pub fn speak(&mut self, text: &str) -> Result<Vec<f32>> {
+        let prompt = self.encode(text)?;
+        let description = self.encode(DESCRIPTION)?;
+        let lp = LogitsProcessor::new(0, Some(0.0), None);
+        let codes = self
+            .model
+            .generate(&prompt, &description, lp, MAX_STEPS)?
+            .to_dtype(DType::I64)?
+            .unsqueeze(0)?;
+        let pcm = self
+            .model
+            .audio_encoder
+            .decode_codes(&codes.to_device(&device()?)?)?
+            .i((0, 0))?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?;
+        Ok(normalize(pcm))
+    }
+}
+
+fn normalize(mut pcm: Vec<f32>) -> Vec<f32> {
+    let peak = pcm.iter().fold(0f32, |acc, s| acc.max(s.abs()));
+    if peak > 0.0 {
+        pcm.iter_mut().for_each(|s| *s /= peak);
+    }
+    pcm
+}
+
+fn spec(sample_rate: u32) -> WavSpec {
+    WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    }
+}
+
+/// Converts samples to little endian signed 16 bit PCM.
+pub fn pcm16(pcm: &[f32]) -> Vec<u8> {
+    pcm.iter()
+        .flat_map(|s| ((s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())
+        .collect()
+}
+
+/// Encodes samples as a complete WAV file.
+pub fn wav(pcm: &[f32], sample_rate: u32) -> Result<Vec<u8>> {
+    let mut bytes = Cursor::new(Vec::new());
+    let mut writer = WavWriter::new(&mut bytes, spec(sample_rate))?;
+    for sample in pcm {
+        writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+    }
+    writer.finalize()?;
+    Ok(bytes.into_inner())
+}
+
+/// A WAV header for a stream of unknown length, to be followed by `pcm16` chunks.
+pub fn wav_header(sample_rate: u32) -> Result<Vec<u8>> {
+    let mut header = wav(&[], sample_rate)?;
+    let len = header.len();
+    if len < 44 {
+        return Err(anyhow!("short wav header"));
+    }
+    // The RIFF chunk size and the data chunk size
+    header[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+    header[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+    Ok(header)
+}
+
+/// Splits a stream of generated tokens into sentences so that each one can be
+/// spoken as soon as it is complete.
+#[derive(Default)]
+pub struct Sentences {
+    buffer: String,
+}
+
+impl Sentences {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, text: &str) -> Vec<String> {
+        self.buffer.push_str(text);
+        let mut sentences = vec![];
+        let mut start = 0;
+        let mut chars = self.buffer.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            let end = match (c, chars.peek()) {
+                ('\n', _) => true,
+                ('.' | '!' | '?', Some((_, next))) => next.is_whitespace(),
+                _ => false,
+            };
+            if end {
+                let sentence = self.buffer[start..=i].trim();
+                if !sentence.is_empty() {
+                    sentences.push(sentence.to_string());
+                }
+                start = i + 1;
+            }
+        }
+        self.buffer.drain(..start);
+        sentences
+    }
+
+    pub fn finish(&mut self) -> Option<String> {
+        let rest = self.buffer.trim().to_string();
+        self.buffer.clear();
+        if rest.is_empty() {
+            None
+        } else {
+            Some(rest)
+        }
+    }
+}
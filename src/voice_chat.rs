@@ -0,0 +1,257 @@
+//! A full duplex voice conversation over a WebSocket.
+//!
+//! The client streams microphone audio as binary frames of little endian 16
+//! bit mono PCM, at 16kHz unless a `sample_rate` query parameter (8kHz to
+//! 192kHz) says otherwise. When the speaker pauses the utterance is
+//! transcribed and answered, and the answer is streamed back one sentence at a
+//! time as a `sentence` event followed by a binary frame of PCM16 audio at the
+//! voice's sample rate. Speaking over the answer, or sending a `barge_in` event,
+//! cancels it. Browsers can't set headers on a WebSocket, so the API key can
+//! also be an `api_key` query parameter.
+use std::ops::RangeInclusive;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    response::IntoResponse,
+    Extension,
+};
+use candle_transformers::models::whisper::SAMPLE_RATE;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{
+    assistant::AnswerOptions,
+    auth::{Caller, QueryAuthenticated},
+    error::{self, Error},
+    moderation::{Decision, Decisions},
+    server::{
+        answer_aloud, mask_input, meter, transcribe_with, AppState, Storage, TranscriberStorage,
+        VoiceStorage,
+    },
+    telemetry::RequestId,
+    transcription::Pcm16Stream,
+    vad::VoiceActivity,
+};
+
+/// The sample rates clients can send audio at.
+const SAMPLE_RATES: RangeInclusive<usize> = 8_000..=192_000;
+
+#[derive(Deserialize)]
+pub(crate) struct VoiceParams {
+    sample_rate: Option<usize>,
+    persona: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerEvent {
+    Ready { sample_rate: u32 },
+    Transcript { text: String },
+    Sentence { text: String },
+    Done { moderation: Vec<Decision> },
+    Cancelled,
+    Error { message: String },
+}
+
+impl From<ServerEvent> for Message {
+    fn from(event: ServerEvent) -> Self {
+        Message::Text(serde_json::to_string(&event).unwrap_or_default())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientEvent {
+    BargeIn,
+}
+
+pub(crate) async fn voice_chat(
+    ws: WebSocketUpgrade,
+    Query(params): Query<VoiceParams>,
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    QueryAuthenticated(caller): QueryAuthenticated,
+) -> error::Result<impl IntoResponse> {
+    let sample_rate = params.sample_rate.unwrap_or(SAMPLE_RATE);
+    if !SAMPLE_RATES.contains(&sample_rate) {
+        return Err(Error::InvalidRequest(format!(
+            "sample_rate must be between {} and {}",
+            SAMPLE_RATES.start(),
+            SAMPLE_RATES.end()
+        )));
+    }
+    // Checked now so a missing persona fails the upgrade
+    state.personas.get(params.persona.as_deref())?;
+    let speech = Speech {
+        assistant: state.models.assistant().await?,
+        voice: state.models.voice().await?,
+        transcriber: state.models.transcriber().await?,
+        persona: params.persona,
+        request_id,
+        caller,
+        state,
+    };
+    Ok(ws.on_upgrade(move |socket| converse(socket, speech, sample_rate)))
+}
+
+/// Everything a conversation needs, checked to be turned on.
+#[derive(Clone)]
+struct Speech {
+    state: AppState,
+    assistant: Storage,
+    voice: VoiceStorage,
+    transcriber: TranscriberStorage,
+    persona: Option<String>,
+    /// Of the request that opened the conversation, shared by every reply
+    request_id: RequestId,
+    caller: Option<Caller>,
+}
+
+async fn converse(socket: WebSocket, speech: Speech, sample_rate: usize) {
+    let (mut sink, mut source) = socket.split();
+    let (out, mut out_rx) = mpsc::channel::<Message>(64);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = out_rx.recv().await {
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+    let voice_rate = speech.voice.lock().await.sample_rate();
+    let _ = out
+        .send(
+            ServerEvent::Ready {
+                sample_rate: voice_rate,
+            }
+            .into(),
+        )
+        .await;
+    let mut pcm16 = Pcm16Stream::new(sample_rate, SAMPLE_RATE);
+    let mut vad = VoiceActivity::new();
+    let mut reply: Option<JoinHandle<()>> = None;
+    while let Some(Ok(message)) = source.next().await {
+        match message {
+            Message::Binary(bytes) => {
+                let pcm = pcm16.push(&bytes);
+                let was_speaking = vad.speaking();
+                let utterances = vad.push(&pcm);
+                if !was_speaking && vad.speaking() {
+                    cancel(&mut reply, &out).await;
+                }
+                if !utterances.is_empty() {
+                    cancel(&mut reply, &out).await;
+                    let (speech, out) = (speech.clone(), out.clone());
+                    // Utterances that ended together are answered in turn
+                    reply = Some(tokio::spawn(async move {
+                        for utterance in utterances {
+                            respond(speech.clone(), utterance, out.clone()).await;
+                        }
+                    }));
+                }
+            }
+            Message::Text(text) => match serde_json::from_str(&text) {
+                Ok(ClientEvent::BargeIn) => cancel(&mut reply, &out).await,
+                Err(e) => {
+                    let message = e.to_string();
+                    let _ = out.send(ServerEvent::Error { message }.into()).await;
+                }
+            },
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    if let Some(reply) = reply {
+        reply.abort();
+    }
+    drop(out);
+    let _ = writer.await;
+}
+
+/// Stops the reply in progress, if there is one.
+async fn cancel(reply: &mut Option<JoinHandle<()>>, out: &mpsc::Sender<Message>) {
+    if let Some(reply) = reply.take() {
+        if !reply.is_finished() {
+            reply.abort();
+            let _ = out.send(ServerEvent::Cancelled.into()).await;
+        }
+    }
+}
+
+async fn respond(speech: Speech, utterance: Vec<f32>, out: mpsc::Sender<Message>) {
+    if let Err(e) = answer(speech, utterance, &out).await {
+        let message = e.to_string();
+        let _ = out.send(ServerEvent::Error { message }.into()).await;
+    }
+}
+
+/// Transcribes the utterance and speaks the answer to it, one sentence at a
+/// time.
+async fn answer(
+    speech: Speech,
+    utterance: Vec<f32>,
+    out: &mpsc::Sender<Message>,
+) -> error::Result<()> {
+    let text =
+        transcribe_with(&speech.transcriber, move |it| it.transcribe_pcm(&utterance)).await?;
+    if text.is_empty() {
+        return Ok(());
+    }
+    let persona = speech.state.personas.get(speech.persona.as_deref())?;
+    let meter = meter(speech.caller.as_ref()).await?;
+    let decisions = Decisions::default();
+    let text = speech.state.moderator.input(&text, &decisions).await?;
+    let (prompt, _, _, placeholders) =
+        mask_input(&speech.state.masker, text.clone(), vec![], None).await?;
+    let prompt = persona.ask(&prompt, &[], None);
+    let options = AnswerOptions {
+        meter,
+        sampling: persona.sampling,
+        request_id: Some(speech.request_id.clone()),
+        scheduler: Some(speech.state.scheduler.clone()),
+        key: speech.caller.as_ref().map(|it| it.name().into()),
+        ..Default::default()
+    };
+    let _ = out.send(ServerEvent::Transcript { text }.into()).await;
+    let (tx, mut rx) = mpsc::channel(20);
+    // Held until the reply is done, aborting it drops the set and stops generation
+    let _set = answer_aloud(
+        speech.assistant,
+        speech.voice,
+        prompt,
+        options,
+        speech
+            .state
+            .moderator
+            .output(&decisions)
+            .unmasking(&placeholders),
+        &speech.state.shutdown,
+        tx,
+    )?;
+    while let Some(utterance) = rx.recv().await {
+        let messages = match utterance {
+            Ok(utterance) => vec![
+                ServerEvent::Sentence {
+                    text: utterance.text,
+                }
+                .into(),
+                Message::Binary(utterance.audio),
+            ],
+            Err(e) => vec![ServerEvent::Error {
+                message: e.to_string(),
+            }
+            .into()],
+        };
+        for message in messages {
+            if out.send(message).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+    let moderation = decisions.take();
+    let _ = out.send(ServerEvent::Done { moderation }.into()).await;
+    Ok(())
+}
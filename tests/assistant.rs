@@ -1,19 +1,131 @@
-use persephone::assistant::Assistant;
+mod common;
+
+use std::pin::pin;
+use std::sync::{atomic::Ordering::SeqCst, Arc};
+
+use common::{config, tokenizer, ScriptedModel, EOS, LOVES, PARIS, PERSEPHONE, SUN, THE};
+use futures_util::StreamExt;
+use persephone::assistant::{Assistant, Tally};
+use persephone::error::Error;
 use persephone::loading::{ModelFile, TokenizerFile};
+use persephone::model::Model;
 use persephone::prompt::BlockingPrompt;
 use persephone::prompt::SimplePrompt;
+use persephone::speculative::{PromptLookup, SpeculationStats};
+
+const SCRIPT: [u32; 6] = [PERSEPHONE, LOVES, PARIS, THE, SUN, EOS];
+
+async fn collect<M: Model>(assistant: &Assistant<M>, prompt: &str) -> Vec<String> {
+    let tokens = assistant.answer(prompt.into()).await.unwrap();
+    let tokens: Vec<_> = pin!(tokens).collect().await;
+    tokens.into_iter().map(|it| it.unwrap()).collect()
+}
+
+#[tokio::test]
+async fn answers_stream_a_token_at_a_time() {
+    let assistant = Assistant::new(ScriptedModel::new(&SCRIPT), tokenizer(), config());
+    let tokens = collect(&assistant, "hello").await;
+    assert_eq!(tokens, ["persephone", " loves", " paris", " the", " sun"]);
+}
+
+#[tokio::test]
+async fn answers_stop_at_the_end_of_sequence() {
+    let model = ScriptedModel::new(&SCRIPT);
+    let forwards = model.forwards();
+    let assistant = Assistant::new(model, tokenizer(), config());
+    collect(&assistant, "hello").await;
+    assert_eq!(forwards.load(SeqCst), SCRIPT.len());
+}
+
+#[tokio::test]
+async fn answers_stop_at_the_most_tokens_allowed() {
+    let assistant =
+        Assistant::new(ScriptedModel::new(&SCRIPT), tokenizer(), config()).with_max_tokens(Some(2));
+    let tokens = collect(&assistant, "hello").await;
+    assert_eq!(tokens, ["persephone", " loves"]);
+}
+
+#[tokio::test]
+async fn prompt_lookup_gives_the_same_answer() {
+    let stats = Arc::new(SpeculationStats::default());
+    let assistant = Assistant::new(ScriptedModel::new(&SCRIPT), tokenizer(), config())
+        .with_speculation(stats.clone());
+    let tokens = assistant
+        .answer_with_lookup("persephone loves paris".into(), PromptLookup::default())
+        .await
+        .unwrap();
+    let answer: Vec<_> = pin!(tokens).map(|it| it.unwrap()).collect().await;
+    assert_eq!(answer.concat(), "persephone loves paris the sun");
+    assert!(assistant.speculation().accepted() > 0);
+    assert_eq!(stats.accepted(), assistant.speculation().accepted());
+}
+
+#[tokio::test]
+async fn prompts_longer_than_the_context_are_refused() {
+    let mut config = config();
+    config.max_position_embeddings = 2;
+    let assistant = Assistant::new(ScriptedModel::new(&SCRIPT), tokenizer(), config);
+    let Err(error) = assistant.answer("hello persephone".into()).await else {
+        panic!("the prompt fit");
+    };
+    assert!(matches!(
+        error,
+        Error::ContextOverflow {
+            tokens: 2,
+            limit: 2
+        }
+    ));
+}
+
+#[tokio::test]
+async fn answers_end_when_the_context_fills_up() {
+    let mut config = config();
+    config.max_position_embeddings = 3;
+    let assistant = Assistant::new(ScriptedModel::new(&SCRIPT), tokenizer(), config);
+    let tokens = assistant.answer("hello".into()).await.unwrap();
+    let tokens: Vec<_> = pin!(tokens).collect().await;
+    assert_eq!(tokens.len(), 3);
+    assert!(matches!(
+        tokens.last(),
+        Some(Err(Error::ContextOverflow { tokens: 3, .. }))
+    ));
+}
+
+#[tokio::test]
+async fn simple_prompts_collect_the_whole_answer() {
+    let assistant = Assistant::new(ScriptedModel::new(&SCRIPT), tokenizer(), config());
+    let result = SimplePrompt::new()
+        .run(&assistant, Some("what is your name?".into()))
+        .await
+        .unwrap();
+    assert_eq!(result, "persephone loves paris the sun");
+}
+
+#[test]
+fn embeddings_average_the_hidden_states() {
+    let assistant = Assistant::new(ScriptedModel::new(&SCRIPT), tokenizer(), config());
+    // The scripted model's hidden states are one-hot, so equal parts of each
+    let tally = Tally::default();
+    let embedding = assistant.embed("the sun", Some(&tally)).unwrap();
+    assert_eq!(tally.prompt_tokens(), 2);
+    let half = 0.5f32.sqrt();
+    assert!((embedding[THE as usize] - half).abs() < 1e-6);
+    assert!((embedding[SUN as usize] - half).abs() < 1e-6);
+    assert_eq!(embedding.iter().filter(|it| **it != 0.0).count(), 2);
+}
 
 // This test is really expensive
 #[tokio::test]
+#[ignore = "downloads a model from the Hub"]
 async fn assistant_works() {
     let tokenizer = TokenizerFile::download().unwrap().tokenizer().unwrap();
     let (model, config) = ModelFile::download().unwrap().model().unwrap();
-    let mut assistant = Assistant::new(model, tokenizer, config);
+    let assistant = Assistant::new(model, tokenizer, config);
     let prompt = SimplePrompt::new();
 
     let result = prompt
         .run(
-            &mut assistant,
+            &assistant,
             Some(String::from(
                 "<|im_start|>system
 Reply to all questions with your name 'Persephone'<|im_end|>
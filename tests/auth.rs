@@ -0,0 +1,158 @@
+mod common;
+
+use std::{pin::pin, sync::Arc};
+
+use axum::{
+    http::{HeaderMap, HeaderValue},
+    response::IntoResponse,
+};
+use common::{config, tokenizer, ScriptedModel, EOS, PARIS, PERSEPHONE};
+use futures_util::StreamExt;
+use persephone::{
+    assistant::{AnswerOptions, Assistant},
+    auth::{hash_key, new_key, Auth, AuthSettings, KeySettings, TokenBucket},
+    error::Error,
+    usage::Usage,
+};
+use serde_json::json;
+
+fn auth(key: &str, requests_per_minute: Option<u32>, tokens_per_minute: Option<u32>) -> Auth {
+    let settings = AuthSettings {
+        keys: vec![KeySettings {
+            name: "alice".into(),
+            sha256: hash_key(key),
+            requests_per_minute,
+            tokens_per_minute,
+            monthly_tokens: None,
+            admin: false,
+        }],
+    };
+    Auth::new(&settings, &Usage::in_memory().unwrap())
+}
+
+#[test]
+fn keys_are_checked_by_their_hash() {
+    let key = new_key();
+    assert!(key.starts_with("sk-"));
+    assert_ne!(key, new_key());
+    let auth = auth(&key, None, None);
+    assert!(auth.enabled());
+    assert_eq!(auth.authenticate(&key).unwrap().name(), "alice");
+    assert!(matches!(
+        auth.authenticate("sk-wrong"),
+        Err(Error::Unauthorized)
+    ));
+}
+
+#[test]
+fn keys_come_from_headers_or_the_connection_payload() {
+    let auth = auth("sk-secret", None, None);
+    let mut bearer = HeaderMap::new();
+    bearer.insert(
+        "authorization",
+        HeaderValue::from_static("Bearer sk-secret"),
+    );
+    assert!(auth.from_headers(&bearer).unwrap().is_some());
+    let mut api_key = HeaderMap::new();
+    api_key.insert("x-api-key", HeaderValue::from_static("sk-secret"));
+    assert!(auth.from_headers(&api_key).unwrap().is_some());
+    assert!(matches!(
+        auth.from_headers(&HeaderMap::new()),
+        Err(Error::Unauthorized)
+    ));
+
+    let payload = json!({ "apiKey": "sk-secret" });
+    assert!(auth.from_payload(&payload).unwrap().is_some());
+    let payload = json!({ "Authorization": "Bearer sk-secret" });
+    assert!(auth.from_payload(&payload).unwrap().is_some());
+    assert!(matches!(
+        auth.from_payload(&json!({})),
+        Err(Error::Unauthorized)
+    ));
+
+    let uri = "/api/ask?prompt=hi&api_key=sk-secret".parse().unwrap();
+    assert!(auth.from_query(&uri, &HeaderMap::new()).unwrap().is_some());
+    let uri = "/api/ask?prompt=hi".parse().unwrap();
+    assert!(auth.from_query(&uri, &bearer).unwrap().is_some());
+    let uri = "/api/ask?api_key=sk-wrong".parse().unwrap();
+    assert!(matches!(
+        auth.from_query(&uri, &bearer),
+        Err(Error::Unauthorized)
+    ));
+
+    // Without keys nobody needs one
+    let open = Auth::default();
+    assert!(!open.enabled());
+    assert!(open.from_headers(&HeaderMap::new()).unwrap().is_none());
+    assert!(open.from_payload(&json!({})).unwrap().is_none());
+}
+
+#[test]
+fn requests_over_the_limit_are_told_when_to_retry() {
+    let caller = auth("sk-secret", Some(2), None)
+        .authenticate("sk-secret")
+        .unwrap();
+    caller.request().unwrap();
+    caller.request().unwrap();
+    let error = caller.request().unwrap_err();
+    assert!(matches!(
+        error,
+        Error::RateLimited {
+            limit: "requests",
+            retry_after: 30
+        }
+    ));
+    assert_eq!(caller.requests_made(), 2);
+
+    let response = error.into_response();
+    assert_eq!(response.status(), 429);
+    assert_eq!(response.headers()["retry-after"], "30");
+}
+
+#[test]
+fn buckets_refill_over_a_minute() {
+    let bucket = TokenBucket::per_minute(60);
+    assert_eq!(bucket.capacity(), 60);
+    assert!(bucket.take(60.0).is_ok());
+    let wait = bucket.take(1.0).unwrap_err();
+    assert!(
+        wait.as_millis() > 900 && wait.as_millis() <= 1000,
+        "{wait:?}"
+    );
+    bucket.spend(10.0);
+    assert!(bucket.available() < -9.0);
+}
+
+#[tokio::test]
+async fn answers_spend_the_callers_tokens() {
+    let caller = auth("sk-secret", None, Some(2))
+        .authenticate("sk-secret")
+        .unwrap();
+    caller.check_tokens().await.unwrap();
+
+    let assistant = Assistant::new(
+        ScriptedModel::new(&[PERSEPHONE, PARIS, EOS]),
+        tokenizer(),
+        config(),
+    );
+    let options = AnswerOptions {
+        meter: Some(Arc::new(caller.clone())),
+        ..Default::default()
+    };
+    let mut tokens = pin!(assistant
+        .answer_with("hello".into(), options)
+        .await
+        .unwrap());
+    while let Some(token) = tokens.next().await {
+        token.unwrap();
+    }
+    assert_eq!(caller.tokens_generated(), 3);
+    // Answers aren't cut short, but the next has to wait
+    assert!(matches!(
+        caller.check_tokens().await,
+        Err(Error::RateLimited {
+            limit: "tokens",
+            ..
+        })
+    ));
+}
@@ -0,0 +1,179 @@
+mod common;
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use common::{tokenizer, ScriptedModel, EOS, PARIS, PERSEPHONE};
+use futures_util::lock::Mutex;
+use persephone::{
+    assistant::Assistant,
+    auth::{hash_key, Auth, AuthSettings, KeySettings},
+    batch::{Batch, Batches, Progress},
+    error::Error,
+    moderation::{ModerationSettings, Moderator},
+    persona::{PersonaSettings, Personas},
+    pii::{Masker, PiiSettings},
+    readiness::Loadable,
+    scheduler::Scheduler,
+    shutdown::Shutdown,
+    usage::Usage,
+};
+use serde_json::Value;
+
+/// Answers every request with "persephone paris".
+fn batch() -> Batch<ScriptedModel> {
+    let model = ScriptedModel::new(&[PERSEPHONE, PARIS, EOS]);
+    let assistant = Assistant::new(model, tokenizer(), common::config());
+    Batch::new(
+        Loadable::ready("assistant", Arc::new(Mutex::new(assistant))),
+        Personas::load(&PersonaSettings::default()).unwrap(),
+        Moderator::new(&ModerationSettings::default(), None).unwrap(),
+        Masker::new(&PiiSettings::default(), None),
+        Scheduler::default(),
+        Shutdown::default(),
+    )
+}
+
+fn temp(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("persephone-{name}-{}", rand::random::<u64>()))
+}
+
+fn rows(results: &[u8]) -> Vec<Value> {
+    std::str::from_utf8(results)
+        .unwrap()
+        .lines()
+        .map(|it| serde_json::from_str(it).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn each_request_gets_an_answer_or_an_error() {
+    let (input, output) = (temp("batch.jsonl"), temp("results.jsonl"));
+    let requests = [
+        r#"{"id": "q1", "kind": "ask", "prompt": "hello"}"#,
+        "",
+        "not json",
+        r#"{"kind": "summarize", "messages": []}"#,
+        r#"{"id": "q5", "kind": "summarize", "messages": [{"author": "user", "message": "the sun"}]}"#,
+    ];
+    std::fs::write(&input, requests.join("\n")).unwrap();
+    let progress = batch().run(&input, &output, None).await.unwrap();
+    let results = std::fs::read(&output).unwrap();
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&output).unwrap();
+
+    assert_eq!(
+        progress,
+        Progress {
+            total: 4,
+            answered: 2,
+            failed: 2
+        }
+    );
+    let rows = rows(&results);
+    let lines: Vec<_> = rows.iter().map(|it| it["line"].as_u64().unwrap()).collect();
+    assert_eq!(lines, [1, 3, 4, 5]);
+    assert_eq!(rows[0]["id"], "q1");
+    assert_eq!(rows[0]["text"], "persephone paris");
+    assert_eq!(rows[0]["usage"]["completion_tokens"], 3);
+    assert_eq!(rows[1]["error"]["code"], "INVALID_REQUEST");
+    assert!(rows[1].get("id").is_none());
+    assert_eq!(rows[2]["error"]["code"], "INVALID_REQUEST");
+    assert_eq!(rows[3]["id"], "q5");
+    assert_eq!(rows[3]["text"], "persephone paris");
+}
+
+#[tokio::test]
+async fn runs_carry_on_after_the_last_result() {
+    let (input, output) = (temp("batch.jsonl"), temp("results.jsonl"));
+    let ask = r#"{"kind": "ask", "prompt": "hello"}"#;
+    std::fs::write(&input, [ask, ask, ask].join("\n")).unwrap();
+    // The second result was cut off by a crash
+    std::fs::write(
+        &output,
+        "{\"line\":1,\"text\":\"earlier\"}\n{\"line\":2,\"te",
+    )
+    .unwrap();
+    let progress = batch().run(&input, &output, None).await.unwrap();
+    let results = std::fs::read(&output).unwrap();
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&output).unwrap();
+
+    assert_eq!(progress.answered, 3);
+    assert!(progress.finished());
+    let rows = rows(&results);
+    let lines: Vec<_> = rows.iter().map(|it| it["line"].as_u64().unwrap()).collect();
+    assert_eq!(lines, [1, 2, 3]);
+    assert_eq!(rows[0]["text"], "earlier");
+    assert_eq!(rows[1]["text"], "persephone paris");
+}
+
+fn key(name: &str, admin: bool) -> KeySettings {
+    KeySettings {
+        name: name.into(),
+        sha256: hash_key(&format!("sk-{name}")),
+        requests_per_minute: None,
+        tokens_per_minute: None,
+        monthly_tokens: None,
+        admin,
+    }
+}
+
+#[tokio::test]
+async fn uploaded_batches_are_only_seen_by_their_key() {
+    let settings = AuthSettings {
+        keys: vec![key("alice", false), key("bob", false), key("root", true)],
+    };
+    let auth = Auth::new(&settings, &Usage::in_memory().unwrap());
+    let directory = temp("batches");
+    let batches = Batches::new(&directory, batch(), auth.clone()).unwrap();
+    let alice = auth.authenticate("sk-alice").unwrap();
+    let jsonl = br#"{"kind": "ask", "prompt": "hello"}"#;
+    let submitted = batches.submit(jsonl, Some(&alice)).unwrap();
+    assert_eq!(submitted.progress.total, 1);
+
+    let id = submitted.id;
+    let finished = tokio::time::timeout(Duration::from_secs(5), async {
+        while !batches.status(&id, Some(&alice)).unwrap().finished {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await;
+    assert!(finished.is_ok());
+    let results = batches.results(&id, Some(&alice)).unwrap();
+    assert_eq!(rows(&results)[0]["text"], "persephone paris");
+
+    let bob = auth.authenticate("sk-bob").unwrap();
+    assert!(matches!(
+        batches.status(&id, Some(&bob)),
+        Err(Error::Forbidden(_))
+    ));
+    let root = auth.authenticate("sk-root").unwrap();
+    assert!(batches.results(&id, Some(&root)).is_ok());
+    assert!(matches!(
+        batches.status("../etc", Some(&alice)),
+        Err(Error::NotFound(_))
+    ));
+    assert!(matches!(
+        batches.submit(b"\n\n", Some(&alice)),
+        Err(Error::InvalidRequest(_))
+    ));
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn unreadable_batches_are_skipped_when_resuming() {
+    let directory = temp("batches");
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("notes.jsonl"), "not a batch").unwrap();
+    std::fs::write(
+        directory.join("abc.jsonl"),
+        r#"{"kind": "ask", "prompt": "hi"}"#,
+    )
+    .unwrap();
+    std::fs::write(directory.join("abc.json"), r#"{"key": null}"#).unwrap();
+    std::fs::write(directory.join("abc.results.jsonl"), "not json\n").unwrap();
+    let auth = Auth::new(&AuthSettings::default(), &Usage::in_memory().unwrap());
+    let batches = Batches::new(&directory, batch(), auth).unwrap();
+    assert!(batches.resume().is_ok());
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use std::sync::atomic::Ordering::SeqCst;
+
+use candle_core::Device;
+use common::{config, ScriptedModel, HELLO, PARIS, PERSEPHONE};
+use persephone::{
+    bench::{measure, with_threads, Case, CpuFeatures, Report, Run, Setup},
+    config::{DeviceKind, Precision},
+    error::Error,
+};
+
+const SCRIPT: [u32; 3] = [HELLO, PERSEPHONE, PARIS];
+
+#[test]
+fn measures_one_prefill_and_a_forward_per_decoded_token() {
+    let model = ScriptedModel::new(&SCRIPT);
+    let forwards = model.forwards();
+    let case = Case {
+        prompt_tokens: 32,
+        output_tokens: 5,
+    };
+    let measured = measure(&model, &config(), &Device::Cpu, case).unwrap();
+    assert_eq!(forwards.load(SeqCst), 5);
+    assert!(measured.prefill_tokens_per_second > 0.0);
+    assert!(measured.decode_tokens_per_second > 0.0);
+    assert!(measured.time_to_first_token_ms >= 0.0);
+    if cfg!(target_os = "linux") {
+        assert!(measured.peak_memory_bytes.unwrap() > 0);
+    }
+}
+
+#[test]
+fn refuses_cases_longer_than_the_context() {
+    let model = ScriptedModel::new(&SCRIPT);
+    let case = Case {
+        prompt_tokens: 4000,
+        output_tokens: 100,
+    };
+    let result = measure(&model, &config(), &Device::Cpu, case);
+    assert!(matches!(
+        result,
+        Err(Error::ContextOverflow {
+            tokens: 4100,
+            limit: 4096
+        })
+    ));
+}
+
+#[test]
+fn runs_on_the_requested_threads() {
+    let threads = with_threads(2, rayon::current_num_threads).unwrap();
+    assert_eq!(threads, 2);
+    assert!(with_threads(0, || ()).is_err());
+}
+
+#[test]
+fn reports_flat_json_rows() {
+    let model = ScriptedModel::new(&SCRIPT);
+    let case = Case {
+        prompt_tokens: 4,
+        output_tokens: 2,
+    };
+    let report = Report {
+        model: "scripted".into(),
+        cpu: CpuFeatures::detect(),
+        runs: vec![Run {
+            setup: Setup {
+                dtype: Precision::F32,
+                device: DeviceKind::Cpu,
+                threads: 1,
+            },
+            measurement: measure(&model, &config(), &Device::Cpu, case).unwrap(),
+        }],
+    };
+    let json = serde_json::to_value(&report).unwrap();
+    let run = &json["runs"][0];
+    assert_eq!(run["dtype"], "f32");
+    assert_eq!(run["device"], "cpu");
+    assert_eq!(run["threads"], 1);
+    assert_eq!(run["prompt_tokens"], 4);
+    assert_eq!(run["output_tokens"], 2);
+    for key in [
+        "prefill_tokens_per_second",
+        "decode_tokens_per_second",
+        "time_to_first_token_ms",
+    ] {
+        assert!(run[key].is_number(), "{key}");
+    }
+    assert!(json["cpu"]["avx"].is_boolean());
+}
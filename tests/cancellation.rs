@@ -0,0 +1,75 @@
+mod common;
+
+use std::{
+    pin::pin,
+    sync::{atomic::Ordering::SeqCst, Arc},
+    time::Duration,
+};
+
+use common::{config, tokenizer, ScriptedModel, HELLO, PARIS, PERSEPHONE};
+use futures_util::{lock::Mutex, StreamExt};
+use persephone::{
+    assistant::{AnswerOptions, Assistant},
+    server::subscribe,
+    shutdown::Shutdown,
+};
+
+// Never says </s>
+const SCRIPT: [u32; 3] = [HELLO, PERSEPHONE, PARIS];
+
+#[tokio::test]
+async fn dropping_the_answer_stops_decoding() {
+    let model = ScriptedModel::new(&SCRIPT);
+    let forwards = model.forwards();
+    let assistant = Assistant::new(model, tokenizer(), config());
+    {
+        let mut tokens = pin!(assistant.answer("hello".into()).await.unwrap());
+        for _ in 0..3 {
+            tokens.next().await.unwrap().unwrap();
+        }
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(forwards.load(SeqCst), 3);
+}
+
+#[tokio::test]
+async fn a_departed_subscriber_releases_the_assistant() {
+    let model = ScriptedModel::new(&SCRIPT);
+    let forwards = model.forwards();
+    let storage = Arc::new(Mutex::new(Assistant::new(model, tokenizer(), config())));
+    let mut tokens = Box::pin(subscribe(
+        storage.clone(),
+        "hello".into(),
+        AnswerOptions::default(),
+        &Shutdown::default(),
+    ));
+    tokens.next().await.unwrap().unwrap();
+    drop(tokens);
+    let assistant = tokio::time::timeout(Duration::from_secs(5), storage.lock()).await;
+    assert!(assistant.is_ok(), "the assistant is still answering");
+    drop(assistant);
+    let stopped_at = forwards.load(SeqCst);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(forwards.load(SeqCst), stopped_at);
+    assert!(stopped_at < config().max_position_embeddings / 2);
+}
+
+#[tokio::test]
+async fn a_subscriber_that_leaves_while_waiting_is_never_answered() {
+    let model = ScriptedModel::new(&SCRIPT);
+    let forwards = model.forwards();
+    let storage = Arc::new(Mutex::new(Assistant::new(model, tokenizer(), config())));
+    let busy = storage.lock().await;
+    let tokens = subscribe(
+        storage.clone(),
+        "hello".into(),
+        AnswerOptions::default(),
+        &Shutdown::default(),
+    );
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(tokens);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(busy);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(forwards.load(SeqCst), 0);
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use std::fs;
+
+use common::{config, tokenizer, ScriptedModel, EOS, PARIS, PERSEPHONE};
+use persephone::assistant::{Assistant, Sampling};
+use persephone::chat::{Chat, Command};
+use persephone::error::Error;
+use persephone::persona::Persona;
+
+#[test]
+fn slash_commands_parse() {
+    assert!(Command::parse("what is your name?").is_none());
+    assert_eq!(Command::parse("/reset").unwrap().unwrap(), Command::Reset);
+    assert_eq!(
+        Command::parse("/system You are terse. ").unwrap().unwrap(),
+        Command::System("You are terse.".into())
+    );
+    assert_eq!(
+        Command::parse("/temp 0.3").unwrap().unwrap(),
+        Command::Temperature(0.3)
+    );
+    assert_eq!(
+        Command::parse("/save chat.json").unwrap().unwrap(),
+        Command::Save("chat.json".into())
+    );
+    for line in ["/temp hot", "/system", "/save", "/dance"] {
+        assert!(matches!(
+            Command::parse(line),
+            Some(Err(Error::InvalidRequest(_)))
+        ));
+    }
+}
+
+#[tokio::test]
+async fn answers_stream_out_and_are_remembered() {
+    let assistant = Assistant::new(
+        ScriptedModel::new(&[PERSEPHONE, PARIS, EOS]),
+        tokenizer(),
+        config(),
+    );
+    let mut chat = Chat::new(Persona::default(), Sampling::default());
+    let mut out = vec![];
+    let answer = chat.say(&assistant, "hello", &mut out).await.unwrap();
+    assert_eq!(answer, "persephone paris");
+    assert_eq!(String::from_utf8(out).unwrap(), answer);
+    chat.say(&assistant, "hello again", &mut vec![])
+        .await
+        .unwrap();
+    let history: Vec<_> = chat.history().iter().map(|it| &it.message).collect();
+    assert_eq!(
+        history,
+        [
+            "hello",
+            "persephone paris",
+            "hello again",
+            "persephone paris"
+        ]
+    );
+
+    chat.run(Command::Reset).unwrap();
+    assert!(chat.history().is_empty());
+}
+
+#[tokio::test]
+async fn conversations_save_as_json() {
+    let assistant = Assistant::new(ScriptedModel::new(&[PARIS, EOS]), tokenizer(), config());
+    let mut chat = Chat::new(Persona::default(), Sampling::default());
+    chat.say(&assistant, "hello", &mut vec![]).await.unwrap();
+    let path = std::env::temp_dir().join(format!("persephone-chat-{}.json", std::process::id()));
+    chat.run(Command::Save(path.clone())).unwrap();
+    let saved: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved[0]["author"], "user");
+    assert_eq!(saved[1]["message"], "paris");
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn temperatures_are_checked() {
+    let mut chat = Chat::new(Persona::default(), Sampling::default());
+    assert!(chat.run(Command::Temperature(0.5)).is_ok());
+    assert!(matches!(
+        chat.run(Command::Temperature(0.0)),
+        Err(Error::Config(_))
+    ));
+}
@@ -0,0 +1,127 @@
+#![allow(dead_code)]
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering::SeqCst},
+    Arc,
+};
+
+use candle_core::{Device, Result, Tensor};
+use candle_nn::encoding::one_hot;
+use candle_transformers::models::llama::{Config, LlamaEosToks};
+use persephone::model::{KvCache, Model};
+use tokenizers::Tokenizer;
+
+/// The size of the fixture tokenizer's vocabulary
+pub const VOCAB_SIZE: usize = 20;
+pub const EOS: u32 = 1;
+pub const HELLO: u32 = 4;
+pub const PERSEPHONE: u32 = 5;
+pub const LOVES: u32 = 6;
+pub const PARIS: u32 = 7;
+pub const THE: u32 = 8;
+pub const SUN: u32 = 9;
+
+/// A lowercasing word level tokenizer that knows a handful of words.
+pub fn tokenizer() -> Tokenizer {
+    Tokenizer::from_file(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/tokenizer.json"
+    ))
+    .unwrap()
+}
+
+pub fn config() -> Config {
+    Config {
+        hidden_size: 16,
+        intermediate_size: 32,
+        vocab_size: VOCAB_SIZE,
+        num_hidden_layers: 1,
+        num_attention_heads: 4,
+        num_key_value_heads: 4,
+        use_flash_attn: false,
+        rms_norm_eps: 1e-5,
+        rope_theta: 10000.0,
+        bos_token_id: None,
+        eos_token_id: Some(LlamaEosToks::Single(EOS)),
+        rope_scaling: None,
+        max_position_embeddings: 4096,
+        tie_word_embeddings: true,
+    }
+}
+
+pub struct ScriptedCache {
+    len: usize,
+    prompt: usize,
+}
+
+impl KvCache for ScriptedCache {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        self.len = self.len.min(len);
+        Ok(())
+    }
+}
+
+/// Answers any prompt with `script`, over and over, counting its forward
+/// passes.
+pub struct ScriptedModel {
+    script: Vec<u32>,
+    forwards: Arc<AtomicUsize>,
+}
+
+impl ScriptedModel {
+    pub fn new(script: &[u32]) -> Self {
+        Self {
+            script: script.to_vec(),
+            forwards: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn forwards(&self) -> Arc<AtomicUsize> {
+        self.forwards.clone()
+    }
+
+    /// Logits all but certain of the `n`th scripted token.
+    fn logits(&self, n: usize, device: &Device) -> Result<Tensor> {
+        let mut logits = vec![0f32; VOCAB_SIZE];
+        logits[self.script[n % self.script.len()] as usize] = 20.0;
+        Tensor::new(logits, device)
+    }
+}
+
+impl Model for ScriptedModel {
+    type Cache = ScriptedCache;
+
+    fn cache(&self, _config: &Config, _device: &Device) -> Result<ScriptedCache> {
+        Ok(ScriptedCache { len: 0, prompt: 0 })
+    }
+
+    fn forward(&self, x: &Tensor, cache: &mut ScriptedCache) -> Result<Tensor> {
+        self.forward_last(x, 1, cache)?.squeeze(1)
+    }
+
+    fn forward_last(&self, x: &Tensor, n: usize, cache: &mut ScriptedCache) -> Result<Tensor> {
+        self.forwards.fetch_add(1, SeqCst);
+        let (_, seq_len) = x.dims2()?;
+        if cache.len == 0 {
+            cache.prompt = seq_len;
+        }
+        // The logits at each position are for the token after it
+        let rows = (cache.len + 1..=cache.len + seq_len)
+            .map(|next| self.logits(next.saturating_sub(cache.prompt), x.device()))
+            .collect::<Result<Vec<_>>>()?;
+        cache.len += seq_len;
+        let n = n.min(seq_len);
+        Tensor::stack(&rows[seq_len - n..], 0)?.unsqueeze(0)
+    }
+
+    /// Each token's hidden state is its one-hot encoding.
+    fn hidden(&self, x: &Tensor, cache: &mut ScriptedCache) -> Result<Tensor> {
+        let (_, seq_len) = x.dims2()?;
+        cache.len += seq_len;
+        one_hot(x.clone(), VOCAB_SIZE, 1f32, 0f32)
+    }
+}
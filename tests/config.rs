@@ -0,0 +1,90 @@
+use persephone::config::{DeviceKind, Precision, Settings};
+use persephone::error::Error;
+use persephone::telemetry::LogFormat;
+
+fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+    vars.iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn defaults_need_no_file() {
+    let settings = Settings::parse("", []).unwrap();
+    assert_eq!(settings.server.listen, "0.0.0.0:8000");
+    assert_eq!(settings.model.dtype, Precision::F16);
+    assert_eq!(settings.model.device, DeviceKind::Auto);
+    assert!(settings.features.voice);
+    assert_eq!(settings.logging.format, LogFormat::Text);
+    assert!(!settings.logging.prompts);
+    assert!(settings.auth.keys.is_empty());
+    assert!(!settings.pii.mask_prompts);
+    assert!(settings.batch.directory.is_none());
+}
+
+#[test]
+fn the_environment_overrides_the_file() {
+    let toml = r#"
+[server]
+listen = "127.0.0.1:8000"
+
+[sampling]
+temperature = 0.5
+top_p = 0.5
+"#;
+    let settings = Settings::parse(
+        toml,
+        env(&[
+            ("PERSEPHONE_SERVER__LISTEN", "127.0.0.1:9000"),
+            ("PERSEPHONE_SAMPLING__TOP_P", "0.95"),
+            ("PERSEPHONE_FEATURES__VOICE", "false"),
+            ("PERSEPHONE_MODEL__DTYPE", "f32"),
+            ("PERSEPHONE_CONFIG", "ignored.toml"),
+            ("HOME", "/root"),
+        ]),
+    )
+    .unwrap();
+    assert_eq!(settings.server.listen, "127.0.0.1:9000");
+    assert_eq!(settings.sampling.temperature, 0.5);
+    assert_eq!(settings.sampling.top_p, 0.95);
+    assert!(!settings.features.voice);
+    assert_eq!(settings.model.dtype, Precision::F32);
+}
+
+#[test]
+fn invalid_settings_are_refused() {
+    for (toml, env) in [
+        ("[sampling]\ntemprature = 1.0", vec![]),
+        ("[sampling]\ntop_p = 1.5", vec![]),
+        ("", env(&[("PERSEPHONE_SERVER__LISTEN", "localhost")])),
+        ("[model]\nspeculate = 0", vec![]),
+        ("[personas]\nsummary_prompt = \"no chat here\"", vec![]),
+        ("[personas]\ndefault = \"nobody\"", vec![]),
+        ("[model]\ndtype = \"f8\"", vec![]),
+        ("[logging]\nlevel = \"persephone=loud\"", vec![]),
+        ("[logging]\nformat = \"xml\"", vec![]),
+        ("[pii]\nkinds = [\"ssn\"]", vec![]),
+        ("[server]\ngrpc_listen = \"localhost\"", vec![]),
+        ("[scheduler]\npromote_after_seconds = 0", vec![]),
+        (
+            "[[moderation.rules]]\nname = \"x\"\npattern = \"(\"\naction = \"redact\"",
+            vec![],
+        ),
+        ("[[auth.keys]]\nname = \"alice\"\nsha256 = \"abc\"", vec![]),
+        (
+            "[[auth.keys]]\nname = \"alice\"\nsha256 = \"2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b\"\nrequests_per_minute = 0",
+            vec![],
+        ),
+        (
+            "[[auth.keys]]\nname = \"alice\"\nsha256 = \"2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b\"\n\
+             [[auth.keys]]\nname = \"alice\"\nsha256 = \"3bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b\"",
+            vec![],
+        ),
+    ] {
+        let result = Settings::parse(toml, env);
+        assert!(
+            matches!(result, Err(Error::Config(_))),
+            "{toml} was accepted"
+        );
+    }
+}
@@ -0,0 +1,41 @@
+use async_graphql::{ErrorExtensions, Value};
+use axum::{http::StatusCode, response::IntoResponse};
+use persephone::error::Error;
+
+#[test]
+fn context_overflow_carries_its_size() {
+    let error = Error::ContextOverflow {
+        tokens: 9000,
+        limit: 8192,
+    };
+    assert_eq!(error.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let extensions = error.extend().extensions.unwrap();
+    assert_eq!(
+        extensions.get("code"),
+        Some(&Value::from("CONTEXT_OVERFLOW"))
+    );
+    assert_eq!(extensions.get("tokens"), Some(&Value::from(9000)));
+    assert_eq!(extensions.get("limit"), Some(&Value::from(8192)));
+}
+
+#[test]
+fn errors_map_to_status_codes() {
+    let response = Error::RateLimited {
+        limit: "requests_per_minute",
+        retry_after: 5,
+    }
+    .into_response();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let response = Error::InvalidRequest("no file".into()).into_response();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(Error::Cancelled.status().as_u16(), 499);
+    assert_eq!(
+        Error::ModelLoad(anyhow::anyhow!("missing")).status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(
+        Error::QuotaExceeded { budget: 100 }.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(Error::Forbidden("look").status(), StatusCode::FORBIDDEN);
+}
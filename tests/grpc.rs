@@ -0,0 +1,141 @@
+#![cfg(feature = "grpc")]
+
+mod common;
+
+use std::sync::Arc;
+
+use common::{tokenizer, ScriptedModel, EOS, PARIS, PERSEPHONE, VOCAB_SIZE};
+use futures_util::{lock::Mutex, StreamExt};
+use persephone::{
+    assistant::Assistant,
+    auth::{hash_key, Auth, AuthSettings, KeySettings},
+    grpc::{
+        assistant_client::AssistantClient, serve, AssistantService, EmbedRequest, Event,
+        GenerateRequest, Message, SummarizeRequest,
+    },
+    moderation::{ModerationSettings, Moderator},
+    persona::{PersonaSettings, Personas},
+    pii::{Masker, PiiSettings},
+    readiness::Loadable,
+    scheduler::Scheduler,
+    shutdown::Shutdown,
+    usage::Usage,
+};
+use tokio::net::TcpListener;
+use tonic::{transport::Channel, Code, Request};
+
+/// Serves a scripted model that answers "persephone paris", returning a
+/// client for it.
+async fn client(auth: Auth) -> AssistantClient<Channel> {
+    let model = ScriptedModel::new(&[PERSEPHONE, PARIS, EOS]);
+    let assistant = Assistant::new(model, tokenizer(), common::config());
+    let service = AssistantService::new(
+        Loadable::ready("assistant", Arc::new(Mutex::new(assistant))),
+        Personas::load(&PersonaSettings::default()).unwrap(),
+        Moderator::new(&ModerationSettings::default(), None).unwrap(),
+        Masker::new(&PiiSettings::default(), None),
+        Scheduler::default(),
+        Shutdown::default(),
+        auth,
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(service, listener, Shutdown::default()));
+    AssistantClient::connect(format!("http://{addr}"))
+        .await
+        .unwrap()
+}
+
+fn question(prompt: &str) -> GenerateRequest {
+    GenerateRequest {
+        prompt: prompt.into(),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn questions_are_answered_whole_or_streamed() {
+    let mut client = client(Auth::default()).await;
+    let reply = client
+        .generate(question("hello"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(reply.text, "persephone paris");
+    assert!(!reply.stopped);
+    let usage = reply.usage.unwrap();
+    assert_eq!(usage.completion_tokens, 3);
+    assert_eq!(usage.total_tokens, usage.prompt_tokens + 3);
+
+    let mut chunks = client
+        .generate_stream(question("hello"))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut text = String::new();
+    let mut finished = None;
+    while let Some(chunk) = chunks.next().await {
+        match chunk.unwrap().event.unwrap() {
+            Event::Token(token) => text.push_str(&token),
+            Event::Finished(it) => finished = Some(it),
+        }
+    }
+    assert_eq!(text, "persephone paris");
+    assert_eq!(finished.unwrap().usage.unwrap().completion_tokens, 3);
+}
+
+#[tokio::test]
+async fn conversations_are_summarized() {
+    let mut client = client(Auth::default()).await;
+    let request = SummarizeRequest {
+        messages: vec![Message {
+            author: "user".into(),
+            message: "hello the sun".into(),
+        }],
+        summary: None,
+    };
+    let reply = client.summarize(request).await.unwrap().into_inner();
+    assert_eq!(reply.text, "persephone paris");
+
+    let status = client
+        .summarize(SummarizeRequest::default())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn texts_are_embedded_at_unit_length() {
+    let mut client = client(Auth::default()).await;
+    let request = EmbedRequest {
+        text: "hello the sun".into(),
+    };
+    let embedding = client.embed(request).await.unwrap().into_inner().embedding;
+    assert_eq!(embedding.len(), VOCAB_SIZE);
+    let norm: f32 = embedding.iter().map(|it| it * it).sum::<f32>().sqrt();
+    assert!((norm - 1.0).abs() < 1e-5, "{norm}");
+}
+
+#[tokio::test]
+async fn keys_come_in_metadata() {
+    let settings = AuthSettings {
+        keys: vec![KeySettings {
+            name: "alice".into(),
+            sha256: hash_key("sk-secret"),
+            requests_per_minute: None,
+            tokens_per_minute: None,
+            monthly_tokens: None,
+            admin: false,
+        }],
+    };
+    let mut client = client(Auth::new(&settings, &Usage::in_memory().unwrap())).await;
+    let status = client.generate(question("hello")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    let mut request = Request::new(question("hello"));
+    request
+        .metadata_mut()
+        .insert("authorization", "Bearer sk-secret".parse().unwrap());
+    let reply = client.generate(request).await.unwrap().into_inner();
+    assert_eq!(reply.text, "persephone paris");
+}
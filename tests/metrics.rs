@@ -0,0 +1,72 @@
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use common::{config, tokenizer, ScriptedModel, EOS, PARIS, PERSEPHONE};
+use futures_util::{lock::Mutex, StreamExt};
+use persephone::{
+    assistant::{AnswerOptions, Assistant},
+    metrics::{metrics, Operation},
+    server::subscribe,
+    shutdown::Shutdown,
+};
+
+fn storage() -> Arc<Mutex<Assistant<ScriptedModel>>> {
+    let model = ScriptedModel::new(&[PERSEPHONE, PARIS, EOS]);
+    Arc::new(Mutex::new(Assistant::new(model, tokenizer(), config())))
+}
+
+// The metrics are global, so this is one test rather than several racing ones
+#[tokio::test]
+async fn answers_are_counted_queued_and_timed() {
+    let metrics = metrics();
+    let summaries = metrics.requests.with_label_values(&["summarize"]);
+    let (asked, timed) = (
+        summaries.get(),
+        metrics.time_to_first_token.get_sample_count(),
+    );
+    let tokens = metrics.generated_tokens.get_sample_sum();
+    let waits = metrics.queue_wait.with_label_values(&["interactive"]);
+    let waited = waits.get_sample_count();
+
+    let storage = storage();
+    let held = storage.lock().await;
+    let options = AnswerOptions {
+        operation: Operation::Summarize,
+        ..Default::default()
+    };
+    let answer = subscribe(
+        storage.clone(),
+        "hello".into(),
+        options,
+        &Shutdown::default(),
+    );
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(metrics.queue_depth.get(), 1);
+    assert_eq!(summaries.get(), asked);
+
+    drop(held);
+    let answer: Vec<_> = answer.collect().await;
+    assert!(answer.iter().all(|it| it.is_ok()));
+    assert_eq!(metrics.queue_depth.get(), 0);
+    assert_eq!(metrics.active_generations.get(), 0);
+    assert_eq!(summaries.get(), asked + 1);
+    assert_eq!(metrics.time_to_first_token.get_sample_count(), timed + 1);
+    assert_eq!(metrics.generated_tokens.get_sample_sum(), tokens + 3.0);
+    assert_eq!(waits.get_sample_count(), waited + 1);
+    assert!(waits.get_sample_sum() >= 0.05);
+
+    let text = metrics.render().unwrap();
+    for name in [
+        "persephone_requests_total{operation=\"summarize\"}",
+        "persephone_queue_depth",
+        "persephone_queue_wait_seconds_bucket{priority=\"interactive\"",
+        "persephone_active_generations",
+        "persephone_time_to_first_token_seconds_bucket",
+        "persephone_request_duration_seconds_bucket",
+        "persephone_generated_tokens_bucket",
+        "persephone_decode_tokens_per_second_bucket",
+    ] {
+        assert!(text.contains(name), "{name} in {text}");
+    }
+}
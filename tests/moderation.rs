@@ -0,0 +1,268 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::{config, tokenizer, ScriptedModel, EOS, PARIS, PERSEPHONE};
+use futures_util::{lock::Mutex, StreamExt};
+use persephone::{
+    assistant::{AnswerOptions, Assistant},
+    error::{Error, Result},
+    moderation::{
+        Action, Classifier, ClassifierSettings, Decision, Decisions, ModerationSettings, Moderator,
+        OutputFilter, RuleSettings, Stage,
+    },
+    readiness::Loadable,
+    server::{moderated, subscribe, AnswerEvent},
+    shutdown::Shutdown,
+};
+
+fn rule(name: &str, words: &[&str], pattern: Option<&str>, action: Action) -> RuleSettings {
+    RuleSettings {
+        name: name.into(),
+        words: words.iter().map(|it| it.to_string()).collect(),
+        pattern: pattern.map(Into::into),
+        action,
+        on: vec![Stage::Input, Stage::Output],
+    }
+}
+
+fn moderator(rules: Vec<RuleSettings>) -> Moderator {
+    let settings = ModerationSettings {
+        rules,
+        hold_back: 8,
+        ..Default::default()
+    };
+    Moderator::new(&settings, None).unwrap()
+}
+
+/// Feeds the tokens through the filter, returning what's released and whether
+/// it stopped.
+async fn run(filter: &mut OutputFilter, tokens: &[&str]) -> (String, bool) {
+    let mut text = String::new();
+    for token in tokens {
+        let released = filter.push(token).await.unwrap();
+        text.push_str(&released.text);
+        if released.stop {
+            return (text, true);
+        }
+    }
+    let released = filter.finish().await.unwrap();
+    text.push_str(&released.text);
+    (text, released.stop)
+}
+
+fn decision(rule: &str, stage: Stage, action: Action) -> Decision {
+    Decision {
+        rule: rule.into(),
+        stage,
+        action,
+    }
+}
+
+#[tokio::test]
+async fn prompts_are_refused_or_redacted() {
+    let moderator = moderator(vec![
+        rule("blocklist", &["Forbidden Word"], None, Action::Refuse),
+        rule("email", &[], Some(r"\S+@\S+\.\w+"), Action::Redact),
+    ]);
+    let decisions = Decisions::default();
+    let text = moderator
+        .input("mail me at me@example.com or you@example.org", &decisions)
+        .await
+        .unwrap();
+    assert_eq!(text, "mail me at [redacted] or [redacted]");
+    assert_eq!(
+        decisions.take(),
+        vec![
+            decision("email", Stage::Input, Action::Redact),
+            decision("email", Stage::Input, Action::Redact),
+        ]
+    );
+
+    let refused = moderator.input("say the forbidden word", &decisions).await;
+    assert!(matches!(refused, Err(Error::Refused(rule)) if rule == "blocklist"));
+    // Words match whole
+    assert!(moderator
+        .input("forbidden wordsmith", &decisions)
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn answers_are_redacted_across_tokens() {
+    let moderator = moderator(vec![rule(
+        "phone",
+        &[],
+        Some(r"\d{3}-\d{4}"),
+        Action::Redact,
+    )]);
+    let decisions = Decisions::default();
+    let mut filter = moderator.output(&decisions);
+    let (text, stopped) = run(&mut filter, &["call ", "55", "5-12", "34", " now"]).await;
+    assert_eq!(text, "call [redacted] now");
+    assert!(!stopped);
+    assert_eq!(
+        decisions.take(),
+        vec![decision("phone", Stage::Output, Action::Redact)]
+    );
+
+    // Matches can still grow until the answer ends
+    let mut filter = moderator.output(&decisions);
+    assert_eq!(run(&mut filter, &["555-", "1234"]).await.0, "[redacted]");
+}
+
+#[tokio::test]
+async fn answers_stop_before_what_matched() {
+    let moderator = moderator(vec![rule("secret", &["password"], None, Action::Stop)]);
+    let decisions = Decisions::default();
+    let mut filter = moderator.output(&decisions);
+    let (text, stopped) = run(&mut filter, &["the ", "pass", "word ", "is ", "hunter2"]).await;
+    assert_eq!(text, "the ");
+    assert!(stopped);
+    assert!(filter.push("more").await.unwrap().stop);
+    assert_eq!(
+        decisions.take(),
+        vec![decision("secret", Stage::Output, Action::Stop)]
+    );
+}
+
+/// Flags any text that shouts.
+struct Shouting;
+
+impl Classifier for Shouting {
+    fn classify(&mut self, text: &str) -> Result<Vec<(String, f32)>> {
+        let shouting = text.contains('!') as u8 as f32;
+        Ok(vec![
+            ("calm".into(), 1.0 - shouting),
+            ("angry".into(), shouting),
+        ])
+    }
+}
+
+#[tokio::test]
+async fn the_classifier_refuses_prompts_and_stops_answers() {
+    let settings = ModerationSettings {
+        classifier: Some(ClassifierSettings {
+            repo: "unused".into(),
+            labels: vec!["angry".into()],
+            threshold: 0.8,
+            action: Action::Stop,
+            on: vec![Stage::Input, Stage::Output],
+        }),
+        hold_back: 4,
+        ..Default::default()
+    };
+    let moderator = Moderator::new(&settings, Some(Box::new(Shouting))).unwrap();
+    let decisions = Decisions::default();
+    assert!(moderator.input("hello there", &decisions).await.is_ok());
+    assert!(matches!(
+        moderator.input("hello there!", &decisions).await,
+        Err(Error::Refused(label)) if label == "angry"
+    ));
+
+    let mut filter = moderator.output(&decisions);
+    let (text, stopped) = run(&mut filter, &["Fine. ", "Go ", "away! ", "Now."]).await;
+    assert!(stopped);
+    // The flagged sentence is held back, as far as it still can be
+    assert_eq!(text, "Fine.");
+    assert_eq!(
+        decisions.take(),
+        vec![
+            decision("angry", Stage::Input, Action::Stop),
+            decision("angry", Stage::Output, Action::Stop),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn prompts_wait_for_the_classifier_to_load() {
+    let settings = ModerationSettings {
+        classifier: Some(ClassifierSettings {
+            repo: "unused".into(),
+            labels: vec![],
+            threshold: 0.8,
+            action: Action::Refuse,
+            on: vec![Stage::Input],
+        }),
+        ..Default::default()
+    };
+    let loaded = Loadable::spawn("classifier", || {
+        Ok(Box::new(Shouting) as Box<dyn Classifier>)
+    });
+    let moderator = Moderator::loading(&settings, Some(loaded)).unwrap();
+    let decisions = Decisions::default();
+    assert!(matches!(
+        moderator.input("go away!", &decisions).await,
+        Err(Error::Refused(label)) if label == "angry"
+    ));
+
+    let failed = Loadable::spawn("classifier", || {
+        Err::<Box<dyn Classifier>, _>(Error::Config("no such repo".into()))
+    });
+    let moderator = Moderator::loading(&settings, Some(failed)).unwrap();
+    assert!(matches!(
+        moderator.input("hello", &decisions).await,
+        Err(Error::ModelLoad(_))
+    ));
+}
+
+#[test]
+fn invalid_rules_are_refused() {
+    for settings in [
+        ModerationSettings {
+            rules: vec![rule("broken", &[], Some("(unclosed"), Action::Redact)],
+            ..Default::default()
+        },
+        ModerationSettings {
+            rules: vec![rule("neither", &[], None, Action::Redact)],
+            ..Default::default()
+        },
+        ModerationSettings {
+            classifier: Some(ClassifierSettings {
+                repo: "unused".into(),
+                labels: vec![],
+                threshold: 0.5,
+                action: Action::Redact,
+                on: vec![Stage::Output],
+            }),
+            ..Default::default()
+        },
+    ] {
+        assert!(matches!(settings.validate(), Err(Error::Config(_))));
+    }
+}
+
+#[tokio::test]
+async fn answers_end_with_what_moderation_did() {
+    let model = ScriptedModel::new(&[PERSEPHONE, PARIS, EOS]);
+    let storage = Arc::new(Mutex::new(Assistant::new(model, tokenizer(), config())));
+    let moderator = moderator(vec![rule("cities", &["paris"], None, Action::Redact)]);
+    let decisions = Decisions::default();
+    let tokens = subscribe(
+        storage,
+        "hello".into(),
+        AnswerOptions::default(),
+        &Shutdown::default(),
+    );
+    let events: Vec<_> = moderated(tokens, moderator.output(&decisions), decisions)
+        .map(|it| it.unwrap())
+        .collect()
+        .await;
+    let (last, tokens) = events.split_last().unwrap();
+    let text: String = tokens
+        .iter()
+        .map(|it| match it {
+            AnswerEvent::Token(token) => token.text.as_str(),
+            AnswerEvent::Finished(_) => panic!("finished early"),
+        })
+        .collect();
+    assert_eq!(text, "persephone [redacted]");
+    let AnswerEvent::Finished(finished) = last else {
+        panic!("expected the answer to finish");
+    };
+    assert!(!finished.stopped);
+    assert_eq!(
+        finished.moderation,
+        vec![decision("cities", Stage::Output, Action::Redact)]
+    );
+}
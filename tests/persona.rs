@@ -0,0 +1,140 @@
+use std::{fs, path::PathBuf};
+
+use persephone::error::Error;
+use persephone::persona::{Persona, PersonaSettings, Personas};
+use persephone::server::Message;
+
+const HADES: &str = r#"
+name = "Hades"
+description = "Grumpy"
+system = "You are Hades. You are grumpy."
+
+[sampling]
+temperature = 0.2
+
+[[examples]]
+user = "How are you?"
+assistant = "Underground."
+"#;
+
+fn directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("persephone-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+fn settings(directory: PathBuf) -> PersonaSettings {
+    PersonaSettings {
+        directory: Some(directory),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn persephone_is_built_in() {
+    let personas = Personas::load(&PersonaSettings::default()).unwrap();
+    let persephone = personas.get(None).unwrap();
+    assert_eq!(persephone.name, "Persephone");
+    let ids: Vec<_> = personas.list().into_iter().map(|(id, _)| id).collect();
+    assert_eq!(ids, ["persephone"]);
+}
+
+#[test]
+fn examples_come_before_the_question() {
+    let hades = Persona::parse(HADES).unwrap();
+    assert_eq!(hades.sampling.unwrap().temperature, 0.2);
+    let prompt = hades.ask("Hello?", &[], None);
+    assert_eq!(
+        prompt,
+        "<|im_start|>system\nYou are Hades. You are grumpy.<|im_end|>\n\
+         <|im_start|>user\nHow are you?<|im_end|>\n\
+         <|im_start|>assistant\nUnderground.<|im_end|>\n\
+         <|im_start|>user\nHello?<|im_end|>\n\
+         <|im_start|>assistant\n"
+    );
+}
+
+#[test]
+fn history_is_told_apart_by_author() {
+    let hades = Persona::parse(HADES).unwrap();
+    let message = |author: &str, message: &str| Message {
+        author: author.into(),
+        message: message.into(),
+    };
+    let history = [
+        message("user", "Hi"),
+        message("hades", "Go away."),
+        message("user", "Why?"),
+        message("assistant", "It's dark."),
+    ];
+    let prompt = hades.ask("Hello?", &history, None);
+    assert!(prompt.ends_with(
+        "<|im_start|>user\nHi<|im_end|>\n\
+         <|im_start|>assistant\nGo away.<|im_end|>\n\
+         <|im_start|>user\nWhy?<|im_end|>\n\
+         <|im_start|>assistant\nIt's dark.<|im_end|>\n\
+         <|im_start|>user\nHello?<|im_end|>\n\
+         <|im_start|>assistant\n"
+    ));
+}
+
+#[test]
+fn personas_are_picked_by_file_name() {
+    let directory = directory("picked");
+    fs::write(directory.join("hades.toml"), HADES).unwrap();
+    fs::write(directory.join("notes.txt"), "not a persona").unwrap();
+    let personas = Personas::load(&settings(directory.clone())).unwrap();
+    assert_eq!(personas.get(Some("hades")).unwrap().name, "Hades");
+    assert_eq!(personas.get(None).unwrap().name, "Persephone");
+    assert!(matches!(
+        personas.get(Some("zeus")),
+        Err(Error::InvalidRequest(_))
+    ));
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn changed_files_are_reloaded() {
+    let directory = directory("reloaded");
+    let path = directory.join("hades.toml");
+    fs::write(&path, HADES).unwrap();
+    let personas = Personas::load(&settings(directory.clone())).unwrap();
+    assert!(!personas.reload().unwrap());
+
+    fs::write(&path, HADES.replace("Grumpy", "Very grumpy indeed")).unwrap();
+    assert!(personas.reload().unwrap());
+    assert_eq!(
+        personas.get(Some("hades")).unwrap().description,
+        "Very grumpy indeed"
+    );
+
+    // A broken file leaves the personas as they were
+    fs::write(&path, "system = ").unwrap();
+    assert!(matches!(personas.reload(), Err(Error::Config(_))));
+    assert_eq!(
+        personas.get(Some("hades")).unwrap().description,
+        "Very grumpy indeed"
+    );
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn the_default_persona_must_exist() {
+    let settings = PersonaSettings {
+        default: "zeus".into(),
+        ..Default::default()
+    };
+    assert!(matches!(Personas::load(&settings), Err(Error::Config(_))));
+}
+
+#[test]
+fn invalid_sampling_is_refused() {
+    for sampling in ["temperature = 0.0", "top_p = 1.5"] {
+        let toml = HADES.replace("temperature = 0.2", sampling);
+        assert!(
+            matches!(Persona::parse(&toml), Err(Error::Config(_))),
+            "{sampling} was accepted"
+        );
+    }
+}
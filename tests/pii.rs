@@ -0,0 +1,193 @@
+use std::ops::Range;
+
+use persephone::{
+    error::{Error, Result},
+    moderation::{Decisions, ModerationSettings, Moderator},
+    pii::{scrub, Kind, Masker, PiiSettings, Placeholders, Recognizer},
+    readiness::Loadable,
+};
+
+fn masker() -> Masker {
+    let settings = PiiSettings {
+        mask_prompts: true,
+        ..Default::default()
+    };
+    Masker::new(&settings, None)
+}
+
+async fn kinds(masker: &Masker, text: &str) -> Vec<(String, Kind)> {
+    masker
+        .find(text)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(span, kind)| (text[span].to_string(), kind))
+        .collect()
+}
+
+#[tokio::test]
+async fn each_kind_is_found() {
+    let masker = masker();
+    let text = "Mail ana.b+x@example.co.uk or call +44 20 7946 0958, \
+                pay with 4111 1111 1111 1111 from 192.168.0.1 or 2001:db8::1";
+    assert_eq!(
+        kinds(&masker, text).await,
+        vec![
+            ("ana.b+x@example.co.uk".into(), Kind::Email),
+            ("+44 20 7946 0958".into(), Kind::Phone),
+            ("4111 1111 1111 1111".into(), Kind::Card),
+            ("192.168.0.1".into(), Kind::Ip),
+            ("2001:db8::1".into(), Kind::Ip),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn card_numbers_must_pass_the_luhn_check() {
+    let masker = masker();
+    assert_eq!(
+        kinds(&masker, "card 4111111111111111").await,
+        vec![("4111111111111111".into(), Kind::Card)]
+    );
+    assert!(kinds(&masker, "order 4111111111111112")
+        .await
+        .iter()
+        .all(|(_, kind)| *kind != Kind::Card));
+}
+
+#[tokio::test]
+async fn only_the_chosen_kinds_are_found() {
+    let settings = PiiSettings {
+        kinds: vec![Kind::Ip],
+        ..Default::default()
+    };
+    let masker = Masker::new(&settings, None);
+    assert!(!masker.masks_prompts());
+    assert_eq!(
+        kinds(&masker, "me@example.com at 10.0.0.1").await,
+        vec![("10.0.0.1".into(), Kind::Ip)]
+    );
+}
+
+#[tokio::test]
+async fn placeholders_are_reused_and_put_back() {
+    let masker = masker();
+    let mut placeholders = Placeholders::default();
+    let prompt = "Write to a@example.com, b@example.com and a@example.com";
+    let masked = masker.mask(prompt, &mut placeholders).await.unwrap();
+    assert_eq!(masked, "Write to <EMAIL_1>, <EMAIL_2> and <EMAIL_1>");
+    // Later messages share the placeholders
+    let masked = masker
+        .mask("from b@example.com, 10.0.0.1", &mut placeholders)
+        .await
+        .unwrap();
+    assert_eq!(masked, "from <EMAIL_2>, <IP_1>");
+    assert_eq!(
+        placeholders.unmask("Sent <EMAIL_1> a note, cc <EMAIL_2>."),
+        "Sent a@example.com a note, cc b@example.com."
+    );
+}
+
+#[tokio::test]
+async fn answers_are_unmasked_across_tokens() {
+    let masker = masker();
+    let mut placeholders = Placeholders::default();
+    masker
+        .mask("me@example.com", &mut placeholders)
+        .await
+        .unwrap();
+    let mut unmasker = placeholders.unmasker();
+    let mut text = String::new();
+    for token in ["Hi ", "<EM", "AIL", "_1", ">", ", 1 < 2", " and more"] {
+        text.push_str(&unmasker.push(token));
+    }
+    assert_eq!(text, "Hi me@example.com, 1 < 2 and more");
+    // What looked like a placeholder is let go when the answer ends
+    assert_eq!(unmasker.push("see <EMA"), "see ");
+    assert_eq!(unmasker.finish(), "<EMA");
+}
+
+/// Finds capitalised words after "Dear".
+struct Letters;
+
+impl Recognizer for Letters {
+    fn names(&mut self, text: &str) -> Result<Vec<Range<usize>>> {
+        Ok(text
+            .match_indices("Dear ")
+            .map(|(i, it)| {
+                let start = i + it.len();
+                let end = text[start..]
+                    .find(|c: char| !c.is_alphabetic())
+                    .map_or(text.len(), |it| start + it);
+                start..end
+            })
+            .collect())
+    }
+}
+
+#[tokio::test]
+async fn names_are_found_by_the_recognizer() {
+    let settings = PiiSettings {
+        mask_prompts: true,
+        ..Default::default()
+    };
+    let recognizing = Masker::new(&settings, Some(Box::new(Letters)));
+    let mut placeholders = Placeholders::default();
+    let masked = recognizing
+        .mask("Dear Ada, Dear Grace, Dear Ada", &mut placeholders)
+        .await
+        .unwrap();
+    assert_eq!(masked, "Dear <NAME_1>, Dear <NAME_2>, Dear <NAME_1>");
+    // Without one, names aren't looked for
+    assert!(masker().find("Dear Ada").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn names_wait_for_the_recognizer_to_load() {
+    let settings = PiiSettings {
+        mask_prompts: true,
+        ..Default::default()
+    };
+    let loaded = Loadable::spawn("ner", || Ok(Box::new(Letters) as Box<dyn Recognizer>));
+    let masker = Masker::loading(&settings, Some(loaded));
+    assert_eq!(
+        kinds(&masker, "Dear Ada").await,
+        [("Ada".to_string(), Kind::Name)]
+    );
+
+    let failed = Loadable::spawn("ner", || {
+        Err::<Box<dyn Recognizer>, _>(Error::Config("no such repo".into()))
+    });
+    let masker = Masker::loading(&settings, Some(failed));
+    assert!(matches!(
+        masker.find("Dear Ada").await,
+        Err(Error::ModelLoad(_))
+    ));
+}
+
+#[test]
+fn logs_are_scrubbed_for_good() {
+    assert_eq!(
+        scrub("from me@example.com at 10.0.0.1, card 4111-1111-1111-1111"),
+        "from <EMAIL> at <IP>, card <CARD>"
+    );
+    assert_eq!(scrub("nothing to hide"), "nothing to hide");
+}
+
+#[tokio::test]
+async fn moderated_answers_are_unmasked() {
+    let moderator = Moderator::new(&ModerationSettings::default(), None).unwrap();
+    let mut placeholders = Placeholders::default();
+    masker()
+        .mask("+1 555 010 9999", &mut placeholders)
+        .await
+        .unwrap();
+    let decisions = Decisions::default();
+    let mut filter = moderator.output(&decisions).unmasking(&placeholders);
+    let mut text = String::new();
+    for token in ["Calling <PH", "ONE_1> now"] {
+        text.push_str(&filter.push(token).await.unwrap().text);
+    }
+    text.push_str(&filter.finish().await.unwrap().text);
+    assert_eq!(text, "Calling +1 555 010 9999 now");
+}
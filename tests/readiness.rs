@@ -0,0 +1,48 @@
+use std::sync::{mpsc, Arc};
+
+use futures_util::lock::Mutex;
+use persephone::{
+    error::Error,
+    readiness::{LoadState, Loadable, Readiness},
+};
+
+#[tokio::test]
+async fn requests_wait_for_models_to_load() {
+    let (go, wait) = mpsc::channel::<()>();
+    let model = Loadable::spawn("model", move || {
+        wait.recv().unwrap();
+        Ok(42)
+    });
+    assert_eq!(model.readiness().state, LoadState::Loading);
+    assert!(!Readiness::of(vec![model.readiness()]).ready);
+
+    let waiting = tokio::spawn({
+        let model = model.clone();
+        async move { *model.wait().await.unwrap().lock().await }
+    });
+    go.send(()).unwrap();
+    assert_eq!(waiting.await.unwrap(), 42);
+    assert_eq!(model.readiness().state, LoadState::Ready);
+    assert!(Readiness::of(vec![model.readiness()]).ready);
+}
+
+#[tokio::test]
+async fn failing_to_load_is_reported() {
+    let model: Loadable<u32> = Loadable::spawn("model", || Err(Error::Config("no weights".into())));
+    let result = model.wait().await;
+    assert!(matches!(result, Err(Error::ModelLoad(_))));
+    let readiness = model.readiness();
+    assert_eq!(readiness.state, LoadState::Failed);
+    assert!(readiness.error.unwrap().contains("no weights"));
+}
+
+#[tokio::test]
+async fn loaded_models_are_ready() {
+    let voice = Loadable::ready("voice", Arc::new(Mutex::new("hello")));
+    let model: Loadable<u32> = Loadable::spawn("model", || panic!("out of memory"));
+    assert!(matches!(model.wait().await, Err(Error::ModelLoad(_))));
+    let readiness = Readiness::of(vec![voice.readiness(), model.readiness()]);
+    assert!(!readiness.ready);
+    assert_eq!(readiness.models[0].state, LoadState::Ready);
+    assert_eq!(readiness.models[1].state, LoadState::Failed);
+}
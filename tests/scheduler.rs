@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use persephone::scheduler::{Priority, Scheduler, SchedulerSettings};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// Waits until `n` answers are waiting for a turn.
+async fn waiting(scheduler: &Scheduler, n: usize) {
+    while scheduler.waiting() < n {
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Queues the `n`th answer, which sends `n` once it has its turn.
+async fn queue(
+    scheduler: &Scheduler,
+    tx: &UnboundedSender<usize>,
+    n: usize,
+    priority: Priority,
+    key: Option<&'static str>,
+) {
+    let (waiter, tx) = (scheduler.clone(), tx.clone());
+    let queued = scheduler.waiting() + 1;
+    tokio::spawn(async move {
+        let _turn = waiter.turn(priority, key).await;
+        tx.send(n).unwrap();
+    });
+    waiting(scheduler, queued).await;
+}
+
+async fn order(rx: &mut UnboundedReceiver<usize>, n: usize) -> Vec<usize> {
+    let mut order = vec![];
+    for _ in 0..n {
+        order.push(rx.recv().await.unwrap());
+    }
+    order
+}
+
+#[tokio::test]
+async fn interactive_answers_go_first_then_background_then_batch() {
+    let scheduler = Scheduler::default();
+    let first = scheduler.turn(Priority::Interactive, None).await;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let priorities = [
+        Priority::Batch,
+        Priority::Background,
+        Priority::Interactive,
+        Priority::Batch,
+    ];
+    for (n, priority) in priorities.into_iter().enumerate() {
+        queue(&scheduler, &tx, n, priority, None).await;
+    }
+    drop(first);
+    assert_eq!(order(&mut rx, 4).await, [2, 1, 0, 3]);
+}
+
+#[tokio::test]
+async fn keys_take_turns() {
+    let scheduler = Scheduler::default();
+    let first = scheduler.turn(Priority::Interactive, Some("carol")).await;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let keys = ["alice", "alice", "alice", "bob", "bob"];
+    for (n, key) in keys.into_iter().enumerate() {
+        queue(&scheduler, &tx, n, Priority::Interactive, Some(key)).await;
+    }
+    drop(first);
+    assert_eq!(order(&mut rx, 5).await, [0, 3, 1, 4, 2]);
+}
+
+#[tokio::test]
+async fn answers_that_wait_long_enough_are_promoted() {
+    let settings = SchedulerSettings {
+        promote_after_seconds: 1,
+    };
+    let scheduler = Scheduler::new(&settings);
+    let first = scheduler.turn(Priority::Interactive, None).await;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    queue(&scheduler, &tx, 0, Priority::Batch, None).await;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    queue(&scheduler, &tx, 1, Priority::Background, None).await;
+    drop(first);
+    assert_eq!(order(&mut rx, 2).await, [0, 1]);
+}
+
+#[tokio::test]
+async fn waiters_that_leave_give_up_their_place() {
+    let scheduler = Scheduler::default();
+    let first = scheduler.turn(Priority::Interactive, None).await;
+    let waiter = tokio::spawn({
+        let scheduler = scheduler.clone();
+        async move {
+            let _turn = scheduler.turn(Priority::Interactive, None).await;
+        }
+    });
+    waiting(&scheduler, 1).await;
+    waiter.abort();
+    drop(first);
+    let turn = tokio::time::timeout(
+        Duration::from_secs(5),
+        scheduler.turn(Priority::Batch, None),
+    )
+    .await;
+    assert!(turn.is_ok());
+}
@@ -0,0 +1,120 @@
+mod common;
+
+use std::sync::Arc;
+
+use async_graphql::Value;
+use common::{config, tokenizer, ScriptedModel, EOS, PARIS, PERSEPHONE};
+use futures_util::{lock::Mutex, StreamExt};
+use persephone::assistant::{AnswerOptions, Assistant, Meter, Tally};
+use persephone::error::Error;
+use persephone::moderation::{Decisions, ModerationSettings, Moderator};
+use persephone::persona::{Persona, PersonaSettings, Personas};
+use persephone::server::{answer_once, moderated, subscribe, Message};
+use persephone::shutdown::Shutdown;
+
+fn messages() -> Vec<Message> {
+    vec![
+        Message {
+            author: "user".into(),
+            message: "Where do you live?".into(),
+        },
+        Message {
+            author: "persephone".into(),
+            message: "In Paris, in a house full of sun.".into(),
+        },
+    ]
+}
+
+#[test]
+fn questions_carry_the_conversation_so_far() {
+    let prompt = Persona::default().ask(
+        "Do you like it there?",
+        &messages(),
+        Some("We talked about home".into()),
+    );
+    assert!(prompt.contains(
+        "<|im_start|>user\nWhere do you live?<|im_end|>\n\
+         <|im_start|>assistant\nIn Paris, in a house full of sun.<|im_end|>\n"
+    ));
+    assert!(prompt.contains("\"We talked about home\""));
+    assert!(prompt.contains("Do you like it there?<|im_end|>"));
+    assert!(!prompt.contains("{{"));
+}
+
+#[test]
+fn summaries_need_a_conversation() {
+    let personas = Personas::load(&PersonaSettings::default()).unwrap();
+    let prompt = personas.summarize(&messages(), None).unwrap();
+    assert!(prompt.contains("Where do you live?\nIn Paris"));
+    assert!(!prompt.contains("{{"));
+    assert!(matches!(
+        personas.summarize(&[], None),
+        Err(Error::InvalidRequest(_))
+    ));
+}
+
+#[tokio::test]
+async fn subscriptions_stream_the_answer() {
+    let model = ScriptedModel::new(&[PERSEPHONE, PARIS, EOS]);
+    let storage = Arc::new(Mutex::new(Assistant::new(model, tokenizer(), config())));
+    let tokens: Vec<_> = subscribe(
+        storage,
+        "hello".into(),
+        AnswerOptions::default(),
+        &Shutdown::default(),
+    )
+    .map(|it| it.unwrap())
+    .collect()
+    .await;
+    assert_eq!(tokens.concat(), "persephone paris");
+}
+
+#[tokio::test]
+async fn subscriptions_report_errors_with_a_code() {
+    let mut config = config();
+    config.max_position_embeddings = 1;
+    let model = ScriptedModel::new(&[PERSEPHONE]);
+    let storage = Arc::new(Mutex::new(Assistant::new(model, tokenizer(), config)));
+    let results: Vec<_> = subscribe(
+        storage,
+        "hello".into(),
+        AnswerOptions::default(),
+        &Shutdown::default(),
+    )
+    .collect()
+    .await;
+    let [Err(error)] = results.as_slice() else {
+        panic!("expected a single error");
+    };
+    let code = error.extensions.as_ref().and_then(|it| it.get("code"));
+    assert_eq!(code, Some(&Value::from("CONTEXT_OVERFLOW")));
+}
+
+#[tokio::test]
+async fn answers_can_be_returned_whole() {
+    let model = ScriptedModel::new(&[PERSEPHONE, PARIS, EOS]);
+    let storage = Arc::new(Mutex::new(Assistant::new(model, tokenizer(), config())));
+    let wrapped = Arc::new(Tally::default());
+    let tally = Arc::new(Tally::new(Some(wrapped.clone() as Arc<dyn Meter>)));
+    let options = AnswerOptions {
+        meter: Some(tally.clone()),
+        ..Default::default()
+    };
+    let tokens = subscribe(storage, "hello".into(), options, &Shutdown::default());
+    let moderator = Moderator::new(&ModerationSettings::default(), None).unwrap();
+    let decisions = Decisions::default();
+    let events = moderated(tokens, moderator.output(&decisions), decisions);
+    let answer = answer_once(events, &tally).await.unwrap();
+    assert_eq!(answer.text, "persephone paris");
+    assert!(!answer.stopped);
+    assert!(answer.moderation.is_empty());
+    assert_eq!(answer.usage.completion_tokens, 3);
+    assert!(answer.usage.prompt_tokens > 0);
+    assert_eq!(
+        answer.usage.total_tokens,
+        answer.usage.prompt_tokens + answer.usage.completion_tokens
+    );
+    // The meter it wraps is told too
+    assert_eq!(wrapped.generated_tokens(), 3);
+    assert_eq!(wrapped.prompt_tokens() as u64, answer.usage.prompt_tokens);
+}
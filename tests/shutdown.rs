@@ -0,0 +1,95 @@
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use async_graphql::Value;
+use common::{config, tokenizer, ScriptedModel, EOS, PARIS, PERSEPHONE};
+use futures_util::{lock::Mutex, StreamExt};
+use persephone::{
+    assistant::{AnswerOptions, Assistant},
+    error::Error,
+    server::subscribe,
+    shutdown::Shutdown,
+};
+
+fn storage() -> Arc<Mutex<Assistant<ScriptedModel>>> {
+    let model = ScriptedModel::new(&[PERSEPHONE, PARIS, EOS]);
+    Arc::new(Mutex::new(Assistant::new(model, tokenizer(), config())))
+}
+
+fn code(error: &async_graphql::Error) -> Option<&Value> {
+    error.extensions.as_ref().and_then(|it| it.get("code"))
+}
+
+#[tokio::test]
+async fn no_answers_start_once_shutting_down() {
+    let shutdown = Shutdown::default();
+    shutdown.begin();
+    assert!(matches!(shutdown.admit(), Err(Error::ShuttingDown)));
+    let results: Vec<_> = subscribe(
+        storage(),
+        "hello".into(),
+        AnswerOptions::default(),
+        &shutdown,
+    )
+    .collect()
+    .await;
+    assert_eq!(results.len(), 1);
+    let error = results[0].as_ref().unwrap_err();
+    assert_eq!(code(error), Some(&Value::from("SHUTTING_DOWN")));
+    assert_eq!(error.message, "server shutting down");
+    assert_eq!(shutdown.active(), 0);
+}
+
+#[tokio::test]
+async fn answers_going_are_let_finish() {
+    let shutdown = Shutdown::default();
+    let storage = storage();
+    let busy = storage.lock().await;
+    let answer = subscribe(
+        storage.clone(),
+        "hello".into(),
+        AnswerOptions::default(),
+        &shutdown,
+    );
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(shutdown.active(), 1);
+    shutdown.begin();
+    let drained = tokio::time::timeout(Duration::from_millis(50), shutdown.drained()).await;
+    assert!(drained.is_err(), "drained with an answer still going");
+
+    drop(busy);
+    let tokens: Vec<_> = answer.collect().await;
+    assert!(tokens.iter().all(|it| it.is_ok()));
+    assert_eq!(
+        tokens.into_iter().map(Result::unwrap).collect::<String>(),
+        "persephone paris"
+    );
+    tokio::time::timeout(Duration::from_secs(1), shutdown.drained())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn answers_past_the_deadline_are_told_why_they_stopped() {
+    let shutdown = Shutdown::default();
+    let storage = storage();
+    let _busy = storage.lock().await;
+    let answer = subscribe(
+        storage.clone(),
+        "hello".into(),
+        AnswerOptions::default(),
+        &shutdown,
+    );
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    shutdown.begin();
+    shutdown.stop();
+    let results: Vec<_> = tokio::time::timeout(Duration::from_secs(1), answer.collect())
+        .await
+        .unwrap();
+    let error = results.last().unwrap().as_ref().unwrap_err();
+    assert_eq!(code(error), Some(&Value::from("SHUTTING_DOWN")));
+    tokio::time::timeout(Duration::from_secs(1), shutdown.drained())
+        .await
+        .unwrap();
+}
@@ -0,0 +1,126 @@
+use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::llama::Config;
+use persephone::llama::{Cache, Llama};
+use persephone::speculative::{PromptLookup, Sampler};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+fn tiny() -> (Llama, Config) {
+    let config = Config {
+        hidden_size: 16,
+        intermediate_size: 32,
+        vocab_size: 10,
+        num_hidden_layers: 2,
+        num_attention_heads: 4,
+        num_key_value_heads: 2,
+        use_flash_attn: false,
+        rms_norm_eps: 1e-5,
+        rope_theta: 10000.0,
+        bos_token_id: None,
+        eos_token_id: None,
+        rope_scaling: None,
+        max_position_embeddings: 64,
+        tie_word_embeddings: true,
+    };
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    (Llama::load(vb, &config).unwrap(), config)
+}
+
+fn input(tokens: &[u32]) -> Tensor {
+    Tensor::new(tokens, &Device::Cpu)
+        .unwrap()
+        .unsqueeze(0)
+        .unwrap()
+}
+
+fn assert_close(a: &Tensor, b: &Tensor) {
+    let diff = (a - b)
+        .unwrap()
+        .abs()
+        .unwrap()
+        .max_all()
+        .unwrap()
+        .to_scalar::<f32>()
+        .unwrap();
+    assert!(diff < 1e-4, "logits differ by {diff}");
+}
+
+#[test]
+fn verifying_in_one_pass_matches_decoding_one_token_at_a_time() {
+    let (model, config) = tiny();
+    let tokens = [1, 2, 3, 4, 5, 6, 7];
+
+    let mut cache = Cache::new(DType::F32, &config, &Device::Cpu).unwrap();
+    let mut expected = vec![model.forward(&input(&tokens[..3]), &mut cache).unwrap()];
+    for token in &tokens[3..] {
+        expected.push(model.forward(&input(&[*token]), &mut cache).unwrap());
+    }
+
+    let mut cache = Cache::new(DType::F32, &config, &Device::Cpu).unwrap();
+    model.forward(&input(&tokens[..2]), &mut cache).unwrap();
+    // Roll back a rejected proposal before verifying the rest in one pass
+    model.forward(&input(&[9]), &mut cache).unwrap();
+    cache.truncate(2).unwrap();
+    let logits = model
+        .forward_last(&input(&tokens[2..]), 4, &mut cache)
+        .unwrap();
+    assert_eq!(cache.len(), tokens.len());
+    assert_eq!(logits.dim(1).unwrap(), 4);
+    for (i, expected) in expected[1..].iter().enumerate() {
+        assert_close(&logits.i((.., i, ..)).unwrap(), expected);
+    }
+}
+
+#[test]
+fn acceptance_preserves_the_target_distribution() {
+    let target = vec![0.7, 0.2, 0.1];
+    let draft = [vec![0.1, 0.3, 0.6]];
+    let mut sampler = Sampler::new(42, 1.0, 1.0);
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut counts = [0usize; 3];
+    let n = 20_000;
+    for _ in 0..n {
+        let r: f32 = rng.gen();
+        let proposal = if r < 0.1 {
+            0
+        } else if r < 0.4 {
+            1
+        } else {
+            2
+        };
+        let rows = [target.clone(), target.clone()];
+        let (accepted, next) = sampler.verify(&[proposal], &rows, Some(&draft)).unwrap();
+        let token = if accepted == 1 { proposal } else { next };
+        counts[token as usize] += 1;
+    }
+    for (count, p) in counts.iter().zip(target) {
+        let observed = *count as f32 / n as f32;
+        assert!((observed - p).abs() < 0.02, "{observed} vs {p}");
+    }
+}
+
+#[test]
+fn certain_proposals_are_accepted_when_the_model_agrees() {
+    let mut sampler = Sampler::new(42, 1.0, 1.0);
+    let rows = [vec![0.0, 1.0], vec![1.0, 0.0], vec![0.0, 1.0]];
+    assert_eq!(sampler.verify(&[1, 0], &rows, None).unwrap(), (2, 1));
+    assert_eq!(sampler.verify(&[0, 0], &rows, None).unwrap(), (0, 1));
+}
+
+#[test]
+fn prompt_lookup_proposes_what_followed_the_latest_tokens() {
+    let lookup = PromptLookup {
+        ngram: 2,
+        lookahead: 3,
+    };
+    // The prompt is the first seven tokens
+    let tokens = [5, 1, 2, 3, 4, 6, 9, 7, 1, 2];
+    assert_eq!(lookup.propose(&tokens, 7), vec![3, 4, 6]);
+    // Falls back to shorter n-grams and stops at the end of the prompt
+    let tokens = [5, 1, 2, 3, 4, 6, 9, 8, 6];
+    assert_eq!(lookup.propose(&tokens, 7), vec![9]);
+    // Nothing to propose when the tokens never appeared
+    let tokens = [5, 1, 2, 3, 4, 6, 9, 8];
+    assert_eq!(lookup.propose(&tokens, 7), Vec::<u32>::new());
+}
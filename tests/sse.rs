@@ -0,0 +1,73 @@
+mod common;
+
+use std::sync::Arc;
+
+use axum::{
+    body::to_bytes,
+    response::{IntoResponse, Sse},
+};
+use candle_transformers::models::llama::Config;
+use common::{config, tokenizer, ScriptedModel, EOS, PARIS, PERSEPHONE};
+use futures_util::lock::Mutex;
+use persephone::{
+    assistant::{AnswerOptions, Assistant, Tally},
+    moderation::{Decisions, ModerationSettings, Moderator},
+    server::{moderated, subscribe},
+    shutdown::Shutdown,
+    sse::to_events,
+};
+
+/// The answer's events as they'd be sent.
+async fn sent(model: ScriptedModel, config: Config) -> String {
+    let storage = Arc::new(Mutex::new(Assistant::new(model, tokenizer(), config)));
+    let tally = Arc::new(Tally::default());
+    let options = AnswerOptions {
+        meter: Some(tally.clone()),
+        ..Default::default()
+    };
+    let tokens = subscribe(storage, "hello".into(), options, &Shutdown::default());
+    let moderator = Moderator::new(&ModerationSettings::default(), None).unwrap();
+    let decisions = Decisions::default();
+    let events = moderated(tokens, moderator.output(&decisions), decisions);
+    let response = Sse::new(to_events(events, tally)).into_response();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn answers_stream_as_events_ending_with_stats() {
+    let sent = sent(ScriptedModel::new(&[PERSEPHONE, PARIS, EOS]), config()).await;
+    let events: Vec<_> = sent.split_terminator("\n\n").collect();
+    let (done, tokens) = events.split_last().unwrap();
+    let text: String = tokens
+        .iter()
+        .map(|it| {
+            let data = it.strip_prefix("event: token\ndata: ").unwrap();
+            serde_json::from_str::<serde_json::Value>(data).unwrap()["text"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        })
+        .collect();
+    assert_eq!(text, "persephone paris");
+    let done: serde_json::Value =
+        serde_json::from_str(done.strip_prefix("event: done\ndata: ").unwrap()).unwrap();
+    assert_eq!(done["usage"]["completion_tokens"], 3);
+    assert!(done["usage"]["prompt_tokens"].as_u64().unwrap() > 0);
+    assert_eq!(done["stopped"], false);
+    assert_eq!(done["moderation"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn errors_end_the_stream_with_their_code() {
+    let mut config = config();
+    config.max_position_embeddings = 1;
+    let sent = sent(ScriptedModel::new(&[PERSEPHONE]), config).await;
+    let data = sent
+        .strip_prefix("event: error\ndata: ")
+        .and_then(|it| it.strip_suffix("\n\n"))
+        .unwrap();
+    let error: serde_json::Value = serde_json::from_str(data).unwrap();
+    assert_eq!(error["code"], "CONTEXT_OVERFLOW");
+    assert!(error["message"].as_str().is_some());
+}
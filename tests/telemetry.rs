@@ -0,0 +1,128 @@
+mod common;
+
+use std::{
+    io::Write,
+    pin::pin,
+    sync::{Arc, Mutex},
+};
+
+use axum::{body::Body, http::Request, middleware, routing::get, Extension, Router};
+use common::{config, tokenizer, ScriptedModel, EOS, PARIS, PERSEPHONE};
+use futures_util::StreamExt;
+use persephone::{
+    assistant::{AnswerOptions, Assistant},
+    telemetry::{self, redact, RequestId, REQUEST_ID},
+};
+use tower::ServiceExt;
+use tracing_subscriber::fmt::{format::FmtSpan, MakeWriter};
+
+/// Collects everything logged.
+#[derive(Clone, Default)]
+struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl Logs {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Logs {
+    type Writer = Logs;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[test]
+fn answers_are_traced_with_their_request_id() {
+    let logs = Logs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(logs.clone())
+        .with_ansi(false)
+        .json()
+        .finish();
+    let assistant = Assistant::new(
+        ScriptedModel::new(&[PERSEPHONE, PARIS, EOS]),
+        tokenizer(),
+        config(),
+    );
+    let options = AnswerOptions {
+        request_id: RequestId::parse("req-42"),
+        ..Default::default()
+    };
+    tracing::subscriber::with_default(subscriber, || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut tokens = pin!(assistant
+                .answer_with("hello the sun".into(), options)
+                .await
+                .unwrap());
+            while let Some(token) = tokens.next().await {
+                token.unwrap();
+            }
+        });
+    });
+    let logs = logs.contents();
+    for span in ["\"answer\"", "\"prefill\"", "\"decode\""] {
+        assert!(logs.contains(span), "{span} in {logs}");
+    }
+    assert!(logs.contains("req-42"));
+    assert!(logs.contains("answered"));
+    // Prompts are redacted by default
+    assert!(!logs.contains("hello the sun"));
+    assert!(logs.contains("<13 characters redacted>"));
+}
+
+#[test]
+fn prompts_are_redacted_by_default() {
+    assert_eq!(redact("a secret").to_string(), "<8 characters redacted>");
+}
+
+#[test]
+fn request_ids_come_from_callers_or_are_made_up() {
+    assert_eq!(RequestId::parse("abc-123").unwrap().as_str(), "abc-123");
+    assert!(RequestId::parse("").is_none());
+    assert!(RequestId::parse("has space").is_none());
+    assert!(RequestId::parse(&"x".repeat(129)).is_none());
+    assert_ne!(RequestId::new(), RequestId::new());
+}
+
+#[tokio::test]
+async fn requests_carry_their_id_through_to_handlers() {
+    let app = Router::new()
+        .route(
+            "/",
+            get(|Extension(id): Extension<RequestId>| async move { id.to_string() }),
+        )
+        .layer(middleware::from_fn(telemetry::request_id));
+
+    let request = Request::get("/")
+        .header(REQUEST_ID, "from-the-caller")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.headers()[REQUEST_ID], "from-the-caller");
+    let body = axum::body::to_bytes(response.into_body(), 1024)
+        .await
+        .unwrap();
+    assert_eq!(body, "from-the-caller");
+
+    let request = Request::get("/").body(Body::empty()).unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.headers()[REQUEST_ID].len(), 32);
+}
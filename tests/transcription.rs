@@ -0,0 +1,58 @@
+use std::io::Cursor;
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use persephone::transcription::{mel_filters, read_pcm16, read_wav, resample, Pcm16Stream};
+
+#[test]
+fn read_wav_resamples_to_mono_16khz() {
+    let mut bytes = Cursor::new(Vec::new());
+    let spec = WavSpec {
+        channels: 2,
+        sample_rate: 8_000,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    let mut writer = WavWriter::new(&mut bytes, spec).unwrap();
+    for _ in 0..8_000 {
+        writer.write_sample(i16::MAX / 2).unwrap();
+        writer.write_sample(0i16).unwrap();
+    }
+    writer.finalize().unwrap();
+
+    let pcm = read_wav(&bytes.into_inner()).unwrap();
+    assert_eq!(pcm.len(), 16_000);
+    assert!(pcm.iter().all(|s| (s - 0.25).abs() < 1e-3));
+}
+
+#[test]
+fn resampling_from_no_sample_rate_gives_no_samples() {
+    assert!(resample(&[0.5; 4], 0, 16_000).is_empty());
+}
+
+#[test]
+fn samples_split_between_frames_are_kept() {
+    let bytes: Vec<u8> = (0..1_000i16)
+        .flat_map(|it| (it * 30).to_le_bytes())
+        .collect();
+    for from in [8_000, 44_100, 48_000] {
+        let whole = resample(&read_pcm16(&bytes), from, 16_000);
+        for frame in [1, 3, 7, 160, 333] {
+            let mut stream = Pcm16Stream::new(from, 16_000);
+            let pcm: Vec<f32> = bytes.chunks(frame).flat_map(|it| stream.push(it)).collect();
+            // The last samples wait for a frame that never comes
+            assert!(pcm.len().abs_diff(whole.len()) <= 2);
+            let n = pcm.len().min(whole.len());
+            assert_eq!(pcm[..n], whole[..n]);
+        }
+    }
+}
+
+#[test]
+fn mel_filters_cover_every_bin() {
+    let filters = mel_filters(80);
+    assert_eq!(filters.len(), 80 * 201);
+    for row in filters.chunks(201) {
+        assert!(row.iter().all(|w| *w >= 0.0));
+        assert!(row.iter().any(|w| *w > 0.0));
+    }
+}
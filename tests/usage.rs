@@ -0,0 +1,172 @@
+mod common;
+
+use std::{pin::pin, time::Duration};
+
+use async_graphql::{ErrorExtensions, Value};
+use common::{config, tokenizer, ScriptedModel, EOS, PARIS, PERSEPHONE};
+use futures_util::StreamExt;
+use persephone::{
+    assistant::{AnswerOptions, Assistant},
+    auth::{hash_key, Auth, AuthSettings, KeySettings},
+    error::Error,
+    usage::{Day, Tokens, Usage, UsageSettings},
+};
+
+fn day(text: &str) -> Day {
+    Day::parse(text).unwrap()
+}
+
+fn tokens(prompt: u64, completion: u64) -> Tokens {
+    Tokens {
+        requests: 1,
+        prompt,
+        completion,
+    }
+}
+
+#[test]
+fn days_are_calendar_dates() {
+    assert_eq!(Day::from_ymd(1970, 1, 1).unwrap().to_string(), "1970-01-01");
+    assert_eq!(day("9999-12-31").to_string(), "9999-12-31");
+    assert_eq!(day("2024-02-29").to_string(), "2024-02-29");
+    assert_eq!(day("2026-10-19").first_of_month(), day("2026-10-01"));
+    assert_eq!(day("2000-03-01").first_of_month().ymd(), (2000, 3, 1));
+    for invalid in [
+        "2023-02-29",
+        "2026-04-31",
+        "2026-13-01",
+        "yesterday",
+        "2026-10",
+        "1969-12-31",
+        "10000-01-01",
+        "9223372036854775807-01-01",
+        "-9223372036854775808-01-01",
+    ] {
+        assert!(
+            matches!(Day::parse(invalid), Err(Error::InvalidRequest(_))),
+            "{invalid} was accepted"
+        );
+    }
+    assert!(Day::today() > day("2024-01-01"));
+}
+
+#[test]
+fn usage_is_totalled_by_key_and_day() {
+    let usage = Usage::in_memory().unwrap();
+    usage
+        .record("alice", day("2026-09-30"), tokens(100, 10))
+        .unwrap();
+    usage
+        .record("alice", day("2026-10-01"), tokens(5, 5))
+        .unwrap();
+    usage
+        .record("alice", day("2026-10-01"), tokens(20, 2))
+        .unwrap();
+    usage
+        .record("bob", day("2026-10-02"), tokens(1, 1))
+        .unwrap();
+
+    let month = usage.month("alice", day("2026-10-19")).unwrap();
+    assert_eq!(
+        month,
+        Tokens {
+            requests: 2,
+            prompt: 25,
+            completion: 7
+        }
+    );
+    assert_eq!(month.total(), 32);
+
+    let alice = usage
+        .between(Some("alice"), day("2026-09-01"), day("2026-10-31"))
+        .unwrap();
+    assert_eq!(alice.len(), 2);
+    assert_eq!(alice[0].day, day("2026-09-30"));
+    assert_eq!(alice[1].tokens.requests, 2);
+
+    let october = usage
+        .between(None, day("2026-10-01"), day("2026-10-31"))
+        .unwrap();
+    let keys: Vec<_> = october.iter().map(|it| it.key.as_str()).collect();
+    assert_eq!(keys, ["alice", "bob"]);
+}
+
+#[test]
+fn usage_survives_restarts() {
+    let path =
+        std::env::temp_dir().join(format!("persephone-usage-{}.redb", rand::random::<u64>()));
+    let settings = UsageSettings {
+        path: Some(path.clone()),
+    };
+    let today = Day::today();
+    Usage::open(&settings)
+        .unwrap()
+        .record("alice", today, tokens(3, 4))
+        .unwrap();
+    let month = Usage::open(&settings)
+        .unwrap()
+        .month("alice", today)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(month.total(), 7);
+}
+
+#[tokio::test]
+async fn answers_are_charged_until_the_budget_is_used_up() {
+    let usage = Usage::in_memory().unwrap();
+    let settings = AuthSettings {
+        keys: vec![KeySettings {
+            name: "alice".into(),
+            sha256: hash_key("sk-secret"),
+            requests_per_minute: None,
+            tokens_per_minute: None,
+            monthly_tokens: Some(5),
+            admin: false,
+        }],
+    };
+    let caller = Auth::new(&settings, &usage)
+        .authenticate("sk-secret")
+        .unwrap();
+    caller.check_tokens().await.unwrap();
+
+    let assistant = Assistant::new(
+        ScriptedModel::new(&[PERSEPHONE, PARIS, EOS]),
+        tokenizer(),
+        config(),
+    );
+    let options = AnswerOptions {
+        meter: Some(caller.meter()),
+        ..Default::default()
+    };
+    {
+        let mut tokens = pin!(assistant
+            .answer_with("hello the sun".into(), options)
+            .await
+            .unwrap());
+        while let Some(token) = tokens.next().await {
+            token.unwrap();
+        }
+    }
+    // The answer is recorded in the background
+    let month = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let month = usage.month("alice", Day::today()).unwrap();
+            if month.requests > 0 {
+                return month;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(month.requests, 1);
+    assert_eq!(month.completion, 3);
+    assert!(month.prompt >= 3, "{month:?}");
+    assert_eq!(caller.this_month().unwrap(), month);
+
+    // The answer that ran over is let finish, the next is refused
+    let error = caller.check_tokens().await.unwrap_err();
+    assert!(matches!(error, Error::QuotaExceeded { budget: 5 }));
+    let extensions = error.extend().extensions.unwrap();
+    assert_eq!(extensions.get("code"), Some(&Value::from("QUOTA_EXCEEDED")));
+}
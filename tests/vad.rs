@@ -0,0 +1,40 @@
+use persephone::vad::VoiceActivity;
+
+fn tone(ms: usize) -> Vec<f32> {
+    (0..16 * ms).map(|i| (i as f32 * 0.1).sin() * 0.5).collect()
+}
+
+fn silence(ms: usize) -> Vec<f32> {
+    vec![0.0; 16 * ms]
+}
+
+#[test]
+fn utterance_ends_after_a_pause() {
+    let mut vad = VoiceActivity::new();
+    assert!(vad.push(&silence(500)).is_empty());
+    assert!(!vad.speaking());
+    assert!(vad.push(&tone(600)).is_empty());
+    assert!(vad.speaking());
+    let utterances = vad.push(&silence(1000));
+    assert_eq!(utterances.len(), 1);
+    assert!(utterances[0].len() >= 16 * 600);
+    assert!(!vad.speaking());
+}
+
+#[test]
+fn short_noises_are_ignored() {
+    let mut vad = VoiceActivity::new();
+    assert!(vad.push(&tone(60)).is_empty());
+    assert!(vad.push(&silence(1000)).is_empty());
+}
+
+#[test]
+fn every_utterance_in_a_buffer_is_returned() {
+    let mut vad = VoiceActivity::new();
+    let buffer = [tone(600), silence(1000), tone(300), silence(1000)].concat();
+    let utterances = vad.push(&buffer);
+    assert_eq!(utterances.len(), 2);
+    assert!(utterances[0].len() >= 16 * 600);
+    assert!(utterances[1].len() >= 16 * 300);
+    assert!(utterances[1].len() < utterances[0].len());
+}
@@ -0,0 +1,26 @@
+use persephone::voice::{wav, wav_header, Sentences};
+
+#[test]
+fn sentences_split_as_tokens_arrive() {
+    let mut sentences = Sentences::new();
+    assert!(sentences.push("Hello").is_empty());
+    assert!(sentences.push(" there.").is_empty());
+    assert_eq!(sentences.push(" I am"), vec!["Hello there."]);
+    assert_eq!(
+        sentences.push(" Persephone! Version 1.5 is"),
+        vec!["I am Persephone!"]
+    );
+    assert!(sentences.push(" here").is_empty());
+    assert_eq!(sentences.finish(), Some("Version 1.5 is here".to_string()));
+    assert_eq!(sentences.finish(), None);
+}
+
+#[test]
+fn wav_encodes_samples() {
+    let bytes = wav(&[0.0, 1.0, -1.0], 24_000).unwrap();
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(bytes.len(), 44 + 3 * 2);
+    let header = wav_header(24_000).unwrap();
+    assert_eq!(header.len(), 44);
+    assert_eq!(&header[40..44], &u32::MAX.to_le_bytes());
+}